@@ -0,0 +1,5 @@
+DROP SCHEMA main CASCADE;
+
+DROP TYPE genre;
+
+DROP TYPE kind;
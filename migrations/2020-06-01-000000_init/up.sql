@@ -0,0 +1,231 @@
+-- Base schema: authors, images and documents, along with the functions used by the
+-- service to read and write them.
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+CREATE SCHEMA main;
+
+CREATE TYPE kind AS ENUM ('doc', 'post');
+
+CREATE TYPE genre AS ENUM ('tutorial', 'howto', 'background', 'reference');
+
+CREATE TABLE main.authors (
+  id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+  fullname TEXT NOT NULL UNIQUE,
+  resource TEXT NOT NULL
+);
+
+CREATE TABLE main.images (
+  id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+  title TEXT NOT NULL,
+  author_id UUID NOT NULL REFERENCES main.authors(id),
+  resource TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE main.documents (
+  id UUID PRIMARY KEY,
+  title TEXT NOT NULL UNIQUE,
+  outline TEXT NOT NULL,
+  author_id UUID NOT NULL REFERENCES main.authors(id),
+  content TEXT NOT NULL,
+  tags TEXT[] NOT NULL DEFAULT '{}',
+  image_id UUID NOT NULL REFERENCES main.images(id),
+  kind KIND NOT NULL DEFAULT 'doc',
+  genre GENRE NOT NULL DEFAULT 'tutorial',
+  search TSVECTOR,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX documents_search_idx ON main.documents USING GIN (search);
+
+CREATE INDEX documents_tags_idx ON main.documents USING GIN (tags);
+
+CREATE OR REPLACE FUNCTION main.update_document_search()
+RETURNS TRIGGER
+AS $$
+BEGIN
+  NEW.search :=
+    setweight(to_tsvector('english', coalesce(NEW.title, '')), 'A') ||
+    setweight(to_tsvector('english', array_to_string(NEW.tags, ' ')), 'A') ||
+    setweight(to_tsvector('english', coalesce(NEW.outline, '')), 'B') ||
+    setweight(to_tsvector('english', coalesce(NEW.content, '')), 'C');
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER update_document_search
+  BEFORE INSERT OR UPDATE ON main.documents
+  FOR EACH ROW EXECUTE PROCEDURE main.update_document_search();
+
+-- A document without its content, as listed or searched.
+CREATE TYPE main.short_document_type AS (
+  id UUID,
+  title TEXT,
+  outline TEXT,
+  author_id UUID,
+  author_fullname TEXT,
+  author_resource TEXT,
+  tags TEXT[],
+  image_id UUID,
+  image_title TEXT,
+  image_author_id UUID,
+  image_author_fullname TEXT,
+  image_author_resource TEXT,
+  image_resource TEXT,
+  kind KIND,
+  genre GENRE,
+  created_at TIMESTAMPTZ,
+  updated_at TIMESTAMPTZ
+);
+
+-- A full document. created is only set by create_document_with_id, when the document
+-- did not exist before.
+CREATE TYPE main.return_document_type AS (
+  created BOOLEAN,
+  id UUID,
+  title TEXT,
+  outline TEXT,
+  author_id UUID,
+  author_fullname TEXT,
+  author_resource TEXT,
+  content TEXT,
+  tags TEXT[],
+  image_id UUID,
+  image_title TEXT,
+  image_author_id UUID,
+  image_author_fullname TEXT,
+  image_author_resource TEXT,
+  image_resource TEXT,
+  kind KIND,
+  genre GENRE,
+  created_at TIMESTAMPTZ,
+  updated_at TIMESTAMPTZ
+);
+
+CREATE VIEW main.short_documents AS
+  SELECT d.id, d.title, d.outline,
+    a.id AS author_id, a.fullname AS author_fullname, a.resource AS author_resource,
+    d.tags,
+    i.id AS image_id, i.title AS image_title,
+    ia.id AS image_author_id, ia.fullname AS image_author_fullname,
+    ia.resource AS image_author_resource, i.resource AS image_resource,
+    d.kind, d.genre, d.created_at, d.updated_at,
+    d.search
+  FROM main.documents AS d
+  JOIN main.authors AS a ON a.id = d.author_id
+  JOIN main.images AS i ON i.id = d.image_id
+  JOIN main.authors AS ia ON ia.id = i.author_id;
+
+CREATE OR REPLACE FUNCTION main.list_documents(_kind KIND)
+RETURNS SETOF main.short_document_type
+AS $$
+  SELECT id, title, outline, author_id, author_fullname, author_resource, tags,
+    image_id, image_title, image_author_id, image_author_fullname, image_author_resource,
+    image_resource, kind, genre, created_at, updated_at
+  FROM main.short_documents
+  WHERE kind = _kind
+  ORDER BY updated_at DESC;
+$$ LANGUAGE sql STABLE;
+
+CREATE OR REPLACE FUNCTION main.search_documents_by_query(_query TEXT)
+RETURNS SETOF main.short_document_type
+AS $$
+  SELECT id, title, outline, author_id, author_fullname, author_resource, tags,
+    image_id, image_title, image_author_id, image_author_fullname, image_author_resource,
+    image_resource, kind, genre, created_at, updated_at
+  FROM main.short_documents
+  WHERE search @@ plainto_tsquery('english', _query)
+  ORDER BY ts_rank(search, plainto_tsquery('english', _query)) DESC;
+$$ LANGUAGE sql STABLE;
+
+CREATE OR REPLACE FUNCTION main.search_documents_by_tag(_tag TEXT)
+RETURNS SETOF main.short_document_type
+AS $$
+  SELECT id, title, outline, author_id, author_fullname, author_resource, tags,
+    image_id, image_title, image_author_id, image_author_fullname, image_author_resource,
+    image_resource, kind, genre, created_at, updated_at
+  FROM main.short_documents
+  WHERE _tag = ANY(tags)
+  ORDER BY updated_at DESC;
+$$ LANGUAGE sql STABLE;
+
+CREATE OR REPLACE FUNCTION main.get_document_by_id(_id UUID)
+RETURNS SETOF main.return_document_type
+AS $$
+  SELECT FALSE, d.id, d.title, d.outline,
+    a.id, a.fullname, a.resource,
+    d.content, d.tags,
+    i.id, i.title, ia.id, ia.fullname, ia.resource, i.resource,
+    d.kind, d.genre, d.created_at, d.updated_at
+  FROM main.documents AS d
+  JOIN main.authors AS a ON a.id = d.author_id
+  JOIN main.images AS i ON i.id = d.image_id
+  JOIN main.authors AS ia ON ia.id = i.author_id
+  WHERE d.id = _id;
+$$ LANGUAGE sql STABLE;
+
+-- Create a document with the given id, or update it if it exists already. Its author,
+-- image and image author are created or updated along with it.
+--
+-- The author resource is given twice (_author_resource, _author_url): the second one
+-- is only kept for compatibility with existing callers, and is ignored.
+CREATE OR REPLACE FUNCTION main.create_document_with_id(
+  _id UUID,
+  _title TEXT,
+  _outline TEXT,
+  _author_fullname TEXT,
+  _author_resource TEXT,
+  _author_url TEXT,
+  _content TEXT,
+  _tags TEXT[],
+  _image_title TEXT,
+  _image_author_fullname TEXT,
+  _image_author_resource TEXT,
+  _image_resource TEXT,
+  _kind KIND,
+  _genre GENRE)
+RETURNS SETOF main.return_document_type
+AS $$
+DECLARE
+  _author_id UUID;
+  _image_author_id UUID;
+  _image_id UUID;
+  _created BOOLEAN;
+BEGIN
+  INSERT INTO main.authors (fullname, resource)
+  VALUES (_author_fullname, _author_resource)
+  ON CONFLICT (fullname) DO UPDATE SET resource = EXCLUDED.resource
+  RETURNING id INTO _author_id;
+
+  INSERT INTO main.authors (fullname, resource)
+  VALUES (_image_author_fullname, _image_author_resource)
+  ON CONFLICT (fullname) DO UPDATE SET resource = EXCLUDED.resource
+  RETURNING id INTO _image_author_id;
+
+  INSERT INTO main.images (title, author_id, resource)
+  VALUES (_image_title, _image_author_id, _image_resource)
+  ON CONFLICT (resource) DO UPDATE SET title = EXCLUDED.title, author_id = EXCLUDED.author_id
+  RETURNING id INTO _image_id;
+
+  _created := NOT EXISTS (SELECT 1 FROM main.documents WHERE id = _id);
+
+  INSERT INTO main.documents (id, title, outline, author_id, content, tags, image_id, kind, genre)
+  VALUES (_id, _title, _outline, _author_id, _content, _tags, _image_id, _kind, _genre)
+  ON CONFLICT (id) DO UPDATE SET
+    title = EXCLUDED.title,
+    outline = EXCLUDED.outline,
+    author_id = EXCLUDED.author_id,
+    content = EXCLUDED.content,
+    tags = EXCLUDED.tags,
+    image_id = EXCLUDED.image_id,
+    kind = EXCLUDED.kind,
+    genre = EXCLUDED.genre,
+    updated_at = NOW();
+
+  RETURN QUERY SELECT _created, d.id, d.title, d.outline, d.author_id, d.author_fullname,
+    d.author_resource, d.content, d.tags, d.image_id, d.image_title, d.image_author_id,
+    d.image_author_fullname, d.image_author_resource, d.image_resource, d.kind, d.genre,
+    d.created_at, d.updated_at
+  FROM main.get_document_by_id(_id) AS d;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS increment_document_version ON main.documents;
+DROP FUNCTION IF EXISTS main.increment_document_version();
+ALTER TABLE main.documents DROP COLUMN IF EXISTS version;
@@ -0,0 +1,16 @@
+-- Each document carries a version, incremented on every update, which clients use
+-- for optimistic concurrency control.
+ALTER TABLE main.documents ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+CREATE OR REPLACE FUNCTION main.increment_document_version()
+RETURNS TRIGGER
+AS $$
+BEGIN
+  NEW.version := OLD.version + 1;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER increment_document_version
+  BEFORE UPDATE ON main.documents
+  FOR EACH ROW EXECUTE PROCEDURE main.increment_document_version();
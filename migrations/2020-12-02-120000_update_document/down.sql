@@ -0,0 +1,5 @@
+DROP FUNCTION IF EXISTS main.update_document(
+  UUID, TEXT, TEXT, TEXT, TEXT, TEXT, TEXT[], TEXT[], TEXT[],
+  TEXT, TEXT, TEXT, TEXT, KIND, GENRE);
+DROP FUNCTION IF EXISTS main.upsert_image(TEXT, TEXT, TEXT, TEXT);
+DROP FUNCTION IF EXISTS main.upsert_author(TEXT, TEXT);
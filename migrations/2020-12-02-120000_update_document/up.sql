@@ -0,0 +1,112 @@
+-- Returns the id of the author with the given fullname, creating it if needed.
+CREATE OR REPLACE FUNCTION main.upsert_author(
+  _fullname TEXT,
+  _resource TEXT)
+RETURNS UUID
+AS $$
+  INSERT INTO main.authors (fullname, resource)
+  VALUES (_fullname, _resource)
+  ON CONFLICT (fullname) DO UPDATE SET resource = EXCLUDED.resource
+  RETURNING id;
+$$ LANGUAGE sql;
+
+-- Returns the id of the image with the given resource, creating it if needed.
+CREATE OR REPLACE FUNCTION main.upsert_image(
+  _title TEXT,
+  _resource TEXT,
+  _author_fullname TEXT,
+  _author_resource TEXT)
+RETURNS UUID
+AS $$
+  INSERT INTO main.images (title, author_id, resource)
+  VALUES (_title, main.upsert_author(_author_fullname, _author_resource), _resource)
+  ON CONFLICT (resource) DO UPDATE SET title = EXCLUDED.title, author_id = EXCLUDED.author_id
+  RETURNING id;
+$$ LANGUAGE sql;
+
+-- Apply a partial update to a document. NULL arguments leave the corresponding fields
+-- untouched. Tags are replaced by _tags if given, then _add_tags are appended and
+-- _remove_tags are removed.
+-- Returns the id of the document, or NULL if it does not exist.
+CREATE OR REPLACE FUNCTION main.update_document(
+  _id UUID,
+  _title TEXT,
+  _outline TEXT,
+  _author_fullname TEXT,
+  _author_resource TEXT,
+  _content TEXT,
+  _tags TEXT[],
+  _add_tags TEXT[],
+  _remove_tags TEXT[],
+  _image_title TEXT,
+  _image_resource TEXT,
+  _image_author_fullname TEXT,
+  _image_author_resource TEXT,
+  _kind KIND,
+  _genre GENRE)
+RETURNS UUID
+AS $$
+DECLARE
+  _doc main.documents%ROWTYPE;
+  _author main.authors%ROWTYPE;
+  _image main.images%ROWTYPE;
+  _image_author main.authors%ROWTYPE;
+  _author_id UUID;
+  _image_id UUID;
+  _new_tags TEXT[];
+BEGIN
+  SELECT * INTO _doc FROM main.documents WHERE id = _id FOR UPDATE;
+  IF NOT FOUND THEN
+    RETURN NULL;
+  END IF;
+
+  _author_id := _doc.author_id;
+  IF _author_fullname IS NOT NULL OR _author_resource IS NOT NULL THEN
+    SELECT * INTO _author FROM main.authors WHERE id = _doc.author_id;
+    _author_id := main.upsert_author(
+      COALESCE(_author_fullname, _author.fullname),
+      COALESCE(_author_resource, _author.resource));
+  END IF;
+
+  _image_id := _doc.image_id;
+  IF _image_title IS NOT NULL OR _image_resource IS NOT NULL
+     OR _image_author_fullname IS NOT NULL OR _image_author_resource IS NOT NULL THEN
+    SELECT * INTO _image FROM main.images WHERE id = _doc.image_id;
+    SELECT * INTO _image_author FROM main.authors WHERE id = _image.author_id;
+    _image_id := main.upsert_image(
+      COALESCE(_image_title, _image.title),
+      COALESCE(_image_resource, _image.resource),
+      COALESCE(_image_author_fullname, _image_author.fullname),
+      COALESCE(_image_author_resource, _image_author.resource));
+  END IF;
+
+  _new_tags := COALESCE(_tags, _doc.tags);
+  IF _add_tags IS NOT NULL THEN
+    _new_tags := _new_tags || ARRAY(
+      SELECT t FROM unnest(_add_tags) WITH ORDINALITY AS u(t, n)
+      WHERE NOT t = ANY(_new_tags)
+      GROUP BY t
+      ORDER BY MIN(n));
+  END IF;
+  IF _remove_tags IS NOT NULL THEN
+    _new_tags := ARRAY(
+      SELECT t FROM unnest(_new_tags) WITH ORDINALITY AS u(t, n)
+      WHERE NOT t = ANY(_remove_tags)
+      ORDER BY n);
+  END IF;
+
+  UPDATE main.documents SET
+    title = COALESCE(_title, title),
+    outline = COALESCE(_outline, outline),
+    author_id = _author_id,
+    content = COALESCE(_content, content),
+    tags = _new_tags,
+    image_id = _image_id,
+    kind = COALESCE(_kind, kind),
+    genre = COALESCE(_genre, genre),
+    updated_at = NOW()
+  WHERE id = _id;
+
+  RETURN _id;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS main.documents_slug_idx;
+DROP FUNCTION IF EXISTS main.slugify(TEXT);
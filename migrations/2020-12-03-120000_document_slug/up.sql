@@ -0,0 +1,9 @@
+-- The slug of a title, used to detect documents whose titles only differ by case or
+-- punctuation. This must match api::utils::slugify.
+CREATE OR REPLACE FUNCTION main.slugify(_text TEXT)
+RETURNS TEXT
+AS $$
+  SELECT trim(BOTH '-' FROM regexp_replace(lower(_text), '[^a-z0-9]+', '-', 'g'));
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE INDEX documents_slug_idx ON main.documents (main.slugify(title));
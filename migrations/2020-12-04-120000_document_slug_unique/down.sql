@@ -0,0 +1,3 @@
+DROP INDEX main.documents_slug_idx;
+
+CREATE INDEX documents_slug_idx ON main.documents (main.slugify(title));
@@ -0,0 +1,7 @@
+-- Titles with the same slug conflict, whatever the isolation level of the transactions
+-- creating them.
+DROP INDEX main.documents_slug_idx;
+
+-- Titles without any letter or digit have an empty slug, which identifies nothing.
+CREATE UNIQUE INDEX documents_slug_idx ON main.documents (main.slugify(title))
+  WHERE main.slugify(title) <> '';
@@ -0,0 +1,74 @@
+-- Schema of the SQLite store. Unlike the Postgres schema, it is created as a whole
+-- when the store is opened, and versioned with PRAGMA user_version.
+--
+-- Ids are UUIDs and timestamps RFC 3339 strings in UTC, which sort chronologically.
+-- Tags are a JSON array. The slug of the title is computed by the store, as SQLite has
+-- no equivalent of main.slugify.
+CREATE TABLE authors (
+  id TEXT PRIMARY KEY,
+  fullname TEXT NOT NULL UNIQUE,
+  resource TEXT NOT NULL
+);
+
+CREATE TABLE images (
+  id TEXT PRIMARY KEY,
+  title TEXT NOT NULL,
+  author_id TEXT NOT NULL REFERENCES authors(id),
+  resource TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE documents (
+  id TEXT PRIMARY KEY,
+  title TEXT NOT NULL UNIQUE,
+  slug TEXT NOT NULL,
+  outline TEXT NOT NULL,
+  author_id TEXT NOT NULL REFERENCES authors(id),
+  content TEXT NOT NULL,
+  tags TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(tags)),
+  image_id TEXT NOT NULL REFERENCES images(id),
+  kind TEXT NOT NULL CHECK (kind IN ('doc', 'post')),
+  genre TEXT NOT NULL CHECK (genre IN ('tutorial', 'howto', 'background', 'reference')),
+  created_at TEXT NOT NULL,
+  updated_at TEXT NOT NULL,
+  version INTEGER NOT NULL DEFAULT 1
+);
+
+-- Titles without any letter or digit have an empty slug, which identifies nothing.
+CREATE UNIQUE INDEX documents_slug_idx ON documents (slug) WHERE slug <> '';
+
+CREATE VIRTUAL TABLE documents_fts USING fts5(
+  title, tags, outline, content,
+  content = 'documents', content_rowid = 'rowid'
+);
+
+CREATE TRIGGER documents_fts_insert AFTER INSERT ON documents BEGIN
+  INSERT INTO documents_fts (rowid, title, tags, outline, content)
+  VALUES (new.rowid, new.title, new.tags, new.outline, new.content);
+END;
+
+CREATE TRIGGER documents_fts_delete AFTER DELETE ON documents BEGIN
+  INSERT INTO documents_fts (documents_fts, rowid, title, tags, outline, content)
+  VALUES ('delete', old.rowid, old.title, old.tags, old.outline, old.content);
+END;
+
+CREATE TRIGGER documents_fts_update AFTER UPDATE ON documents BEGIN
+  INSERT INTO documents_fts (documents_fts, rowid, title, tags, outline, content)
+  VALUES ('delete', old.rowid, old.title, old.tags, old.outline, old.content);
+  INSERT INTO documents_fts (rowid, title, tags, outline, content)
+  VALUES (new.rowid, new.title, new.tags, new.outline, new.content);
+END;
+
+-- Columns in the order expected by the FromRow implementations.
+CREATE VIEW document_view AS
+  SELECT d.id, d.title, d.outline,
+    a.id AS author_id, a.fullname AS author_fullname, a.resource AS author_resource,
+    d.content, d.tags,
+    i.id AS image_id, i.title AS image_title,
+    ia.id AS image_author_id, ia.fullname AS image_author_fullname,
+    ia.resource AS image_author_resource, i.resource AS image_resource,
+    d.kind, d.genre, d.created_at, d.updated_at, d.version,
+    d.rowid AS doc_rowid
+  FROM documents AS d
+  JOIN authors AS a ON a.id = d.author_id
+  JOIN images AS i ON i.id = d.image_id
+  JOIN authors AS ia ON ia.id = i.author_id;
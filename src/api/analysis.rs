@@ -0,0 +1,686 @@
+//! Static analysis of GraphQL documents, before they are executed.
+
+use juniper::parser::{Lexer, Token};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::settings::Graphql as Limits;
+
+pub const INVALID_QUERY: &str = "INVALID_QUERY";
+pub const TOO_DEEP: &str = "TOO_DEEP";
+pub const TOO_MANY_ALIASES: &str = "TOO_MANY_ALIASES";
+pub const TOO_COSTLY: &str = "TOO_COSTLY";
+pub const INTROSPECTION_DISABLED: &str = "INTROSPECTION_DISABLED";
+
+/// Selection sets nested deeper are not parsed, as the parser, like the analysis,
+/// recurses into them.
+const MAX_NESTING: usize = 128;
+
+/// The kind of an operation, which sets the budget it is charged to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+impl OperationKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OperationKind::Query => "query",
+            OperationKind::Mutation => "mutation",
+            OperationKind::Subscription => "subscription",
+        }
+    }
+}
+
+/// Why a document is not executed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    /// The document could not be analyzed, so it is not executed either.
+    Invalid(String),
+    Depth {
+        depth: usize,
+        max: usize,
+    },
+    Aliases {
+        aliases: usize,
+        max: usize,
+    },
+    Cost {
+        cost: u64,
+        max: u64,
+    },
+    Introspection,
+}
+
+impl Rejection {
+    /// A stable, machine readable code, reported to the client.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::Invalid(_) => INVALID_QUERY,
+            Rejection::Depth { .. } => TOO_DEEP,
+            Rejection::Aliases { .. } => TOO_MANY_ALIASES,
+            Rejection::Cost { .. } => TOO_COSTLY,
+            Rejection::Introspection => INTROSPECTION_DISABLED,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::Invalid(msg) => write!(f, "Invalid query: {}", msg),
+            Rejection::Depth { depth, max } => write!(
+                f,
+                "Query is nested {} levels deep, the maximum is {}",
+                depth, max
+            ),
+            Rejection::Aliases { aliases, max } => {
+                write!(f, "Query has {} aliases, the maximum is {}", aliases, max)
+            }
+            Rejection::Cost { cost, max } => {
+                write!(f, "Query costs {}, the maximum is {}", cost, max)
+            }
+            Rejection::Introspection => write!(f, "Introspection is disabled"),
+        }
+    }
+}
+
+/// What the analysis needs to know of the schema, to key the weights of fields by
+/// `Type.field`.
+pub trait Schema {
+    /// The name of the type at the root of the operations of that kind.
+    fn root_type(&self, kind: OperationKind) -> Option<&str>;
+
+    /// The name of the type of a field, without its list and non null wrappers.
+    fn field_type(&self, parent: &str, field: &str) -> Option<&str>;
+}
+
+/// The measures of the operation executed for a document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub kind: OperationKind,
+    /// Deepest nesting of fields, the fields of the operation being at depth 1
+    pub depth: usize,
+    pub aliases: usize,
+    /// Sum of the weights of the fields, as many times as they are selected
+    pub cost: u64,
+    /// Whether `__schema` or `__type` is queried
+    pub introspection: bool,
+}
+
+impl Analysis {
+    pub fn check(&self, limits: &Limits) -> Result<(), Rejection> {
+        if self.introspection && !limits.introspection {
+            return Err(Rejection::Introspection);
+        }
+        if self.depth > limits.max_depth {
+            return Err(Rejection::Depth {
+                depth: self.depth,
+                max: limits.max_depth,
+            });
+        }
+        if self.aliases > limits.max_aliases {
+            return Err(Rejection::Aliases {
+                aliases: self.aliases,
+                max: limits.max_aliases,
+            });
+        }
+        if self.cost > limits.max_cost {
+            return Err(Rejection::Cost {
+                cost: self.cost,
+                max: limits.max_cost,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Measure the operation with the given name, or the only one, of the document. Weights
+/// are keyed by `Type.field`, and fields without a weight, or whose type is unknown to
+/// the schema, cost 1.
+pub fn analyze(
+    document: &str,
+    operation_name: Option<&str>,
+    schema: &dyn Schema,
+    weights: &HashMap<String, u64>,
+) -> Result<Analysis, Rejection> {
+    let tokens = tokenize(document).map_err(Rejection::Invalid)?;
+    let definitions = Parser::new(&tokens)
+        .document()
+        .map_err(Rejection::Invalid)?;
+    let operation = select(&definitions, operation_name).map_err(Rejection::Invalid)?;
+
+    let kind = operation.kind.unwrap_or(OperationKind::Query);
+    let mut measurer = Measurer {
+        definitions: &definitions,
+        schema,
+        weights,
+        fragments: HashMap::new(),
+        visiting: Vec::new(),
+    };
+    let measure = measurer
+        .selections(schema.root_type(kind), &operation.selections)
+        .map_err(Rejection::Invalid)?;
+
+    Ok(Analysis {
+        kind,
+        depth: measure.depth,
+        aliases: measure.aliases,
+        cost: measure.cost,
+        introspection: measure.introspection,
+    })
+}
+
+/// The tokens of a document, or the first lexing error.
+fn tokenize(document: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    // The lexer does not move past an error, so we stop at the first one.
+    for token in Lexer::new(document) {
+        match token {
+            Ok(token) if token.item == Token::EndOfFile => break,
+            Ok(token) => tokens.push(token.item),
+            Err(err) => return Err(format!("{:?}", err.item)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// An operation, or a fragment, whose kind is None.
+#[derive(Debug)]
+struct Definition<'a> {
+    kind: Option<OperationKind>,
+    name: Option<&'a str>,
+    /// The type a fragment applies to
+    on: Option<&'a str>,
+    selections: Vec<Selection<'a>>,
+}
+
+#[derive(Debug)]
+enum Selection<'a> {
+    Field {
+        name: &'a str,
+        aliased: bool,
+        selections: Vec<Selection<'a>>,
+    },
+    Spread(&'a str),
+    Inline {
+        on: Option<&'a str>,
+        selections: Vec<Selection<'a>>,
+    },
+}
+
+/// The operation with the given name, or the only one, as GraphQL requires.
+fn select<'d, 'a>(
+    definitions: &'d [Definition<'a>],
+    operation_name: Option<&str>,
+) -> Result<&'d Definition<'a>, String> {
+    let mut operations = definitions.iter().filter(|def| def.kind.is_some());
+    match operation_name {
+        Some(name) => operations
+            .find(|def| def.name == Some(name))
+            .ok_or_else(|| format!("unknown operation '{}'", name)),
+        None => {
+            let operation = operations
+                .next()
+                .ok_or_else(|| String::from("no operation"))?;
+            if operations.next().is_some() {
+                return Err(String::from(
+                    "an operation name is required with several operations",
+                ));
+            }
+            Ok(operation)
+        }
+    }
+}
+
+/// Parses the executable definitions of a document, keeping only what the analysis
+/// needs: arguments, variables and directives are skipped.
+struct Parser<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    pos: usize,
+    nesting: usize,
+}
+
+impl<'t, 'a> Parser<'t, 'a> {
+    fn new(tokens: &'t [Token<'a>]) -> Self {
+        Parser {
+            tokens,
+            pos: 0,
+            nesting: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<Token<'a>, String> {
+        let token = self
+            .peek()
+            .ok_or_else(|| String::from("unexpected end of document"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token<'a>) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("expected {:?}, found {:?}", expected, token)),
+        }
+    }
+
+    fn name(&mut self) -> Result<&'a str, String> {
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            token => Err(format!("expected a name, found {:?}", token)),
+        }
+    }
+
+    fn document(&mut self) -> Result<Vec<Definition<'a>>, String> {
+        let mut definitions = Vec::new();
+        while let Some(token) = self.peek() {
+            let definition = match token {
+                Token::CurlyOpen => Definition {
+                    kind: Some(OperationKind::Query),
+                    name: None,
+                    on: None,
+                    selections: self.selection_set()?,
+                },
+                Token::Name("fragment") => {
+                    self.pos += 1;
+                    let name = self.name()?;
+                    self.expect(Token::Name("on"))?;
+                    let on = self.name()?;
+                    self.directives()?;
+                    Definition {
+                        kind: None,
+                        name: Some(name),
+                        on: Some(on),
+                        selections: self.selection_set()?,
+                    }
+                }
+                Token::Name(keyword) => {
+                    let kind = match keyword {
+                        "query" => OperationKind::Query,
+                        "mutation" => OperationKind::Mutation,
+                        "subscription" => OperationKind::Subscription,
+                        _ => return Err(format!("unexpected '{}'", keyword)),
+                    };
+                    self.pos += 1;
+                    let name = match self.peek() {
+                        Some(Token::Name(name)) => {
+                            self.pos += 1;
+                            Some(name)
+                        }
+                        _ => None,
+                    };
+                    self.skip_group(Token::ParenOpen, Token::ParenClose)?;
+                    self.directives()?;
+                    Definition {
+                        kind: Some(kind),
+                        name,
+                        on: None,
+                        selections: self.selection_set()?,
+                    }
+                }
+                token => return Err(format!("unexpected {:?}", token)),
+            };
+            definitions.push(definition);
+        }
+        Ok(definitions)
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection<'a>>, String> {
+        self.expect(Token::CurlyOpen)?;
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            return Err(format!(
+                "selection sets nested more than {} levels deep",
+                MAX_NESTING
+            ));
+        }
+        let mut selections = Vec::new();
+        loop {
+            let selection = match self.next()? {
+                Token::CurlyClose => break,
+                Token::Ellipsis => match self.peek() {
+                    Some(Token::Name("on")) => {
+                        self.pos += 1;
+                        let on = self.name()?;
+                        self.directives()?;
+                        Selection::Inline {
+                            on: Some(on),
+                            selections: self.selection_set()?,
+                        }
+                    }
+                    Some(Token::Name(name)) => {
+                        self.pos += 1;
+                        self.directives()?;
+                        Selection::Spread(name)
+                    }
+                    _ => {
+                        self.directives()?;
+                        Selection::Inline {
+                            on: None,
+                            selections: self.selection_set()?,
+                        }
+                    }
+                },
+                Token::Name(first) => {
+                    let (name, aliased) = if self.peek() == Some(Token::Colon) {
+                        self.pos += 1;
+                        (self.name()?, true)
+                    } else {
+                        (first, false)
+                    };
+                    self.skip_group(Token::ParenOpen, Token::ParenClose)?;
+                    self.directives()?;
+                    let children = if self.peek() == Some(Token::CurlyOpen) {
+                        self.selection_set()?
+                    } else {
+                        Vec::new()
+                    };
+                    Selection::Field {
+                        name,
+                        aliased,
+                        selections: children,
+                    }
+                }
+                token => return Err(format!("unexpected {:?} in selection set", token)),
+            };
+            selections.push(selection);
+        }
+        self.nesting -= 1;
+        Ok(selections)
+    }
+
+    fn directives(&mut self) -> Result<(), String> {
+        while self.peek() == Some(Token::At) {
+            self.pos += 1;
+            self.name()?;
+            self.skip_group(Token::ParenOpen, Token::ParenClose)?;
+        }
+        Ok(())
+    }
+
+    /// Skip a group, eg of arguments, if there is one.
+    fn skip_group(&mut self, open: Token<'a>, close: Token<'a>) -> Result<(), String> {
+        if self.peek() != Some(open) {
+            return Ok(());
+        }
+        let mut depth = 0usize;
+        loop {
+            let token = self.next()?;
+            if token == open {
+                depth += 1;
+            } else if token == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Measure {
+    depth: usize,
+    aliases: usize,
+    cost: u64,
+    introspection: bool,
+}
+
+impl Measure {
+    /// Combine the measures of sibling selections.
+    fn and(self, other: Measure) -> Measure {
+        Measure {
+            depth: self.depth.max(other.depth),
+            aliases: self.aliases.saturating_add(other.aliases),
+            cost: self.cost.saturating_add(other.cost),
+            introspection: self.introspection || other.introspection,
+        }
+    }
+}
+
+/// Measures selections. Fragments are measured once, however many times they are
+/// spread, so that nesting spreads cannot make the analysis itself costly.
+struct Measurer<'d, 'a> {
+    definitions: &'d [Definition<'a>],
+    schema: &'d dyn Schema,
+    weights: &'d HashMap<String, u64>,
+    fragments: HashMap<&'a str, Measure>,
+    visiting: Vec<&'a str>,
+}
+
+impl<'d, 'a> Measurer<'d, 'a> {
+    /// Measure the selections on the `parent` type, if it is known.
+    fn selections(
+        &mut self,
+        parent: Option<&'d str>,
+        selections: &'d [Selection<'a>],
+    ) -> Result<Measure, String> {
+        let schema = self.schema;
+        let mut total = Measure::default();
+        for selection in selections {
+            let measure = match selection {
+                Selection::Field {
+                    name,
+                    aliased,
+                    selections,
+                } => {
+                    let field_type = parent.and_then(|parent| schema.field_type(parent, name));
+                    let inner = self.selections(field_type, selections)?;
+                    let weight = parent
+                        .and_then(|parent| self.weights.get(&format!("{}.{}", parent, name)))
+                        .copied()
+                        .unwrap_or(1);
+                    Measure {
+                        depth: inner.depth + 1,
+                        aliases: inner.aliases.saturating_add(usize::from(*aliased)),
+                        cost: inner.cost.saturating_add(weight),
+                        introspection: inner.introspection
+                            || *name == "__schema"
+                            || *name == "__type",
+                    }
+                }
+                Selection::Inline { on, selections } => {
+                    self.selections(on.or(parent), selections)?
+                }
+                Selection::Spread(name) => self.fragment(*name)?,
+            };
+            total = total.and(measure);
+        }
+        Ok(total)
+    }
+
+    fn fragment(&mut self, name: &'a str) -> Result<Measure, String> {
+        if let Some(measure) = self.fragments.get(name) {
+            return Ok(*measure);
+        }
+        if self.visiting.contains(&name) {
+            return Err(format!("fragment '{}' spreads itself", name));
+        }
+        let definitions = self.definitions;
+        let fragment = definitions
+            .iter()
+            .find(|def| def.kind.is_none() && def.name == Some(name))
+            .ok_or_else(|| format!("unknown fragment '{}'", name))?;
+
+        self.visiting.push(name);
+        let measure = self.selections(fragment.on, &fragment.selections)?;
+        self.visiting.pop();
+        self.fragments.insert(name, measure);
+        Ok(measure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The part of the journal schema queried by the tests.
+    struct Journal;
+
+    impl Schema for Journal {
+        fn root_type(&self, kind: OperationKind) -> Option<&str> {
+            match kind {
+                OperationKind::Query => Some("Query"),
+                OperationKind::Mutation => Some("Mutation"),
+                OperationKind::Subscription => None,
+            }
+        }
+
+        fn field_type(&self, parent: &str, field: &str) -> Option<&str> {
+            match (parent, field) {
+                ("Query", "listDocuments") => Some("MultiDocsResponseBody"),
+                ("Query", "findDocumentById") => Some("SingleDocResponseBody"),
+                ("Mutation", "createDocument") => Some("SingleDocResponseBody"),
+                ("MultiDocsResponseBody", "docs") => Some("ShortDoc"),
+                ("SingleDocResponseBody", "doc") => Some("Doc"),
+                ("ShortDoc", "front") | ("Doc", "front") => Some("Front"),
+                ("Front", "author") => Some("Author"),
+                _ => None,
+            }
+        }
+    }
+
+    fn weights() -> HashMap<String, u64> {
+        vec![("Doc.content", 10), ("Query.listDocuments", 20)]
+            .into_iter()
+            .map(|(field, weight)| (String::from(field), weight))
+            .collect()
+    }
+
+    fn analysis(document: &str) -> Analysis {
+        analyze(document, None, &Journal, &weights()).unwrap()
+    }
+
+    #[test]
+    fn depth_counts_nested_fields() {
+        let analysis = analysis("{ listDocuments { docs { front { author { fullname } } } } }");
+        assert_eq!(analysis.kind, OperationKind::Query);
+        assert_eq!(analysis.depth, 5);
+
+        let limits = Limits {
+            max_depth: 4,
+            ..Limits::default()
+        };
+        assert_eq!(
+            analysis.check(&limits),
+            Err(Rejection::Depth { depth: 5, max: 4 })
+        );
+    }
+
+    #[test]
+    fn aliases_are_counted() {
+        let analysis = analysis(
+            "{ first: listDocuments { docsCount } second: listDocuments { count: docsCount } }",
+        );
+        assert_eq!(analysis.aliases, 3);
+        assert_eq!(analysis.cost, 42);
+
+        let limits = Limits {
+            max_aliases: 2,
+            ..Limits::default()
+        };
+        assert_eq!(
+            analysis.check(&limits),
+            Err(Rejection::Aliases { aliases: 3, max: 2 })
+        );
+    }
+
+    #[test]
+    fn weights_are_keyed_by_type_and_field() {
+        // content weighs 10 on Doc only.
+        let doc = analysis(r#"{ findDocumentById(id: "1") { doc { id content } } }"#);
+        assert_eq!(doc.cost, 13);
+        let short = analysis("{ listDocuments { docs { id content } } }");
+        assert_eq!(short.cost, 23);
+
+        let mutation = analyze(
+            "mutation Create($doc: NewDocSpec!) { createDocument(doc: $doc) { doc { content } } }",
+            Some("Create"),
+            &Journal,
+            &weights(),
+        )
+        .unwrap();
+        assert_eq!(mutation.kind, OperationKind::Mutation);
+        assert_eq!(mutation.cost, 12);
+
+        let limits = Limits {
+            max_cost: 12,
+            ..Limits::default()
+        };
+        assert_eq!(
+            doc.check(&limits),
+            Err(Rejection::Cost { cost: 13, max: 12 })
+        );
+        assert_eq!(mutation.check(&limits), Ok(()));
+    }
+
+    #[test]
+    fn fragments_are_measured_where_they_are_spread() {
+        let spread = analysis(
+            "query { findDocumentById(id: \"1\") { ...body } }
+             fragment body on SingleDocResponseBody { doc { ...content } }
+             fragment content on Doc { front { title } content }",
+        );
+        assert_eq!(spread.depth, 4);
+        assert_eq!(spread.cost, 14);
+
+        let inline = analysis(
+            r#"{ findDocumentById(id: "1") { doc { ... on Doc { content } ... { id } } } }"#,
+        );
+        assert_eq!(inline.depth, 3);
+        assert_eq!(inline.cost, 13);
+
+        // Each spread is charged.
+        let twice = analysis(
+            "{ first: listDocuments { ...count } second: listDocuments { ...count } }
+             fragment count on MultiDocsResponseBody { docsCount }",
+        );
+        assert_eq!(twice.cost, 42);
+    }
+
+    #[test]
+    fn fragment_cycles_are_invalid() {
+        let result = analyze(
+            "{ listDocuments { ...a } }
+             fragment a on MultiDocsResponseBody { ...b }
+             fragment b on MultiDocsResponseBody { docsCount ...a }",
+            None,
+            &Journal,
+            &weights(),
+        );
+        match result {
+            Err(Rejection::Invalid(msg)) => assert!(msg.contains("spreads itself"), "{}", msg),
+            other => panic!("expected an invalid query, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn introspection_can_be_disabled() {
+        let disabled = Limits {
+            introspection: false,
+            ..Limits::default()
+        };
+        for document in &[
+            "{ __schema { types { name } } }",
+            r#"{ __type(name: "Doc") { name } }"#,
+            "{ listDocuments { docsCount } ...meta } fragment meta on Query { __schema { queryType { name } } }",
+        ] {
+            let analysis = analysis(document);
+            assert!(analysis.introspection, "{}", document);
+            assert_eq!(analysis.check(&Limits::default()), Ok(()));
+            assert_eq!(analysis.check(&disabled), Err(Rejection::Introspection));
+        }
+
+        // __typename is not introspection.
+        let typename = analysis("{ __typename listDocuments { docsCount } }");
+        assert!(!typename.introspection);
+        assert_eq!(typename.check(&disabled), Ok(()));
+    }
+}
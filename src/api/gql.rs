@@ -1,17 +1,82 @@
-use juniper::{EmptySubscription, FieldResult, IntoFieldError, RootNode};
-use slog::info;
+use juniper::{EmptySubscription, FieldError, FieldResult, RootNode};
+use slog::{error, info, o, Logger};
+use std::future::Future;
+use std::time::Instant;
 use uuid::Uuid;
 
+use crate::api::analysis::{self, OperationKind};
 use crate::api::model;
+use crate::error;
 use crate::state::State;
 
+/// Name of the operation of requests which do not give one.
+pub const ANONYMOUS: &str = "anonymous";
+
 #[derive(Debug, Clone)]
 pub struct Context {
     pub state: State,
+    /// Identifies the request in the logs and in the errors returned to the client.
+    pub request_id: Uuid,
+    /// Carries the request id and the operation name in every line.
+    pub logger: Logger,
 }
 
 impl juniper::Context for Context {}
 
+impl Context {
+    /// A context outside of an HTTP request, eg for a subcommand.
+    pub fn new(state: State) -> Self {
+        Context::for_request(state, Uuid::new_v4(), ANONYMOUS)
+    }
+
+    pub fn for_request(state: State, request_id: Uuid, operation: &str) -> Self {
+        let logger = state.logger.new(o!(
+            "request_id" => request_id.to_string(),
+            "operation" => String::from(operation)
+        ));
+        Context {
+            state,
+            request_id,
+            logger,
+        }
+    }
+
+    /// Log an error with all its details, and turn it into a GraphQL error which only
+    /// reveals them in debug mode.
+    pub fn field_error(&self, err: error::Error) -> FieldError {
+        self.count_error(&err);
+        let request_id = self.request_id.to_string();
+        if err.code() == error::INTERNAL {
+            error!(self.logger, "{}", err);
+        } else {
+            info!(self.logger, "{}", err; "code" => err.code());
+        }
+        err.into_field_error_with(&request_id, self.state.settings.debug)
+    }
+
+    /// Count the errors of the store reported to the client.
+    pub fn count_error(&self, err: &error::Error) {
+        if let error::Error::DBProvideError { source, .. } = err {
+            self.state.metrics.count_provide_error(source);
+        }
+    }
+
+    /// Run the resolver of a top-level field, recording its latency, and turn its error
+    /// into a GraphQL error. Nested fields are read from the value returned here, so
+    /// they are not timed on their own.
+    pub async fn resolve<T, F>(&self, parent: &str, field: &str, resolver: F) -> FieldResult<T>
+    where
+        F: Future<Output = Result<T, error::Error>>,
+    {
+        let start = Instant::now();
+        let result = resolver.await;
+        self.state
+            .metrics
+            .observe_field(parent, field, result.is_ok(), start.elapsed());
+        result.map_err(|err| self.field_error(err))
+    }
+}
+
 pub struct Query;
 
 #[juniper::graphql_object(
@@ -20,10 +85,10 @@ pub struct Query;
 impl Query {
     /// Returns a list of documents
     async fn list_documents(&self, context: &Context) -> FieldResult<model::MultiDocsResponseBody> {
-        info!(context.state.logger, "Request for documents");
-        model::list_documents(context)
+        info!(context.logger, "Request for documents");
+        context
+            .resolve("Query", "listDocuments", model::list_documents(context))
             .await
-            .map_err(IntoFieldError::into_field_error)
     }
 
     /// Find a document by its id
@@ -32,10 +97,14 @@ impl Query {
         id: Uuid,
         context: &Context,
     ) -> FieldResult<model::SingleDocResponseBody> {
-        info!(context.state.logger, "Request for document with id {}", id);
-        model::find_document_by_id(context, id)
+        info!(context.logger, "Request for document"; "id" => id.to_string());
+        context
+            .resolve(
+                "Query",
+                "findDocumentById",
+                model::find_document_by_id(context, id),
+            )
             .await
-            .map_err(IntoFieldError::into_field_error)
     }
 
     /// Returns a list of documents using full text search.
@@ -44,13 +113,14 @@ impl Query {
         query: String,
         context: &Context,
     ) -> FieldResult<model::MultiDocsResponseBody> {
-        info!(
-            context.state.logger,
-            "Request for documents search using query {}", query
-        );
-        model::list_documents_by_query(context, query.as_str())
+        info!(context.logger, "Request for documents search"; "query" => &query);
+        context
+            .resolve(
+                "Query",
+                "listDocumentsByQuery",
+                model::list_documents_by_query(context, query.as_str()),
+            )
             .await
-            .map_err(IntoFieldError::into_field_error)
     }
 
     /// Returns a list of documents using full text search.
@@ -59,13 +129,14 @@ impl Query {
         tag: String,
         context: &Context,
     ) -> FieldResult<model::MultiDocsResponseBody> {
-        info!(
-            context.state.logger,
-            "Request for documents search using tag {}", tag
-        );
-        model::list_documents_by_tag(context, tag.as_str())
+        info!(context.logger, "Request for documents by tag"; "tag" => &tag);
+        context
+            .resolve(
+                "Query",
+                "listDocumentsByTag",
+                model::list_documents_by_tag(context, tag.as_str()),
+            )
             .await
-            .map_err(IntoFieldError::into_field_error)
     }
 }
 
@@ -80,18 +151,89 @@ impl Mutation {
         doc: model::DocumentRequestBody,
         context: &Context,
     ) -> FieldResult<model::SingleDocResponseBody> {
-        info!(
-            context.state.logger,
-            "Request for document update with id {}", doc.doc.id
-        );
-        model::create_or_update_document(doc, context)
+        info!(context.logger, "Request for document update"; "id" => doc.doc.id.to_string());
+        context
+            .resolve(
+                "Mutation",
+                "createOrUpdateDocument",
+                model::create_or_update_document(doc, context),
+            )
+            .await
+    }
+
+    /// Create a new document, with an id assigned by the server
+    async fn create_document(
+        &self,
+        doc: model::NewDocSpec,
+        context: &Context,
+    ) -> FieldResult<model::SingleDocResponseBody> {
+        info!(context.logger, "Request for document creation"; "title" => &doc.title);
+        context
+            .resolve(
+                "Mutation",
+                "createDocument",
+                model::create_document(doc, context),
+            )
+            .await
+    }
+
+    /// Apply a partial update to an existing document, failing if it does not exist
+    async fn update_document(
+        &self,
+        id: Uuid,
+        patch: model::DocPatch,
+        context: &Context,
+    ) -> FieldResult<model::SingleDocResponseBody> {
+        info!(context.logger, "Request for partial document update"; "id" => id.to_string());
+        context
+            .resolve(
+                "Mutation",
+                "updateDocument",
+                model::update_document(id, patch, context),
+            )
+            .await
+    }
+
+    /// Create or update several documents in a single transaction. Unless atomic is
+    /// false, a single failure aborts the whole batch.
+    async fn create_or_update_documents(
+        &self,
+        docs: Vec<model::DocSpec>,
+        atomic: Option<bool>,
+        context: &Context,
+    ) -> FieldResult<model::BatchDocsResponseBody> {
+        info!(context.logger, "Request for batch update"; "count" => docs.len());
+        context
+            .resolve(
+                "Mutation",
+                "createOrUpdateDocuments",
+                model::create_or_update_documents(docs, atomic.unwrap_or(true), context),
+            )
             .await
-            .map_err(IntoFieldError::into_field_error)
     }
 }
 
-type Schema = RootNode<'static, Query, Mutation, EmptySubscription<Context>>;
+pub type Schema = RootNode<'static, Query, Mutation, EmptySubscription<Context>>;
 
 pub fn schema() -> Schema {
     Schema::new(Query, Mutation, EmptySubscription::new())
 }
+
+/// The types of the schema, as registered with juniper, to weigh the fields of queries.
+impl analysis::Schema for Schema {
+    fn root_type(&self, kind: OperationKind) -> Option<&str> {
+        let root = match kind {
+            OperationKind::Query => Some(self.schema.concrete_query_type()),
+            OperationKind::Mutation => self.schema.concrete_mutation_type(),
+            OperationKind::Subscription => self.schema.concrete_subscription_type(),
+        };
+        root.and_then(|root| root.name())
+    }
+
+    fn field_type(&self, parent: &str, field: &str) -> Option<&str> {
+        self.schema
+            .concrete_type_by_name(parent)
+            .and_then(|parent| parent.field_by_name(field))
+            .map(|field| field.field_type.innermost_name())
+    }
+}
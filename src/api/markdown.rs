@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use url::Url;
+use uuid::Uuid;
+
+use crate::api::model::{
+    default_genre, default_kind, Author, Doc, DocGenre, DocKind, Front, Image, NewDocSpec,
+};
+use crate::api::utils::slugify;
+use crate::error;
+
+const FRONT_MATTER_DELIMITER: &str = "---";
+
+/// The front matter holds the front of the document, along with its version.
+#[derive(Debug, Serialize, Deserialize)]
+struct FrontMatter<F> {
+    #[serde(flatten)]
+    front: F,
+    #[serde(default)]
+    version: i32,
+}
+
+/// Render a document in the authoring format: a YAML front matter holding every
+/// field of the front and the version, followed by the content.
+///
+/// The id is not part of the front matter, it is carried by the file name. The result
+/// can be pushed back with `from_note`, which takes the author and the image as written
+/// here, and ignores the dates and the version.
+pub fn to_markdown(doc: &Doc) -> Result<String, error::Error> {
+    let front_matter = FrontMatter {
+        front: &doc.front,
+        version: doc.version,
+    };
+    let yaml = serde_yaml::to_string(&front_matter).context(error::YAMLError {
+        msg: format!("Could not serialize front matter for document {}", doc.id),
+    })?;
+    // serde_yaml starts the document with its own delimiter.
+    let yaml = yaml.trim_start_matches(FRONT_MATTER_DELIMITER).trim();
+
+    Ok(format!(
+        "{delim}\n{yaml}\n{delim}\n{content}",
+        delim = FRONT_MATTER_DELIMITER,
+        yaml = yaml,
+        content = doc.content
+    ))
+}
+
+/// Read back a document written by `to_markdown`.
+pub fn from_markdown(id: Uuid, text: &str) -> Result<Doc, error::Error> {
+    let (yaml, content) = split_front_matter(text).ok_or(error::Error::MiscError {
+        msg: format!("Could not find front matter for document {}", id),
+    })?;
+
+    let front_matter: FrontMatter<Front> =
+        serde_yaml::from_str(yaml).context(error::YAMLError {
+            msg: format!("Could not deserialize front matter for document {}", id),
+        })?;
+
+    Ok(Doc {
+        id,
+        front: front_matter.front,
+        content: String::from(content),
+        version: front_matter.version,
+    })
+}
+
+/// The front matter written by the `note` script, where the author and the image are
+/// given by name, or by `to_markdown`, where they are given in full.
+#[derive(Debug, Deserialize)]
+struct NoteFront {
+    title: String,
+    #[serde(rename = "abstract")]
+    outline: Option<String>,
+    author: NoteAuthor,
+    image: NoteImage,
+    tags: Option<NoteTags>,
+    #[serde(default = "default_kind")]
+    kind: DocKind,
+    #[serde(default = "default_genre")]
+    genre: DocGenre,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NoteAuthor {
+    Name(String),
+    Author(Author),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NoteImage {
+    Name(String),
+    Image(Image),
+}
+
+/// Tags are either a YAML list, or a single line separated by commas or spaces.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NoteTags {
+    List(Vec<String>),
+    Line(String),
+}
+
+impl NoteTags {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            NoteTags::List(tags) => tags,
+            NoteTags::Line(line) => line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+/// Read a note, as written by the `note` script or by `to_markdown`, into a new document.
+///
+/// When the author and the image are given by name, their resources are built from
+/// `resources`: `<resources>/authors/<author slug>` and `<resources>/images/<image>`,
+/// unless the image is already a URL, and the author of the image is the author of the
+/// document. The `published` date is ignored, as documents are dated by the service.
+pub fn from_note(text: &str, resources: &Url) -> Result<NewDocSpec, error::Error> {
+    let (yaml, content) = split_front_matter(text).ok_or(error::Error::MiscError {
+        msg: String::from("Could not find front matter in note"),
+    })?;
+
+    let front: NoteFront = serde_yaml::from_str(yaml).context(error::YAMLError {
+        msg: String::from("Could not deserialize note front matter"),
+    })?;
+
+    let author = match front.author {
+        NoteAuthor::Author(author) => author,
+        NoteAuthor::Name(fullname) => Author {
+            resource: resource(resources, &format!("authors/{}", slugify(&fullname)))?,
+            fullname,
+        },
+    };
+    let image = match front.image {
+        NoteImage::Image(image) => image,
+        NoteImage::Name(title) => Image {
+            resource: match Url::parse(&title) {
+                Ok(url) => url.to_string(),
+                Err(_) => resource(resources, &format!("images/{}", title))?,
+            },
+            title,
+            author: Author {
+                fullname: author.fullname.clone(),
+                resource: author.resource.clone(),
+            },
+        },
+    };
+
+    Ok(NewDocSpec {
+        title: front.title,
+        outline: front.outline.unwrap_or_default(),
+        author_fullname: author.fullname,
+        author_resource: author.resource,
+        tags: front.tags.map(NoteTags::into_vec).unwrap_or_default(),
+        image_title: image.title,
+        image_resource: image.resource,
+        image_author_fullname: image.author.fullname,
+        image_author_resource: image.author.resource,
+        kind: front.kind,
+        genre: front.genre,
+        content: String::from(content),
+    })
+}
+
+fn resource(base: &Url, path: &str) -> Result<String, error::Error> {
+    base.join(path)
+        .map(|url| url.to_string())
+        .map_err(|err| error::Error::MiscError {
+            msg: format!("Could not build resource {} from {}: {}", path, base, err),
+        })
+}
+
+/// Split a markdown text into its front matter and its content. Lines may end with
+/// `\n` or `\r\n`.
+///
+/// The content is returned verbatim, so that a round trip does not alter it.
+pub fn split_front_matter(text: &str) -> Option<(&str, &str)> {
+    let rest = strip_delimiter(text)?;
+
+    let mut start = 0;
+    while let Some(i) = rest[start..].find('\n') {
+        let end = start + i;
+        if let Some(content) = strip_delimiter(&rest[end + 1..]) {
+            let yaml = &rest[..end];
+            return Some((yaml.strip_suffix('\r').unwrap_or(yaml), content));
+        }
+        start = end + 1;
+    }
+    None
+}
+
+/// The text following a delimiter line.
+fn strip_delimiter(text: &str) -> Option<&str> {
+    let rest = text.strip_prefix(FRONT_MATTER_DELIMITER)?;
+    rest.strip_prefix('\n')
+        .or_else(|| rest.strip_prefix("\r\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn doc(content: &str) -> Doc {
+        let author = Author {
+            fullname: String::from("Jane Doe"),
+            resource: String::from("https://example.com/authors/jane-doe"),
+        };
+        Doc {
+            id: Uuid::new_v4(),
+            front: Front {
+                title: String::from("Async: the --- hard parts"),
+                outline: String::from("Futures, 'pinning' and \"wakers\".\nOn two lines"),
+                author: Author {
+                    fullname: author.fullname.clone(),
+                    resource: author.resource.clone(),
+                },
+                tags: vec![String::from("rust"), String::from("key: value")],
+                image: Image {
+                    title: String::from("#cover"),
+                    resource: String::from("https://example.com/images/cover.jpg"),
+                    author,
+                },
+                kind: DocKind::Post,
+                genre: DocGenre::Background,
+                created_at: Utc.ymd(2020, 12, 1).and_hms_micro(10, 20, 30, 123_456),
+                updated_at: Utc.ymd(2020, 12, 2).and_hms(8, 0, 0),
+            },
+            content: String::from(content),
+            version: 7,
+        }
+    }
+
+    #[test]
+    fn markdown_round_trip() {
+        for content in &[
+            "",
+            "# Title\n\nSome text.\n",
+            "\n---\nA delimiter in the content\n---\n",
+            "No trailing newline  ",
+        ] {
+            let doc = doc(content);
+            let text = to_markdown(&doc).unwrap();
+            assert_eq!(from_markdown(doc.id, &text).unwrap(), doc);
+        }
+    }
+
+    #[test]
+    fn exported_documents_can_be_pushed() {
+        let doc = doc("# Title\n\nSome text.\n");
+        let text = to_markdown(&doc).unwrap();
+        let resources = Url::parse("https://resources.example.com/").unwrap();
+        let spec = from_note(&text, &resources).unwrap();
+
+        assert_eq!(spec.title, doc.front.title);
+        assert_eq!(spec.outline, doc.front.outline);
+        assert_eq!(spec.author_fullname, doc.front.author.fullname);
+        assert_eq!(spec.author_resource, doc.front.author.resource);
+        assert_eq!(spec.tags, doc.front.tags);
+        assert_eq!(spec.image_title, doc.front.image.title);
+        assert_eq!(spec.image_resource, doc.front.image.resource);
+        assert_eq!(spec.image_author_fullname, doc.front.image.author.fullname);
+        assert_eq!(spec.image_author_resource, doc.front.image.author.resource);
+        assert_eq!(spec.kind, doc.front.kind);
+        assert_eq!(spec.genre, doc.front.genre);
+        assert_eq!(spec.content, doc.content);
+    }
+
+    #[test]
+    fn notes_name_their_author_and_image() {
+        let text = "---\ntitle: Ownership\nauthor: Jane Doe\nimage: cover.jpg\ntags: rust, memory\n---\nContent\n";
+        let resources = Url::parse("https://resources.example.com/").unwrap();
+        let spec = from_note(text, &resources).unwrap();
+
+        assert_eq!(spec.author_fullname, "Jane Doe");
+        assert_eq!(
+            spec.author_resource,
+            "https://resources.example.com/authors/jane-doe"
+        );
+        assert_eq!(spec.image_title, "cover.jpg");
+        assert_eq!(
+            spec.image_resource,
+            "https://resources.example.com/images/cover.jpg"
+        );
+        assert_eq!(spec.image_author_fullname, "Jane Doe");
+        assert_eq!(spec.tags, vec!["rust", "memory"]);
+        assert_eq!(spec.content, "Content\n");
+    }
+
+    #[test]
+    fn front_matter_with_crlf_line_endings() {
+        let text = "---\r\ntitle: Ownership\r\n---\r\nContent\r\n";
+        assert_eq!(
+            split_front_matter(text),
+            Some(("title: Ownership", "Content\r\n"))
+        );
+        assert_eq!(
+            split_front_matter("---\ntitle: Ownership\n---\n"),
+            Some(("title: Ownership", ""))
+        );
+        assert_eq!(split_front_matter("title: Ownership\n---\n"), None);
+        assert_eq!(split_front_matter("---\ntitle: Ownership\n"), None);
+    }
+}
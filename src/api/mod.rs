@@ -1,3 +1,6 @@
+pub mod analysis;
 pub mod gql;
+pub mod markdown;
 pub mod model;
 pub mod utils;
+pub mod validation;
@@ -1,23 +1,29 @@
 use chrono::{DateTime, Utc};
-use juniper::futures::TryFutureExt;
 use juniper::{GraphQLEnum, GraphQLInputObject, GraphQLObject};
-use serde::{Deserialize, Serialize};
+use serde::ser::Error as _;
+use serde::{Deserialize, Serialize, Serializer};
 use slog::info;
 use snafu::ResultExt;
-use sqlx::Connection;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use uuid::Uuid;
 
 use crate::api::gql::Context;
+use crate::api::utils;
+use crate::api::validation::{self, Violation};
 use crate::db::model as db;
 use crate::db::model::ProvideJournal;
-use crate::db::Db;
+use crate::db::tx::{self, TxOptions};
+use crate::db::JournalTransaction;
 use crate::error;
+use crate::settings::Validation as Limits;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, GraphQLEnum)]
 #[serde(rename_all = "camelCase")]
 pub enum DocKind {
+    #[serde(alias = "DOC")]
     Doc,
+    #[serde(alias = "POST")]
     Post,
 }
 
@@ -42,9 +48,13 @@ impl From<DocKind> for db::DocKind {
 #[derive(Debug, PartialEq, Serialize, Deserialize, GraphQLEnum)]
 #[serde(rename_all = "camelCase")]
 pub enum DocGenre {
+    #[serde(alias = "TUTORIAL")]
     Tutorial,
+    #[serde(alias = "HOWTO")]
     Howto,
+    #[serde(alias = "BACKGROUND")]
     Background,
+    #[serde(alias = "REFERENCE")]
     Reference,
 }
 
@@ -70,6 +80,30 @@ impl From<DocGenre> for db::DocGenre {
     }
 }
 
+/// Serialize an enum with its GraphQL name, eg `DOC`, rather than its serde name, eg
+/// `doc`, as GraphQL variables require. Both names are accepted when deserializing.
+fn graphql_enum<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize,
+    S: Serializer,
+{
+    match serde_json::to_value(value).map_err(S::Error::custom)? {
+        serde_json::Value::String(name) => serializer.serialize_str(&name.to_uppercase()),
+        _ => Err(S::Error::custom("expected a unit variant")),
+    }
+}
+
+fn graphql_enum_option<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize,
+    S: Serializer,
+{
+    match value {
+        Some(value) => graphql_enum(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, GraphQLObject)]
 #[serde(rename_all = "camelCase")]
 pub struct Author {
@@ -87,6 +121,18 @@ impl From<db::AuthorEntity> for Author {
     }
 }
 
+impl From<Author> for db::AuthorEntity {
+    fn from(author: Author) -> Self {
+        let Author { fullname, resource } = author;
+
+        db::AuthorEntity {
+            id: None,
+            fullname,
+            resource,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, GraphQLObject)]
 #[serde(rename_all = "camelCase")]
 pub struct Image {
@@ -112,6 +158,23 @@ impl From<db::ImageEntity> for Image {
     }
 }
 
+impl From<Image> for db::ImageEntity {
+    fn from(image: Image) -> Self {
+        let Image {
+            title,
+            resource,
+            author,
+        } = image;
+
+        db::ImageEntity {
+            id: None,
+            title,
+            author: db::AuthorEntity::from(author),
+            resource,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, GraphQLObject)]
 #[serde(rename_all = "camelCase")]
 pub struct Front {
@@ -143,6 +206,8 @@ pub struct Doc {
     pub id: Uuid,
     pub front: Front,
     pub content: String,
+    /// Incremented on every update, see `DocSpec::expected_version`.
+    pub version: i32,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, GraphQLObject)]
@@ -166,6 +231,7 @@ impl From<db::DocEntity> for Doc {
             content,
             created_at,
             updated_at,
+            version,
             ..
         } = entity;
 
@@ -183,6 +249,44 @@ impl From<db::DocEntity> for Doc {
                 updated_at,
             },
             content,
+            version,
+        }
+    }
+}
+
+impl From<Doc> for db::DocEntity {
+    fn from(doc: Doc) -> Self {
+        let Doc {
+            id,
+            front,
+            content,
+            version,
+        } = doc;
+        let Front {
+            title,
+            outline,
+            author,
+            tags,
+            image,
+            kind,
+            genre,
+            created_at,
+            updated_at,
+        } = front;
+
+        db::DocEntity {
+            id,
+            title,
+            outline,
+            author: db::AuthorEntity::from(author),
+            tags,
+            image: db::ImageEntity::from(image),
+            kind: db::DocKind::from(kind),
+            genre: db::DocGenre::from(genre),
+            content,
+            created_at,
+            updated_at,
+            version,
         }
     }
 }
@@ -254,6 +358,7 @@ impl From<Vec<ShortDoc>> for MultiDocsResponseBody {
 // I would have like to use Doc to create a new document, but it doesn't work. So this is
 // the I don't want to think about it solution...
 #[derive(Debug, Deserialize, Serialize, GraphQLInputObject)]
+#[serde(rename_all = "camelCase")]
 pub struct DocSpec {
     pub id: Uuid,
     pub title: String,
@@ -265,9 +370,14 @@ pub struct DocSpec {
     pub image_resource: String,
     pub image_author_fullname: String,
     pub image_author_resource: String,
+    #[serde(serialize_with = "graphql_enum")]
     pub kind: DocKind,
+    #[serde(serialize_with = "graphql_enum")]
     pub genre: DocGenre,
     pub content: String,
+    /// When given, the update is rejected unless the document is still at this
+    /// version. A document that does not exist yet is at version 0.
+    pub expected_version: Option<i32>,
 }
 
 impl From<DocSpec> for db::DocEntity {
@@ -320,44 +430,238 @@ impl From<DocSpec> for db::DocEntity {
             content,
             created_at: Utc::now(),
             updated_at: Utc::now(),
+            // The version is maintained by the database.
+            version: 0,
+        }
+    }
+}
+
+/// The specification of a new document, whose id is assigned by the server.
+#[derive(Debug, Deserialize, Serialize, GraphQLInputObject)]
+#[serde(rename_all = "camelCase")]
+pub struct NewDocSpec {
+    pub title: String,
+    pub outline: String,
+    pub author_fullname: String,
+    pub author_resource: String,
+    pub tags: Vec<String>,
+    pub image_title: String,
+    pub image_resource: String,
+    pub image_author_fullname: String,
+    pub image_author_resource: String,
+    #[serde(serialize_with = "graphql_enum")]
+    pub kind: DocKind,
+    #[serde(serialize_with = "graphql_enum")]
+    pub genre: DocGenre,
+    pub content: String,
+}
+
+impl NewDocSpec {
+    pub fn with_id(self, id: Uuid) -> DocSpec {
+        let NewDocSpec {
+            title,
+            outline,
+            author_fullname,
+            author_resource,
+            tags,
+            image_title,
+            image_resource,
+            image_author_fullname,
+            image_author_resource,
+            kind,
+            genre,
+            content,
+        } = self;
+
+        DocSpec {
+            id,
+            title,
+            outline,
+            author_fullname,
+            author_resource,
+            tags,
+            image_title,
+            image_resource,
+            image_author_fullname,
+            image_author_resource,
+            kind,
+            genre,
+            content,
+            // A new document cannot conflict with a previous version.
+            expected_version: None,
+        }
+    }
+}
+
+/// The specification to write back a document, which is rejected if the document changed
+/// since it was read.
+impl From<Doc> for DocSpec {
+    fn from(doc: Doc) -> Self {
+        let Doc {
+            id,
+            front,
+            content,
+            version,
+        } = doc;
+
+        DocSpec {
+            id,
+            title: front.title,
+            outline: front.outline,
+            author_fullname: front.author.fullname,
+            author_resource: front.author.resource,
+            tags: front.tags,
+            image_title: front.image.title,
+            image_resource: front.image.resource,
+            image_author_fullname: front.image.author.fullname,
+            image_author_resource: front.image.author.resource,
+            kind: front.kind,
+            genre: front.genre,
+            content,
+            expected_version: Some(version),
+        }
+    }
+}
+
+/// A partial update of a document. Fields which are not given are left untouched.
+#[derive(Debug, Default, Deserialize, Serialize, GraphQLInputObject)]
+#[serde(rename_all = "camelCase")]
+pub struct DocPatch {
+    pub title: Option<String>,
+    pub outline: Option<String>,
+    pub author_fullname: Option<String>,
+    pub author_resource: Option<String>,
+    /// Replaces all the tags
+    pub tags: Option<Vec<String>>,
+    /// Tags appended to the document, unless already present
+    pub add_tags: Option<Vec<String>>,
+    /// Tags removed from the document
+    pub remove_tags: Option<Vec<String>>,
+    pub image_title: Option<String>,
+    pub image_resource: Option<String>,
+    pub image_author_fullname: Option<String>,
+    pub image_author_resource: Option<String>,
+    #[serde(serialize_with = "graphql_enum_option")]
+    pub kind: Option<DocKind>,
+    #[serde(serialize_with = "graphql_enum_option")]
+    pub genre: Option<DocGenre>,
+    pub content: Option<String>,
+    /// When given, the update is rejected unless the document is still at this version.
+    pub expected_version: Option<i32>,
+}
+
+impl From<DocPatch> for db::DocPatchEntity {
+    fn from(patch: DocPatch) -> Self {
+        let DocPatch {
+            title,
+            outline,
+            author_fullname,
+            author_resource,
+            tags,
+            add_tags,
+            remove_tags,
+            image_title,
+            image_resource,
+            image_author_fullname,
+            image_author_resource,
+            kind,
+            genre,
+            content,
+            ..
+        } = patch;
+
+        db::DocPatchEntity {
+            title,
+            outline,
+            author_fullname,
+            author_resource,
+            tags,
+            add_tags,
+            remove_tags,
+            image_title,
+            image_resource,
+            image_author_fullname,
+            image_author_resource,
+            kind: kind.map(db::DocKind::from),
+            genre: genre.map(db::DocGenre::from),
+            content,
         }
     }
 }
 
 #[derive(Debug, Deserialize, Serialize, GraphQLInputObject)]
+#[serde(rename_all = "camelCase")]
 pub struct DocumentRequestBody {
     pub doc: DocSpec,
 }
 
-/// Retrieve all documents
-pub async fn list_documents(context: &Context) -> Result<MultiDocsResponseBody, error::Error> {
-    async move {
-        let pool = &context.state.pool;
+/// Why a single document of a batch was not applied
+#[derive(Debug, Deserialize, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchItemError {
+    /// Same codes as the `code` extension of GraphQL errors, plus `ABORTED` for documents
+    /// discarded because of another document of the batch.
+    pub code: String,
+    pub message: String,
+}
 
-        let mut tx = pool
-            .conn()
-            .and_then(Connection::begin)
-            .await
-            .context(error::DBError {
-                msg: "could not initiate transaction",
-            })?;
+/// Code for documents of an atomic batch which are discarded because another document
+/// of the batch failed.
+pub const ABORTED: &str = "ABORTED";
 
-        let entities = tx
-            .get_all_documents()
-            .await
-            .context(error::DBProvideError {
-                msg: "Could not get all them documents",
-            })?;
+/// The outcome for a single document of a batch
+#[derive(Debug, Deserialize, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchItemResult {
+    pub id: Uuid,
+    pub doc: Option<Doc>,
+    pub error: Option<BatchItemError>,
+}
 
-        let documents = entities.into_iter().map(ShortDoc::from).collect::<Vec<_>>();
+impl BatchItemResult {
+    fn failed<S: Into<String>>(id: Uuid, code: &str, message: S) -> Self {
+        Self {
+            id,
+            doc: None,
+            error: Some(BatchItemError {
+                code: String::from(code),
+                message: message.into(),
+            }),
+        }
+    }
+}
 
-        tx.commit().await.context(error::DBError {
-            msg: "could not commit transaction",
-        })?;
+/// The response body for a batch of documents, with one result per document, in the
+/// order of the request.
+#[derive(Debug, Deserialize, Serialize, GraphQLObject)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchDocsResponseBody {
+    pub results: Vec<BatchItemResult>,
+    /// Whether the transaction was committed.
+    pub committed: bool,
+}
 
-        Ok(MultiDocsResponseBody::from(documents))
-    }
-    .await
+/// Retrieve all documents
+pub async fn list_documents(context: &Context) -> Result<MultiDocsResponseBody, error::Error> {
+    let entities = tx::run(
+        &*context.state.store,
+        TxOptions::read_only(),
+        &context.logger,
+        |mut tx| async move {
+            let entities = tx
+                .journal()
+                .get_all_documents()
+                .await
+                .context(error::DBProvideError {
+                    msg: "Could not get all them documents",
+                });
+            (tx, entities)
+        },
+    )
+    .await?;
+
+    let documents = entities.into_iter().map(ShortDoc::from).collect::<Vec<_>>();
+    Ok(MultiDocsResponseBody::from(documents))
 }
 
 /// search all documents for matching query
@@ -365,33 +669,25 @@ pub async fn list_documents_by_query(
     context: &Context,
     query: &str,
 ) -> Result<MultiDocsResponseBody, error::Error> {
-    async move {
-        let pool = &context.state.pool;
-
-        let mut tx = pool
-            .conn()
-            .and_then(Connection::begin)
-            .await
-            .context(error::DBError {
-                msg: "could not initiate transaction",
-            })?;
-
-        let entities =
-            tx.get_all_documents_by_query(query)
+    let entities = tx::run(
+        &*context.state.store,
+        TxOptions::read_only(),
+        &context.logger,
+        |mut tx| async move {
+            let entities = tx
+                .journal()
+                .get_all_documents_by_query(query)
                 .await
                 .context(error::DBProvideError {
                     msg: "Could not get all them documents",
-                })?;
-
-        let documents = entities.into_iter().map(ShortDoc::from).collect::<Vec<_>>();
-
-        tx.commit().await.context(error::DBError {
-            msg: "could not commit transaction",
-        })?;
+                });
+            (tx, entities)
+        },
+    )
+    .await?;
 
-        Ok(MultiDocsResponseBody::from(documents))
-    }
-    .await
+    let documents = entities.into_iter().map(ShortDoc::from).collect::<Vec<_>>();
+    Ok(MultiDocsResponseBody::from(documents))
 }
 
 /// search all documents for matching tag
@@ -399,112 +695,441 @@ pub async fn list_documents_by_tag(
     context: &Context,
     tag: &str,
 ) -> Result<MultiDocsResponseBody, error::Error> {
-    async move {
-        let pool = &context.state.pool;
+    let entities =
+        tx::run(
+            &*context.state.store,
+            TxOptions::read_only(),
+            &context.logger,
+            |mut tx| async move {
+                let entities = tx.journal().get_all_documents_by_tag(tag).await.context(
+                    error::DBProvideError {
+                        msg: "Could not get all them documents",
+                    },
+                );
+                (tx, entities)
+            },
+        )
+        .await?;
 
-        let mut tx = pool
-            .conn()
-            .and_then(Connection::begin)
-            .await
-            .context(error::DBError {
-                msg: "could not initiate transaction",
-            })?;
+    let documents = entities.into_iter().map(ShortDoc::from).collect::<Vec<_>>();
+    Ok(MultiDocsResponseBody::from(documents))
+}
 
-        let entities = tx
-            .get_all_documents_by_tag(tag)
-            .await
-            .context(error::DBProvideError {
-                msg: "Could not get all them documents",
-            })?;
+/// Retrieve a single document given its id
+pub async fn find_document_by_id(
+    context: &Context,
+    id: Uuid,
+) -> Result<SingleDocResponseBody, error::Error> {
+    let entity = tx::run(
+        &*context.state.store,
+        TxOptions::read_only(),
+        &context.logger,
+        |mut tx| async move {
+            let entity = tx
+                .journal()
+                .get_document_by_id(id)
+                .await
+                .context(error::DBProvideError {
+                    msg: "Could not get document by id",
+                });
+            (tx, entity)
+        },
+    )
+    .await;
 
-        let documents = entities.into_iter().map(ShortDoc::from).collect::<Vec<_>>();
+    match entity {
+        Err(err) => {
+            info!(context.logger, "DB Provide Error: {:?}", err);
+            Err(err)
+        }
+        Ok(None) => Ok(SingleDocResponseBody { doc: None }),
+        Ok(Some(entity)) => {
+            let doc = Doc::from(entity);
+            Ok(SingleDocResponseBody::from(doc))
+        }
+    }
+}
 
-        tx.commit().await.context(error::DBError {
-            msg: "could not commit transaction",
-        })?;
+/// Reject the update of a document that is no longer at the version the client expects.
+async fn check_version<P: ProvideJournal + Send + ?Sized>(
+    provider: &mut P,
+    id: Uuid,
+    expected_version: Option<i32>,
+) -> db::ProvideResult<()> {
+    if let Some(expected) = expected_version {
+        let current = provider.get_document_version(id).await?.unwrap_or(0);
+        if current != expected {
+            return Err(db::ProvideError::VersionConflict { current });
+        }
+    }
+    Ok(())
+}
 
-        Ok(MultiDocsResponseBody::from(documents))
+/// Create or update a document, unless it is no longer at the version the client
+/// expects. A document expected not to exist yet, at version 0, is only inserted, as
+/// there is no row to lock: of two clients creating it concurrently, one gets a conflict.
+async fn write_document<P: ProvideJournal + Send + ?Sized>(
+    provider: &mut P,
+    doc: &db::DocEntity,
+    expected_version: Option<i32>,
+) -> db::ProvideResult<db::DocEntity> {
+    if expected_version == Some(0) {
+        return match provider.create_document(doc).await? {
+            Some(doc) => Ok(doc),
+            None => {
+                let current = provider.get_document_version(doc.id).await?.unwrap_or(0);
+                Err(db::ProvideError::VersionConflict { current })
+            }
+        };
     }
-    .await
+    check_version(provider, doc.id, expected_version).await?;
+    provider.create_or_update_document(doc).await
 }
 
-/// Retrieve a single document given its id
-pub async fn find_document_by_id(
+/// Create a new document, or update it if it exists already
+pub async fn create_or_update_document(
+    doc_request: DocumentRequestBody,
     context: &Context,
-    id: Uuid,
 ) -> Result<SingleDocResponseBody, error::Error> {
-    async move {
-        let pool = &context.state.pool;
+    let DocumentRequestBody { doc } = doc_request;
+    let limits = &context.state.settings.validation;
+    check_violations(validation::validate_doc_spec(&doc, limits, "doc.doc"))?;
+    let expected_version = doc.expected_version;
+    let doc = &db::DocEntity::from(doc);
 
-        let mut tx = pool
-            .conn()
-            .and_then(Connection::begin)
-            .await
-            .context(error::DBError {
-                msg: "could not initiate transaction",
-            })?;
+    let resp = tx::run(
+        &*context.state.store,
+        TxOptions::read_write(),
+        &context.logger,
+        |mut tx| async move {
+            let resp = write_document(tx.journal(), doc, expected_version)
+                .await
+                .context(error::DBProvideError {
+                    msg: "Could not create or update document",
+                });
+            (tx, resp)
+        },
+    )
+    .await?;
 
-        let entity = tx
-            .get_document_by_id(id)
-            .await
+    let doc = Doc::from(resp);
+    Ok(SingleDocResponseBody::from(doc))
+}
+
+/// Create a new document, with an id assigned by the server.
+///
+/// Fails if there is already a document with the same title, or the same slug. Documents
+/// created concurrently are caught by the unique indexes on titles and slugs, and also
+/// fail with a conflict.
+pub async fn create_document(
+    doc: NewDocSpec,
+    context: &Context,
+) -> Result<SingleDocResponseBody, error::Error> {
+    let limits = &context.state.settings.validation;
+    check_violations(validation::validate_new_doc_spec(&doc, limits, "doc"))?;
+    let doc = &db::DocEntity::from(doc.with_id(Uuid::new_v4()));
+    let slug = &utils::slugify(&doc.title);
+
+    let resp = tx::run(
+        &*context.state.store,
+        TxOptions::read_write(),
+        &context.logger,
+        |mut tx| async move {
+            let resp = insert_document(tx.journal(), doc, slug).await;
+            (tx, resp)
+        },
+    )
+    .await?;
+
+    let doc = Doc::from(resp);
+    Ok(SingleDocResponseBody::from(doc))
+}
+
+async fn insert_document(
+    conn: &mut (dyn ProvideJournal + Send),
+    doc: &db::DocEntity,
+    slug: &str,
+) -> Result<db::DocEntity, error::Error> {
+    let existing = conn
+        .find_document_by_title_or_slug(&doc.title, slug)
+        .await
+        .context(error::DBProvideError {
+            msg: "Could not search documents by title",
+        })?;
+
+    if let Some(existing) = existing {
+        return Err(error::Error::DBProvideError {
+            msg: String::from("Could not create document"),
+            source: db::ProvideError::UniqueViolation {
+                details: format!(
+                    "Document {} already has title '{}' or slug '{}'",
+                    existing, doc.title, slug
+                ),
+            },
+        });
+    }
+
+    conn.create_or_update_document(doc)
+        .await
+        .context(error::DBProvideError {
+            msg: "Could not create document",
+        })
+}
+
+/// Apply a partial update to an existing document, failing if it does not exist
+pub async fn update_document(
+    id: Uuid,
+    patch: DocPatch,
+    context: &Context,
+) -> Result<SingleDocResponseBody, error::Error> {
+    let limits = &context.state.settings.validation;
+    check_violations(validation::validate_doc_patch(&patch, limits, "patch"))?;
+    let expected_version = patch.expected_version;
+    let patch = &db::DocPatchEntity::from(patch);
+
+    let resp = tx::run(
+        &*context.state.store,
+        TxOptions::read_write(),
+        &context.logger,
+        |mut tx| async move {
+            let conn = tx.journal();
+            let resp = match check_version(conn, id, expected_version).await {
+                Ok(()) => conn.update_document(id, patch).await,
+                Err(err) => Err(err),
+            }
+            .and_then(|resp| resp.ok_or(db::ProvideError::NotFound))
             .context(error::DBProvideError {
-                msg: "Could not get document by id",
+                msg: format!("Could not update document {}", id),
             });
+            (tx, resp)
+        },
+    )
+    .await?;
 
-        match entity {
-            Err(err) => {
-                info!(context.state.logger, "DB Provide Error: {:?}", err);
-                Err(err)
+    let doc = Doc::from(resp);
+    Ok(SingleDocResponseBody::from(doc))
+}
+
+/// Fail with all the violations, if any
+fn check_violations(violations: Vec<Violation>) -> Result<(), error::Error> {
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(error::Error::ValidationError { violations })
+    }
+}
+
+/// Check a batch before touching the database, returning the violations, if any, for
+/// each document.
+fn check_batch(docs: &[DocSpec], limits: &Limits) -> Vec<Option<String>> {
+    let mut ids = HashSet::new();
+    let mut titles = HashSet::new();
+    docs.iter()
+        .enumerate()
+        .map(|(i, doc)| {
+            let prefix = format!("docs[{}]", i);
+            let mut violations = validation::validate_doc_spec(doc, limits, &prefix);
+            if !ids.insert(doc.id) {
+                violations.push(Violation {
+                    field: format!("{}.id", prefix),
+                    code: validation::DUPLICATE,
+                    message: format!("duplicate id {} in batch", doc.id),
+                });
             }
-            Ok(entity) => {
-                tx.commit().await.context(error::DBError {
-                    msg: "could not commit transaction",
-                })?;
-                match entity {
-                    None => Ok(SingleDocResponseBody { doc: None }),
-                    Some(entity) => {
-                        let doc = Doc::from(entity);
-                        Ok(SingleDocResponseBody::from(doc))
-                    }
-                }
+            if !titles.insert(doc.title.as_str()) {
+                violations.push(Violation {
+                    field: format!("{}.title", prefix),
+                    code: validation::DUPLICATE,
+                    message: format!("duplicate title '{}' in batch", doc.title),
+                });
             }
-        }
+            if violations.is_empty() {
+                None
+            } else {
+                let violations = violations
+                    .iter()
+                    .map(Violation::to_string)
+                    .collect::<Vec<_>>();
+                Some(violations.join("; "))
+            }
+        })
+        .collect()
+}
+
+/// A document of a batch, ready to be applied unless it is invalid
+enum BatchItem {
+    Invalid {
+        id: Uuid,
+        violations: String,
+    },
+    Valid {
+        expected_version: Option<i32>,
+        doc: db::DocEntity,
+    },
+}
+
+/// Create or update a batch of documents in a single transaction.
+///
+/// When atomic, any failure aborts the whole batch. Otherwise each document is applied in
+/// its own savepoint, and only the failing documents are discarded.
+pub async fn create_or_update_documents(
+    docs: Vec<DocSpec>,
+    atomic: bool,
+    context: &Context,
+) -> Result<BatchDocsResponseBody, error::Error> {
+    let violations = check_batch(&docs, &context.state.settings.validation);
+
+    if atomic && violations.iter().any(Option::is_some) {
+        let results = docs
+            .iter()
+            .zip(violations)
+            .map(|(doc, violation)| match violation {
+                Some(violation) => BatchItemResult::failed(doc.id, error::VALIDATION, violation),
+                None => BatchItemResult::failed(
+                    doc.id,
+                    ABORTED,
+                    "Not applied: the batch contains invalid documents",
+                ),
+            })
+            .collect();
+        return Ok(BatchDocsResponseBody {
+            results,
+            committed: false,
+        });
     }
+
+    let items = docs
+        .into_iter()
+        .zip(violations)
+        .map(|(doc, violation)| match violation {
+            Some(violations) => BatchItem::Invalid {
+                id: doc.id,
+                violations,
+            },
+            None => BatchItem::Valid {
+                expected_version: doc.expected_version,
+                doc: db::DocEntity::from(doc),
+            },
+        })
+        .collect::<Vec<_>>();
+    let items = items.as_slice();
+
+    tx::run(
+        &*context.state.store,
+        TxOptions::read_write(),
+        &context.logger,
+        |mut tx| async move {
+            let resp = apply_batch(tx.as_mut(), items, atomic, context).await;
+            (tx, resp)
+        },
+    )
     .await
 }
 
-/// Create a new document, or update it if it exists already
-pub async fn create_or_update_document(
-    doc_request: DocumentRequestBody,
+async fn apply_batch(
+    tx: &mut dyn JournalTransaction,
+    items: &[BatchItem],
+    atomic: bool,
     context: &Context,
-) -> Result<SingleDocResponseBody, error::Error> {
-    async move {
-        let DocumentRequestBody { doc } = doc_request;
-        let doc = db::DocEntity::from(doc);
+) -> Result<BatchDocsResponseBody, error::Error> {
+    // Rolling back to this savepoint discards the whole batch.
+    tx.savepoint("batch").await.context(error::DBProvideError {
+        msg: "could not create savepoint",
+    })?;
+
+    let mut results = Vec::with_capacity(items.len());
+    let mut aborted = false;
 
-        let pool = &context.state.pool;
+    for item in items {
+        let (expected_version, doc) = match item {
+            BatchItem::Invalid { id, violations } => {
+                results.push(BatchItemResult::failed(
+                    *id,
+                    error::VALIDATION,
+                    violations.as_str(),
+                ));
+                continue;
+            }
+            BatchItem::Valid {
+                expected_version,
+                doc,
+            } => (*expected_version, doc),
+        };
+        let id = doc.id;
+
+        if aborted {
+            results.push(BatchItemResult::failed(
+                id,
+                ABORTED,
+                "Not applied: the batch was aborted",
+            ));
+            continue;
+        }
 
-        let mut tx = pool
-            .conn()
-            .and_then(Connection::begin)
+        tx.savepoint("batch_item")
             .await
-            .context(error::DBError {
-                msg: "could not initiate transaction",
+            .context(error::DBProvideError {
+                msg: "could not create savepoint",
             })?;
 
-        let resp =
-            ProvideJournal::create_or_update_document(&mut tx as &mut sqlx::PgConnection, &doc)
-                .await
-                .context(error::DBProvideError {
-                    msg: "Could not create or update document",
-                })?;
+        let resp = write_document(tx.journal(), doc, expected_version).await;
 
-        tx.commit().await.context(error::DBError {
-            msg: "could not retrieve indexes",
-        })?;
+        match resp {
+            Ok(resp) => {
+                tx.release_savepoint("batch_item")
+                    .await
+                    .context(error::DBProvideError {
+                        msg: "could not release savepoint",
+                    })?;
+                results.push(BatchItemResult {
+                    id,
+                    doc: Some(Doc::from(resp)),
+                    error: None,
+                });
+            }
+            Err(err) => {
+                let err = error::Error::DBProvideError {
+                    msg: format!("Could not create or update document {}", id),
+                    source: err,
+                };
+                // The whole batch is run again by the transaction runner.
+                if err.is_retryable() {
+                    return Err(err);
+                }
+                context.count_error(&err);
+                info!(context.logger, "{}", err);
+                tx.rollback_to_savepoint("batch_item")
+                    .await
+                    .context(error::DBProvideError {
+                        msg: "could not rollback to savepoint",
+                    })?;
+                results.push(BatchItemResult::failed(
+                    id,
+                    err.code(),
+                    err.public_message(),
+                ));
+                aborted = atomic;
+            }
+        }
+    }
 
-        let doc = Doc::from(resp);
-        Ok(SingleDocResponseBody::from(doc))
+    if aborted {
+        tx.rollback_to_savepoint("batch")
+            .await
+            .context(error::DBProvideError {
+                msg: "could not rollback to savepoint",
+            })?;
+        for result in results.iter_mut().filter(|result| result.error.is_none()) {
+            *result = BatchItemResult::failed(
+                result.id,
+                ABORTED,
+                "Rolled back: another document of the batch failed",
+            );
+        }
     }
-    .await
+
+    Ok(BatchDocsResponseBody {
+        results,
+        committed: !aborted,
+    })
 }
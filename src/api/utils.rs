@@ -10,3 +10,22 @@
 //         Response::from(e).into()
 //     }
 // }
+
+/// Turn a title into a slug: lowercase ascii alphanumerics, with every other run of
+/// characters replaced by a single dash.
+///
+/// This must match the main.slugify SQL function.
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::with_capacity(title.len());
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    if slug.ends_with('-') {
+        slug.pop();
+    }
+    slug
+}
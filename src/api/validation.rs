@@ -0,0 +1,393 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use url::Url;
+
+use crate::api::model::{DocPatch, DocSpec, NewDocSpec};
+use crate::api::utils::slugify;
+use crate::settings::Validation as Limits;
+
+/// A single violation of the validation rules, reported to the client.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Violation {
+    /// Path to the offending field, eg `docs[2].title`
+    pub field: String,
+    /// A stable, machine readable code, eg `TOO_LONG`
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+pub const REQUIRED: &str = "REQUIRED";
+pub const TOO_LONG: &str = "TOO_LONG";
+pub const TOO_MANY: &str = "TOO_MANY";
+pub const INVALID_URL: &str = "INVALID_URL";
+pub const DUPLICATE: &str = "DUPLICATE";
+pub const EMPTY_SLUG: &str = "EMPTY_SLUG";
+
+/// The fields shared by the different document inputs. Fields which are not given are
+/// not validated.
+struct Fields<'a> {
+    title: Option<&'a str>,
+    outline: Option<&'a str>,
+    author_fullname: Option<&'a str>,
+    author_resource: Option<&'a str>,
+    tags: Vec<(&'static str, &'a [String])>,
+    image_title: Option<&'a str>,
+    image_resource: Option<&'a str>,
+    image_author_fullname: Option<&'a str>,
+    image_author_resource: Option<&'a str>,
+    content: Option<&'a str>,
+}
+
+struct Validator<'a> {
+    limits: &'a Limits,
+    prefix: &'a str,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(limits: &'a Limits, prefix: &'a str) -> Self {
+        Validator {
+            limits,
+            prefix,
+            violations: Vec::new(),
+        }
+    }
+
+    fn violation(&mut self, field: &str, code: &'static str, message: String) {
+        let field = if self.prefix.is_empty() {
+            String::from(field)
+        } else {
+            format!("{}.{}", self.prefix, field)
+        };
+        self.violations.push(Violation {
+            field,
+            code,
+            message,
+        });
+    }
+
+    fn required(&mut self, field: &str, value: Option<&str>) {
+        if let Some(value) = value {
+            if value.trim().is_empty() {
+                self.violation(field, REQUIRED, String::from("must not be empty"));
+            }
+        }
+    }
+
+    /// A title must have a slug, which identifies the document as its title does.
+    fn slug(&mut self, field: &str, value: Option<&str>) {
+        if let Some(value) = value {
+            if !value.trim().is_empty() && slugify(value).is_empty() {
+                self.violation(
+                    field,
+                    EMPTY_SLUG,
+                    String::from("must contain at least one ascii letter or digit"),
+                );
+            }
+        }
+    }
+
+    fn max_length(&mut self, field: &str, value: Option<&str>, max: usize) {
+        if let Some(value) = value {
+            let length = value.chars().count();
+            if length > max {
+                self.violation(
+                    field,
+                    TOO_LONG,
+                    format!("must be at most {} characters long (got {})", max, length),
+                );
+            }
+        }
+    }
+
+    fn url(&mut self, field: &str, value: Option<&str>) {
+        if let Some(value) = value {
+            if let Err(err) = Url::parse(value) {
+                self.violation(
+                    field,
+                    INVALID_URL,
+                    format!("'{}' is not a valid URL ({})", value, err),
+                );
+            }
+        }
+    }
+
+    fn tags(&mut self, field: &str, tags: &[String]) {
+        let max_tags = self.limits.max_tags;
+        let max_tag_length = self.limits.max_tag_length;
+        if tags.len() > max_tags {
+            self.violation(
+                field,
+                TOO_MANY,
+                format!("must hold at most {} tags (got {})", max_tags, tags.len()),
+            );
+        }
+        let mut seen = HashSet::new();
+        for (i, tag) in tags.iter().enumerate() {
+            let field = format!("{}[{}]", field, i);
+            self.required(&field, Some(tag));
+            self.max_length(&field, Some(tag), max_tag_length);
+            if !seen.insert(tag.as_str()) {
+                self.violation(&field, DUPLICATE, format!("duplicate tag '{}'", tag));
+            }
+        }
+    }
+
+    fn fields(mut self, fields: Fields) -> Vec<Violation> {
+        let limits = self.limits;
+
+        self.required("title", fields.title);
+        self.slug("title", fields.title);
+        self.max_length("title", fields.title, limits.max_title_length);
+        self.max_length("outline", fields.outline, limits.max_outline_length);
+        self.max_length("content", fields.content, limits.max_content_length);
+
+        self.required("authorFullname", fields.author_fullname);
+        self.max_length(
+            "authorFullname",
+            fields.author_fullname,
+            limits.max_name_length,
+        );
+        self.url("authorResource", fields.author_resource);
+
+        self.max_length("imageTitle", fields.image_title, limits.max_title_length);
+        self.url("imageResource", fields.image_resource);
+        self.required("imageAuthorFullname", fields.image_author_fullname);
+        self.max_length(
+            "imageAuthorFullname",
+            fields.image_author_fullname,
+            limits.max_name_length,
+        );
+        self.url("imageAuthorResource", fields.image_author_resource);
+
+        for (field, tags) in fields.tags {
+            self.tags(field, tags);
+        }
+
+        self.violations
+    }
+}
+
+/// Validate a document specification. Violations are reported with the given prefix
+/// (eg `doc`).
+pub fn validate_doc_spec(doc: &DocSpec, limits: &Limits, prefix: &str) -> Vec<Violation> {
+    Validator::new(limits, prefix).fields(Fields {
+        title: Some(&doc.title),
+        outline: Some(&doc.outline),
+        author_fullname: Some(&doc.author_fullname),
+        author_resource: Some(&doc.author_resource),
+        tags: vec![("tags", doc.tags.as_slice())],
+        image_title: Some(&doc.image_title),
+        image_resource: Some(&doc.image_resource),
+        image_author_fullname: Some(&doc.image_author_fullname),
+        image_author_resource: Some(&doc.image_author_resource),
+        content: Some(&doc.content),
+    })
+}
+
+/// Validate the specification of a new document.
+pub fn validate_new_doc_spec(doc: &NewDocSpec, limits: &Limits, prefix: &str) -> Vec<Violation> {
+    Validator::new(limits, prefix).fields(Fields {
+        title: Some(&doc.title),
+        outline: Some(&doc.outline),
+        author_fullname: Some(&doc.author_fullname),
+        author_resource: Some(&doc.author_resource),
+        tags: vec![("tags", doc.tags.as_slice())],
+        image_title: Some(&doc.image_title),
+        image_resource: Some(&doc.image_resource),
+        image_author_fullname: Some(&doc.image_author_fullname),
+        image_author_resource: Some(&doc.image_author_resource),
+        content: Some(&doc.content),
+    })
+}
+
+/// Validate a partial update: only the fields it sets are validated.
+pub fn validate_doc_patch(patch: &DocPatch, limits: &Limits, prefix: &str) -> Vec<Violation> {
+    let mut tags = Vec::new();
+    if let Some(t) = &patch.tags {
+        tags.push(("tags", t.as_slice()));
+    }
+    if let Some(t) = &patch.add_tags {
+        tags.push(("addTags", t.as_slice()));
+    }
+    if let Some(t) = &patch.remove_tags {
+        tags.push(("removeTags", t.as_slice()));
+    }
+
+    Validator::new(limits, prefix).fields(Fields {
+        title: patch.title.as_deref(),
+        outline: patch.outline.as_deref(),
+        author_fullname: patch.author_fullname.as_deref(),
+        author_resource: patch.author_resource.as_deref(),
+        tags,
+        image_title: patch.image_title.as_deref(),
+        image_resource: patch.image_resource.as_deref(),
+        image_author_fullname: patch.image_author_fullname.as_deref(),
+        image_author_resource: patch.image_author_resource.as_deref(),
+        content: patch.content.as_deref(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::model::{DocGenre, DocKind};
+
+    const LIMITS: Limits = Limits {
+        max_title_length: 10,
+        max_outline_length: 20,
+        max_content_length: 30,
+        max_name_length: 8,
+        max_tags: 2,
+        max_tag_length: 5,
+    };
+
+    fn doc() -> NewDocSpec {
+        NewDocSpec {
+            title: String::from("Title"),
+            outline: String::from("Outline"),
+            author_fullname: String::from("Jane Doe"),
+            author_resource: String::from("https://example.com/jane-doe"),
+            tags: vec![String::from("rust")],
+            image_title: String::from("Cover"),
+            image_resource: String::from("https://example.com/cover.jpg"),
+            image_author_fullname: String::from("John Doe"),
+            image_author_resource: String::from("https://example.com/john-doe"),
+            kind: DocKind::Doc,
+            genre: DocGenre::Tutorial,
+            content: String::from("Content"),
+        }
+    }
+
+    fn codes(violations: Vec<Violation>) -> Vec<(String, &'static str)> {
+        violations
+            .into_iter()
+            .map(|violation| (violation.field, violation.code))
+            .collect()
+    }
+
+    fn chars(n: usize) -> String {
+        "a".repeat(n)
+    }
+
+    fn tags(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("t{}", i)).collect()
+    }
+
+    #[test]
+    fn new_documents_at_their_limits() {
+        type Edit = fn(&mut NewDocSpec);
+        let cases: &[(Edit, Option<(&str, &str)>)] = &[
+            (|_| {}, None),
+            (|d| d.title = chars(10), None),
+            (|d| d.title = chars(11), Some(("doc.title", TOO_LONG))),
+            (
+                |d| d.title = String::from(" "),
+                Some(("doc.title", REQUIRED)),
+            ),
+            (
+                |d| d.title = String::from("日本語"),
+                Some(("doc.title", EMPTY_SLUG)),
+            ),
+            (
+                |d| d.title = String::from("?!"),
+                Some(("doc.title", EMPTY_SLUG)),
+            ),
+            (|d| d.title = String::from("日本語 2"), None),
+            (|d| d.outline = chars(20), None),
+            (|d| d.outline = chars(21), Some(("doc.outline", TOO_LONG))),
+            (|d| d.content = chars(30), None),
+            (|d| d.content = chars(31), Some(("doc.content", TOO_LONG))),
+            (|d| d.author_fullname = chars(8), None),
+            (
+                |d| d.author_fullname = chars(9),
+                Some(("doc.authorFullname", TOO_LONG)),
+            ),
+            (
+                |d| d.author_fullname = String::new(),
+                Some(("doc.authorFullname", REQUIRED)),
+            ),
+            (
+                |d| d.author_resource = String::from("jane"),
+                Some(("doc.authorResource", INVALID_URL)),
+            ),
+            (|d| d.image_title = chars(10), None),
+            (
+                |d| d.image_title = chars(11),
+                Some(("doc.imageTitle", TOO_LONG)),
+            ),
+            (
+                |d| d.image_resource = String::new(),
+                Some(("doc.imageResource", INVALID_URL)),
+            ),
+            (|d| d.image_author_fullname = chars(8), None),
+            (
+                |d| d.image_author_fullname = chars(9),
+                Some(("doc.imageAuthorFullname", TOO_LONG)),
+            ),
+            (|d| d.tags = tags(2), None),
+            (|d| d.tags = tags(3), Some(("doc.tags", TOO_MANY))),
+            (|d| d.tags = vec![chars(5)], None),
+            (|d| d.tags = vec![chars(6)], Some(("doc.tags[0]", TOO_LONG))),
+            (
+                |d| d.tags = vec![String::new()],
+                Some(("doc.tags[0]", REQUIRED)),
+            ),
+            (
+                |d| d.tags = vec![chars(1), chars(1)],
+                Some(("doc.tags[1]", DUPLICATE)),
+            ),
+        ];
+
+        for (i, (edit, expected)) in cases.iter().enumerate() {
+            let mut doc = doc();
+            edit(&mut doc);
+            let expected = expected
+                .iter()
+                .map(|&(field, code)| (field.to_string(), code))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                codes(validate_new_doc_spec(&doc, &LIMITS, "doc")),
+                expected,
+                "case {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn patches_validate_the_fields_they_set() {
+        assert!(validate_doc_patch(&DocPatch::default(), &LIMITS, "patch").is_empty());
+
+        let patch = DocPatch {
+            title: Some(String::from("?")),
+            add_tags: Some(tags(3)),
+            remove_tags: Some(vec![chars(6), chars(5)]),
+            ..DocPatch::default()
+        };
+        assert_eq!(
+            codes(validate_doc_patch(&patch, &LIMITS, "patch")),
+            vec![
+                (String::from("patch.title"), EMPTY_SLUG),
+                (String::from("patch.addTags"), TOO_MANY),
+                (String::from("patch.removeTags[0]"), TOO_LONG),
+            ]
+        );
+
+        let patch = DocPatch {
+            remove_tags: Some(tags(3)),
+            ..DocPatch::default()
+        };
+        assert_eq!(
+            codes(validate_doc_patch(&patch, &LIMITS, "patch")),
+            vec![(String::from("patch.removeTags"), TOO_MANY)]
+        );
+    }
+}
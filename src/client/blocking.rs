@@ -0,0 +1,96 @@
+//! A blocking variant of the client, for programs without an async runtime. As with
+//! `reqwest::blocking`, it must not be used from within an async runtime.
+
+use serde::de::DeserializeOwned;
+use snafu::ResultExt;
+use uuid::Uuid;
+
+use super::{decode, retry_after, Error, Operation, Transport};
+use crate::api::model::{
+    BatchDocsResponseBody, DocPatch, DocSpec, DocumentRequestBody, MultiDocsResponseBody,
+    NewDocSpec, SingleDocResponseBody,
+};
+use crate::utils::construct_headers;
+
+/// A blocking client for the journal GraphQL API.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::blocking::Client,
+    url: String,
+}
+
+impl Client {
+    /// A client for the GraphQL endpoint at `url`, eg `http://journal:5000/graphql`.
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Client::with_http_client(reqwest::blocking::Client::new(), url)
+    }
+
+    /// A client using the given HTTP client, eg to set timeouts or proxies.
+    pub fn with_http_client<S: Into<String>>(http: reqwest::blocking::Client, url: S) -> Self {
+        Client {
+            http,
+            url: url.into(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn list_documents(&self) -> Result<MultiDocsResponseBody, Error> {
+        self.execute(Operation::list_documents())
+    }
+
+    pub fn find_document_by_id(&self, id: Uuid) -> Result<SingleDocResponseBody, Error> {
+        self.execute(Operation::find_document_by_id(id))
+    }
+
+    pub fn list_documents_by_query(&self, query: &str) -> Result<MultiDocsResponseBody, Error> {
+        self.execute(Operation::list_documents_by_query(query))
+    }
+
+    pub fn list_documents_by_tag(&self, tag: &str) -> Result<MultiDocsResponseBody, Error> {
+        self.execute(Operation::list_documents_by_tag(tag))
+    }
+
+    pub fn create_or_update_document(
+        &self,
+        doc: &DocumentRequestBody,
+    ) -> Result<SingleDocResponseBody, Error> {
+        self.execute(Operation::create_or_update_document(doc))
+    }
+
+    pub fn create_document(&self, doc: &NewDocSpec) -> Result<SingleDocResponseBody, Error> {
+        self.execute(Operation::create_document(doc))
+    }
+
+    pub fn update_document(
+        &self,
+        id: Uuid,
+        patch: &DocPatch,
+    ) -> Result<SingleDocResponseBody, Error> {
+        self.execute(Operation::update_document(id, patch))
+    }
+
+    pub fn create_or_update_documents(
+        &self,
+        docs: &[DocSpec],
+        atomic: bool,
+    ) -> Result<BatchDocsResponseBody, Error> {
+        self.execute(Operation::create_or_update_documents(docs, atomic))
+    }
+
+    fn execute<T: DeserializeOwned>(&self, operation: Operation) -> Result<T, Error> {
+        let response = self
+            .http
+            .post(&self.url)
+            .headers(construct_headers())
+            .body(operation.to_body()?)
+            .send()
+            .context(Transport { url: &self.url })?;
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = response.text().context(Transport { url: &self.url })?;
+        decode(&self.url, status, retry_after, &body)
+    }
+}
@@ -0,0 +1,531 @@
+//! A typed client for the journal GraphQL API, with one method per `Query` and `Mutation`
+//! field, returning the same types as the server.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), journal::client::Error> {
+//! let client = journal::client::Client::new("http://journal:5000/graphql");
+//! let docs = client.list_documents_by_tag("rust").await?;
+//! println!("{} documents", docs.docs_count);
+//! # Ok(())
+//! # }
+//! ```
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use snafu::{ResultExt, Snafu};
+use uuid::Uuid;
+
+use crate::api::analysis::{
+    INTROSPECTION_DISABLED, INVALID_QUERY, TOO_COSTLY, TOO_DEEP, TOO_MANY_ALIASES,
+};
+use crate::api::model::{
+    BatchDocsResponseBody, DocPatch, DocSpec, DocumentRequestBody, MultiDocsResponseBody,
+    NewDocSpec, SingleDocResponseBody,
+};
+use crate::error::{
+    BATCH_TOO_LARGE, CONFLICT, INTERNAL, NOT_FOUND, RATE_LIMITED, TIMEOUT, UNAUTHENTICATED,
+    VALIDATION,
+};
+use crate::utils::construct_headers;
+
+pub mod blocking;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum Error {
+    #[snafu(display("Could not reach {}: {}", url, source))]
+    Transport { url: String, source: reqwest::Error },
+
+    #[snafu(display("Could not serialize request: {}", source))]
+    InvalidRequest { source: serde_json::Error },
+
+    #[snafu(display("Invalid response from {}: {} [{}]", url, msg, source))]
+    InvalidResponse {
+        url: String,
+        msg: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Not found: {}", message))]
+    NotFound {
+        message: String,
+        request_id: Option<String>,
+    },
+
+    /// The document changed since the version given by the request.
+    #[snafu(display("Conflict: {}", message))]
+    Conflict {
+        message: String,
+        current_version: Option<i32>,
+        request_id: Option<String>,
+    },
+
+    #[snafu(display("Invalid request: {}", message))]
+    Validation {
+        message: String,
+        violations: Vec<Violation>,
+        request_id: Option<String>,
+    },
+
+    /// The request lacks valid credentials.
+    #[snafu(display("Unauthenticated: {}", message))]
+    Unauthenticated {
+        message: String,
+        request_id: Option<String>,
+    },
+
+    /// The request took too long, eg a slow query, and may be retried later.
+    #[snafu(display("Timeout: {}", message))]
+    Timeout {
+        message: String,
+        request_id: Option<String>,
+    },
+
+    #[snafu(display("Internal server error: {}", message))]
+    Internal {
+        message: String,
+        request_id: Option<String>,
+    },
+
+    /// The client exhausted its budget. Requests may be retried after the given number
+    /// of seconds, when the server tells.
+    #[snafu(display(
+        "Rate limited: {}{}",
+        message,
+        retry_after.map_or(String::new(), |seconds| format!(", retry in {}s", seconds))
+    ))]
+    RateLimited {
+        message: String,
+        retry_after: Option<u64>,
+    },
+
+    /// The query was rejected before execution, eg too deep or too costly. The code is
+    /// one of those of [`crate::api::analysis`], or `BATCH_TOO_LARGE`.
+    #[snafu(display("Query rejected ({}): {}", code, message))]
+    Rejected { message: String, code: String },
+
+    /// The server, or a proxy in front of it, answered an error status without a GraphQL
+    /// error.
+    #[snafu(display("Unexpected status from {}: {}", url, status))]
+    Status { url: String, status: StatusCode },
+
+    /// Any other error, eg a malformed query, which carries no code.
+    #[snafu(display("GraphQL error: {}", message))]
+    GraphQL {
+        message: String,
+        code: Option<String>,
+    },
+}
+
+impl Error {
+    /// The id of the request in the server logs, when the server reported one.
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            Error::NotFound { request_id, .. }
+            | Error::Conflict { request_id, .. }
+            | Error::Validation { request_id, .. }
+            | Error::Unauthenticated { request_id, .. }
+            | Error::Timeout { request_id, .. }
+            | Error::Internal { request_id, .. } => request_id.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// A validation failure, as reported in the `violations` extension.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Violation {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+/// Every field of a document front, aliased so that the response matches the serde
+/// names of the model.
+const FRONT_FIELDS: &str = r#"
+fragment AuthorFields on Author { fullname resource }
+fragment FrontFields on Front {
+  title
+  abstract: outline
+  author { ...AuthorFields }
+  tags
+  image { title resource author { ...AuthorFields } }
+  kind
+  genre
+  createdAt
+  updatedAt
+}"#;
+
+const DOC_FIELDS: &str =
+    "fragment DocFields on Doc { id front { ...FrontFields } content version }";
+
+const DOCS_FIELDS: &str = "fragment DocsFields on MultiDocsResponseBody { docs { id front { ...FrontFields } } docsCount }";
+
+/// A GraphQL query, with its variables. The queried field is aliased `result`.
+#[derive(Debug, Serialize)]
+struct Operation {
+    query: String,
+    variables: Value,
+}
+
+impl Operation {
+    /// GraphQL rejects unused fragments, so an operation includes only those it spreads.
+    fn new(query: &str, fragment: &str, variables: Value) -> Self {
+        Operation {
+            query: format!("{}\n{}\n{}", query, fragment, FRONT_FIELDS),
+            variables,
+        }
+    }
+
+    fn to_body(&self) -> Result<String, Error> {
+        serde_json::to_string(self).context(InvalidRequest)
+    }
+
+    fn list_documents() -> Self {
+        Operation::new(
+            "query { result: listDocuments { ...DocsFields } }",
+            DOCS_FIELDS,
+            json!({}),
+        )
+    }
+
+    fn find_document_by_id(id: Uuid) -> Self {
+        Operation::new(
+            "query($id: Uuid!) { result: findDocumentById(id: $id) { doc { ...DocFields } } }",
+            DOC_FIELDS,
+            json!({ "id": id }),
+        )
+    }
+
+    fn list_documents_by_query(query: &str) -> Self {
+        Operation::new(
+            "query($query: String!) { result: listDocumentsByQuery(query: $query) { ...DocsFields } }",
+            DOCS_FIELDS,
+            json!({ "query": query }),
+        )
+    }
+
+    fn list_documents_by_tag(tag: &str) -> Self {
+        Operation::new(
+            "query($tag: String!) { result: listDocumentsByTag(tag: $tag) { ...DocsFields } }",
+            DOCS_FIELDS,
+            json!({ "tag": tag }),
+        )
+    }
+
+    fn create_or_update_document(doc: &DocumentRequestBody) -> Self {
+        Operation::new(
+            "mutation($doc: DocumentRequestBody!) { result: createOrUpdateDocument(doc: $doc) { doc { ...DocFields } } }",
+            DOC_FIELDS,
+            json!({ "doc": doc }),
+        )
+    }
+
+    fn create_document(doc: &NewDocSpec) -> Self {
+        Operation::new(
+            "mutation($doc: NewDocSpec!) { result: createDocument(doc: $doc) { doc { ...DocFields } } }",
+            DOC_FIELDS,
+            json!({ "doc": doc }),
+        )
+    }
+
+    fn update_document(id: Uuid, patch: &DocPatch) -> Self {
+        Operation::new(
+            "mutation($id: Uuid!, $patch: DocPatch!) { result: updateDocument(id: $id, patch: $patch) { doc { ...DocFields } } }",
+            DOC_FIELDS,
+            json!({ "id": id, "patch": patch }),
+        )
+    }
+
+    fn create_or_update_documents(docs: &[DocSpec], atomic: bool) -> Self {
+        Operation::new(
+            r#"mutation($docs: [DocSpec!]!, $atomic: Boolean) {
+                 result: createOrUpdateDocuments(docs: $docs, atomic: $atomic) {
+                   results { id doc { ...DocFields } error { code message } }
+                   committed
+                 }
+               }"#,
+            DOC_FIELDS,
+            json!({ "docs": docs, "atomic": atomic }),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Response<T> {
+    data: Option<Data<T>>,
+    #[serde(default)]
+    errors: Vec<ResponseError>,
+}
+
+/// The errors of a response, whatever its data.
+#[derive(Debug, Deserialize)]
+struct Errors {
+    #[serde(default)]
+    errors: Vec<ResponseError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Data<T> {
+    result: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseError {
+    message: String,
+    #[serde(default)]
+    extensions: Extensions,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Extensions {
+    code: Option<String>,
+    request_id: Option<String>,
+    current_version: Option<i32>,
+    #[serde(default)]
+    violations: Vec<Violation>,
+}
+
+impl From<ResponseError> for Error {
+    fn from(err: ResponseError) -> Self {
+        let ResponseError {
+            message,
+            extensions:
+                Extensions {
+                    code,
+                    request_id,
+                    current_version,
+                    violations,
+                },
+        } = err;
+        match code.as_deref() {
+            Some(NOT_FOUND) => Error::NotFound {
+                message,
+                request_id,
+            },
+            Some(CONFLICT) => Error::Conflict {
+                message,
+                current_version,
+                request_id,
+            },
+            Some(VALIDATION) => Error::Validation {
+                message,
+                violations,
+                request_id,
+            },
+            Some(UNAUTHENTICATED) => Error::Unauthenticated {
+                message,
+                request_id,
+            },
+            Some(TIMEOUT) => Error::Timeout {
+                message,
+                request_id,
+            },
+            Some(INTERNAL) => Error::Internal {
+                message,
+                request_id,
+            },
+            Some(RATE_LIMITED) => Error::RateLimited {
+                message,
+                retry_after: None,
+            },
+            Some(INVALID_QUERY)
+            | Some(TOO_DEEP)
+            | Some(TOO_MANY_ALIASES)
+            | Some(TOO_COSTLY)
+            | Some(INTROSPECTION_DISABLED)
+            | Some(BATCH_TOO_LARGE) => Error::Rejected {
+                message,
+                code: code.unwrap_or_default(),
+            },
+            _ => Error::GraphQL { message, code },
+        }
+    }
+}
+
+/// The delay, in seconds, given by the Retry-After header of a response.
+fn retry_after(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Extract the result of an operation from the response. Only the first error is
+/// reported, as our operations query a single field.
+///
+/// The status is checked first: requests which are rate limited, or rejected by the
+/// query analysis, are answered 429 or 400 with no data. Failed executions are answered
+/// 400 too, and their errors are reported as for a 200.
+fn decode<T: DeserializeOwned>(
+    url: &str,
+    status: StatusCode,
+    retry_after: Option<u64>,
+    body: &str,
+) -> Result<T, Error> {
+    if !status.is_success() {
+        let err = serde_json::from_str::<Errors>(body)
+            .ok()
+            .and_then(|errors| errors.errors.into_iter().next());
+        return Err(match err {
+            _ if status == StatusCode::TOO_MANY_REQUESTS => Error::RateLimited {
+                message: err.map_or_else(|| status.to_string(), |err| err.message),
+                retry_after,
+            },
+            Some(err) => Error::from(err),
+            None => Error::Status {
+                url: url.to_string(),
+                status,
+            },
+        });
+    }
+    let response: Response<T> = serde_json::from_str(body).context(InvalidResponse {
+        url,
+        msg: "could not parse response",
+    })?;
+    if let Some(err) = response.errors.into_iter().next() {
+        return Err(Error::from(err));
+    }
+    match response.data {
+        Some(data) => Ok(data.result),
+        None => Err(Error::GraphQL {
+            message: String::from("response has neither data nor errors"),
+            code: None,
+        }),
+    }
+}
+
+/// An asynchronous client for the journal GraphQL API.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl Client {
+    /// A client for the GraphQL endpoint at `url`, eg `http://journal:5000/graphql`.
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Client::with_http_client(reqwest::Client::new(), url)
+    }
+
+    /// A client using the given HTTP client, eg to set timeouts or proxies.
+    pub fn with_http_client<S: Into<String>>(http: reqwest::Client, url: S) -> Self {
+        Client {
+            http,
+            url: url.into(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn list_documents(&self) -> Result<MultiDocsResponseBody, Error> {
+        self.execute(Operation::list_documents()).await
+    }
+
+    pub async fn find_document_by_id(&self, id: Uuid) -> Result<SingleDocResponseBody, Error> {
+        self.execute(Operation::find_document_by_id(id)).await
+    }
+
+    pub async fn list_documents_by_query(
+        &self,
+        query: &str,
+    ) -> Result<MultiDocsResponseBody, Error> {
+        self.execute(Operation::list_documents_by_query(query))
+            .await
+    }
+
+    pub async fn list_documents_by_tag(&self, tag: &str) -> Result<MultiDocsResponseBody, Error> {
+        self.execute(Operation::list_documents_by_tag(tag)).await
+    }
+
+    pub async fn create_or_update_document(
+        &self,
+        doc: &DocumentRequestBody,
+    ) -> Result<SingleDocResponseBody, Error> {
+        self.execute(Operation::create_or_update_document(doc))
+            .await
+    }
+
+    pub async fn create_document(&self, doc: &NewDocSpec) -> Result<SingleDocResponseBody, Error> {
+        self.execute(Operation::create_document(doc)).await
+    }
+
+    pub async fn update_document(
+        &self,
+        id: Uuid,
+        patch: &DocPatch,
+    ) -> Result<SingleDocResponseBody, Error> {
+        self.execute(Operation::update_document(id, patch)).await
+    }
+
+    pub async fn create_or_update_documents(
+        &self,
+        docs: &[DocSpec],
+        atomic: bool,
+    ) -> Result<BatchDocsResponseBody, Error> {
+        self.execute(Operation::create_or_update_documents(docs, atomic))
+            .await
+    }
+
+    async fn execute<T: DeserializeOwned>(&self, operation: Operation) -> Result<T, Error> {
+        let response = self
+            .http
+            .post(&self.url)
+            .headers(construct_headers())
+            .body(operation.to_body()?)
+            .send()
+            .await
+            .context(Transport { url: &self.url })?;
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = response
+            .text()
+            .await
+            .context(Transport { url: &self.url })?;
+        decode(&self.url, status, retry_after, &body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "http://journal:5000/graphql";
+
+    fn error(status: StatusCode, retry_after: Option<u64>, body: &str) -> Error {
+        decode::<MultiDocsResponseBody>(URL, status, retry_after, body).unwrap_err()
+    }
+
+    #[test]
+    fn decode_error_statuses() {
+        let body = r#"{"errors":[{"message":"Too many requests, please retry later","extensions":{"code":"RATE_LIMITED"}}]}"#;
+        match error(StatusCode::TOO_MANY_REQUESTS, Some(3), body) {
+            Error::RateLimited { retry_after, .. } => assert_eq!(retry_after, Some(3)),
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        let body =
+            r#"{"errors":[{"message":"Query is too deep","extensions":{"code":"TOO_DEEP"}}]}"#;
+        match error(StatusCode::BAD_REQUEST, None, body) {
+            Error::Rejected { code, .. } => assert_eq!(code, TOO_DEEP),
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        let body = r#"{"data":null,"errors":[{"message":"Invalid document","extensions":{"code":"VALIDATION","violations":[{"field":"doc.title","code":"REQUIRED","message":"must not be empty"}]}}]}"#;
+        match error(StatusCode::BAD_REQUEST, None, body) {
+            Error::Validation { violations, .. } => assert_eq!(violations[0].field, "doc.title"),
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        match error(StatusCode::BAD_GATEWAY, None, "<html>Bad Gateway</html>") {
+            Error::Status { status, .. } => assert_eq!(status, StatusCode::BAD_GATEWAY),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use slog::{info, Logger};
+use std::sync::Arc;
+use warp::http::header::{self, HeaderName, HeaderValue};
+use warp::http::{Method, StatusCode};
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use journal::error;
+use journal::settings::Cors;
+
+/// The CORS policy of the server, validated from the settings.
+#[derive(Debug)]
+pub struct Policy {
+    /// None for any origin.
+    origins: Option<Vec<OriginPattern>>,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<u64>,
+    /// The scheme the server is reached with, to recognize same-origin requests.
+    scheme: &'static str,
+}
+
+#[derive(Debug)]
+enum Host {
+    Exact(String),
+    /// Any subdomain of the domain, but not the domain itself.
+    Subdomains(String),
+}
+
+#[derive(Debug)]
+struct OriginPattern {
+    scheme: String,
+    host: Host,
+    port: Option<u16>,
+}
+
+impl OriginPattern {
+    fn matches(&self, origin: &Origin) -> bool {
+        self.scheme == origin.scheme
+            && self.port == origin.port
+            && match &self.host {
+                Host::Exact(host) => *host == origin.host,
+                Host::Subdomains(domain) => origin
+                    .host
+                    .strip_suffix(domain.as_str())
+                    .map_or(false, |prefix| prefix.len() > 1 && prefix.ends_with('.')),
+            }
+    }
+}
+
+/// The `Origin` header of a request, or an allowed origin: `scheme://host[:port]`.
+#[derive(Debug, PartialEq)]
+struct Origin {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+}
+
+impl Origin {
+    fn parse(origin: &str) -> Option<Origin> {
+        let origin = origin.to_lowercase();
+        let mut parts = origin.splitn(2, "://");
+        let scheme = parts.next()?;
+        let authority = parts.next()?;
+        if scheme != "http" && scheme != "https" {
+            return None;
+        }
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (&authority[..i], Some(authority[i + 1..].parse().ok()?)),
+            None => (authority, None),
+        };
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '*';
+        if host.is_empty() || !host.chars().all(valid) {
+            return None;
+        }
+        // The default ports are implied, as browsers omit them.
+        let port = match (scheme, port) {
+            ("http", Some(80)) | ("https", Some(443)) => None,
+            (_, port) => port,
+        };
+        Some(Origin {
+            scheme: scheme.to_string(),
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl Policy {
+    /// A policy for a server reached over HTTPS when `tls` is set, or plain HTTP.
+    pub fn new(settings: &Cors, tls: bool) -> Result<Self, error::Error> {
+        let any_origin = settings.allowed_origins.iter().any(|origin| origin == "*");
+        let origins = if any_origin {
+            if settings.allowed_origins.len() > 1 {
+                return Err(invalid(
+                    "'*' allows any origin, and cannot be listed with other origins",
+                ));
+            }
+            if settings.allow_credentials {
+                return Err(invalid(
+                    "credentials cannot be allowed for any origin, list the origins instead",
+                ));
+            }
+            None
+        } else {
+            let origins = settings
+                .allowed_origins
+                .iter()
+                .map(String::as_str)
+                .map(origin_pattern)
+                .collect::<Result<Vec<_>, _>>()?;
+            Some(origins)
+        };
+
+        let methods = settings
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|_| invalid(&format!("invalid method '{}'", method)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = settings
+            .allowed_headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| invalid(&format!("invalid header '{}'", name)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Policy {
+            origins,
+            methods,
+            headers,
+            credentials: settings.allow_credentials,
+            max_age: settings.max_age,
+            scheme: if tls { "https" } else { "http" },
+        })
+    }
+
+    pub fn log(&self, settings: &Cors, logger: &Logger) {
+        let origins = if settings.allowed_origins.is_empty() {
+            String::from("none")
+        } else {
+            settings.allowed_origins.join(", ")
+        };
+        info!(
+            logger, "CORS policy";
+            "origins" => origins,
+            "methods" => join(&self.methods),
+            "headers" => join(&self.headers),
+            "credentials" => self.credentials,
+            "max_age" => self.max_age.map(|age| age.to_string()).unwrap_or_default()
+        );
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        match &self.origins {
+            None => true,
+            Some(patterns) => match Origin::parse(origin) {
+                Some(origin) => patterns.iter().any(|pattern| pattern.matches(&origin)),
+                None => false,
+            },
+        }
+    }
+
+    /// Whether the origin is the server itself, given the `Host` header of the request.
+    /// Browsers send the origin on same-origin POST requests, eg from the playground,
+    /// which the policy does not restrict.
+    fn same_origin(&self, origin: &str, host: Option<&str>) -> bool {
+        let host = host.and_then(|host| Origin::parse(&format!("{}://{}", self.scheme, host)));
+        match (Origin::parse(origin), host) {
+            (Some(origin), Some(host)) => origin == host,
+            _ => false,
+        }
+    }
+
+    /// Answer a preflight request, or forbid it when the origin, the method or one of
+    /// the headers is not allowed.
+    fn preflight(&self, origin: &str, method: &str, headers: Option<&str>) -> Response {
+        let method_allowed = self
+            .methods
+            .iter()
+            .any(|allowed| allowed.as_str().eq_ignore_ascii_case(method));
+        let headers_allowed = headers
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                self.headers
+                    .iter()
+                    .any(|allowed| allowed.as_str().eq_ignore_ascii_case(name))
+            });
+        if !self.allows_origin(origin) || !method_allowed || !headers_allowed {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+
+        let mut response = self.decorate(origin, StatusCode::OK.into_response());
+        let response_headers = response.headers_mut();
+        insert(
+            response_headers,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            &join(&self.methods),
+        );
+        insert(
+            response_headers,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            &join(&self.headers),
+        );
+        if let Some(max_age) = self.max_age {
+            insert(
+                response_headers,
+                header::ACCESS_CONTROL_MAX_AGE,
+                &max_age.to_string(),
+            );
+        }
+        response
+    }
+
+    /// Add the headers allowing the origin to read the response.
+    fn decorate(&self, origin: &str, mut response: Response) -> Response {
+        let headers = response.headers_mut();
+        match self.origins {
+            None => insert(headers, header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+            Some(_) => {
+                insert(headers, header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                headers.append(header::VARY, HeaderValue::from_static("origin"));
+            }
+        }
+        if self.credentials {
+            insert(headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        response
+    }
+}
+
+/// Apply the policy to the routes: preflight requests are answered, and requests from
+/// foreign origins which are not allowed are forbidden before they reach the routes.
+pub fn wrap<F, R>(
+    routes: F,
+    policy: Policy,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let policy = Arc::new(policy);
+
+    // Requests this filter does not answer are rejected as not found, which has the
+    // lowest priority, so that the rejections of the routes prevail.
+    let policy1 = policy.clone();
+    let intercept = warp::method()
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>(
+            "access-control-request-method",
+        ))
+        .and(warp::header::optional::<String>(
+            "access-control-request-headers",
+        ))
+        .and_then(
+            move |method: Method,
+                  origin: Option<String>,
+                  host: Option<String>,
+                  requested_method: Option<String>,
+                  requested_headers: Option<String>| {
+                let response = match (origin, requested_method) {
+                    (Some(origin), Some(requested_method)) if method == Method::OPTIONS => Some(
+                        policy1.preflight(&origin, &requested_method, requested_headers.as_deref()),
+                    ),
+                    (Some(origin), _)
+                        if !policy1.allows_origin(&origin)
+                            && !policy1.same_origin(&origin, host.as_deref()) =>
+                    {
+                        Some(StatusCode::FORBIDDEN.into_response())
+                    }
+                    _ => None,
+                };
+                async move { response.ok_or_else(warp::reject::not_found) }
+            },
+        );
+
+    let allowed = warp::header::optional::<String>("origin").and(routes).map(
+        move |origin: Option<String>, reply: R| {
+            let response = reply.into_response();
+            match origin {
+                Some(origin) if policy.allows_origin(&origin) => policy.decorate(&origin, response),
+                _ => response,
+            }
+        },
+    );
+
+    intercept.or(allowed).unify()
+}
+
+fn origin_pattern(origin: &str) -> Result<OriginPattern, error::Error> {
+    let parsed =
+        Origin::parse(origin).ok_or_else(|| invalid(&format!("invalid origin '{}'", origin)))?;
+    let host = match parsed.host.strip_prefix("*.") {
+        Some(domain) => Host::Subdomains(domain.to_string()),
+        None => Host::Exact(parsed.host),
+    };
+    let wildcard = match &host {
+        Host::Exact(host) | Host::Subdomains(host) => host.contains('*'),
+    };
+    if wildcard {
+        return Err(invalid(&format!(
+            "invalid origin '{}', only a leading '*.' is allowed",
+            origin
+        )));
+    }
+    Ok(OriginPattern {
+        scheme: parsed.scheme,
+        host,
+        port: parsed.port,
+    })
+}
+
+fn insert(headers: &mut header::HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+fn join<T: AsRef<str>>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|item| item.as_ref())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn invalid(msg: &str) -> error::Error {
+    error::Error::MiscError {
+        msg: format!("Invalid CORS settings: {}", msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str], credentials: bool) -> Cors {
+        Cors {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allow_credentials: credentials,
+            ..Cors::default()
+        }
+    }
+
+    fn matches(pattern: &str, origin: &str) -> bool {
+        let pattern = origin_pattern(pattern).unwrap();
+        Origin::parse(origin).map_or(false, |origin| pattern.matches(&origin))
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_only() {
+        assert!(matches("https://*.example.com", "https://api.example.com"));
+        assert!(matches("https://*.example.com", "https://a.b.example.com"));
+        assert!(!matches("https://*.example.com", "https://example.com"));
+        assert!(!matches("https://*.example.com", "https://badexample.com"));
+        assert!(!matches("https://*.example.com", "http://api.example.com"));
+        assert!(origin_pattern("https://api.*.example.com").is_err());
+        assert!(origin_pattern("https://*").is_err());
+    }
+
+    #[test]
+    fn default_ports_are_implied() {
+        assert!(matches("https://example.com:443", "https://example.com"));
+        assert!(matches("https://example.com", "https://example.com:443"));
+        assert!(matches("http://example.com:80", "http://example.com"));
+        assert!(!matches("http://example.com:8080", "http://example.com"));
+        assert!(!matches("https://example.com:80", "https://example.com"));
+    }
+
+    #[test]
+    fn origins_are_case_insensitive() {
+        assert!(matches("HTTPS://Example.COM", "https://example.com"));
+        assert!(matches("https://example.com", "https://EXAMPLE.com"));
+        assert_eq!(Origin::parse("ftp://example.com"), None);
+        assert_eq!(Origin::parse("example.com"), None);
+    }
+
+    #[test]
+    fn any_origin_stands_alone() {
+        assert!(Policy::new(&cors(&["*"], false), false).is_ok());
+        assert!(Policy::new(&cors(&["*", "https://example.com"], false), false).is_err());
+        assert!(Policy::new(&cors(&["*"], true), false).is_err());
+        assert!(Policy::new(&cors(&["https://example.com"], true), false).is_ok());
+    }
+
+    #[test]
+    fn same_origin_is_not_restricted() {
+        let policy = Policy::new(&cors(&[], false), true).unwrap();
+        assert!(!policy.allows_origin("https://journal.example.com"));
+        assert!(policy.same_origin("https://journal.example.com", Some("journal.example.com")));
+        assert!(policy.same_origin(
+            "https://journal.example.com",
+            Some("journal.example.com:443")
+        ));
+        assert!(!policy.same_origin("http://journal.example.com", Some("journal.example.com")));
+        assert!(!policy.same_origin("https://evil.example.com", Some("journal.example.com")));
+        assert!(!policy.same_origin("https://journal.example.com", None));
+
+        let policy = Policy::new(&cors(&[], false), false).unwrap();
+        assert!(policy.same_origin("http://localhost:6080", Some("localhost:6080")));
+        assert!(!policy.same_origin("http://localhost:3000", Some("localhost:6080")));
+    }
+}
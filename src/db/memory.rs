@@ -0,0 +1,524 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+use super::model::{
+    self, AuthorEntity, DocCount, DocEntity, DocGenre, DocKind, DocPatchEntity, EntityId,
+    ImageEntity, ProvideError, ProvideJournal, ProvideResult, ShortDocEntity,
+};
+use super::tx::TxOptions;
+use super::{Check, JournalTransaction, Store};
+use crate::api::utils::slugify;
+
+#[derive(Debug, Clone)]
+struct AuthorRow {
+    id: EntityId,
+    fullname: String,
+    resource: String,
+}
+
+#[derive(Debug, Clone)]
+struct ImageRow {
+    id: EntityId,
+    title: String,
+    author_id: EntityId,
+    resource: String,
+}
+
+#[derive(Debug, Clone)]
+struct DocumentRow {
+    id: EntityId,
+    title: String,
+    outline: String,
+    author_id: EntityId,
+    content: String,
+    tags: Vec<String>,
+    image_id: EntityId,
+    kind: DocKind,
+    genre: DocGenre,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    version: i32,
+}
+
+/// The journal, with the same structure, and the same constraints, as the Postgres
+/// schema: authors are unique by fullname, images by resource, and documents by title and
+/// by slug.
+#[derive(Debug, Clone, Default)]
+struct Journal {
+    authors: Vec<AuthorRow>,
+    images: Vec<ImageRow>,
+    documents: Vec<DocumentRow>,
+}
+
+impl Journal {
+    fn upsert_author(&mut self, fullname: &str, resource: &str) -> EntityId {
+        match self.authors.iter_mut().find(|a| a.fullname == fullname) {
+            Some(author) => {
+                author.resource = String::from(resource);
+                author.id
+            }
+            None => {
+                let id = Uuid::new_v4();
+                self.authors.push(AuthorRow {
+                    id,
+                    fullname: String::from(fullname),
+                    resource: String::from(resource),
+                });
+                id
+            }
+        }
+    }
+
+    fn upsert_image(
+        &mut self,
+        title: &str,
+        resource: &str,
+        author_fullname: &str,
+        author_resource: &str,
+    ) -> EntityId {
+        let author_id = self.upsert_author(author_fullname, author_resource);
+        match self.images.iter_mut().find(|i| i.resource == resource) {
+            Some(image) => {
+                image.title = String::from(title);
+                image.author_id = author_id;
+                image.id
+            }
+            None => {
+                let id = Uuid::new_v4();
+                self.images.push(ImageRow {
+                    id,
+                    title: String::from(title),
+                    author_id,
+                    resource: String::from(resource),
+                });
+                id
+            }
+        }
+    }
+
+    fn author(&self, id: EntityId) -> ProvideResult<&AuthorRow> {
+        self.authors
+            .iter()
+            .find(|a| a.id == id)
+            .ok_or_else(|| dangling("author", id))
+    }
+
+    fn image(&self, id: EntityId) -> ProvideResult<&ImageRow> {
+        self.images
+            .iter()
+            .find(|i| i.id == id)
+            .ok_or_else(|| dangling("image", id))
+    }
+
+    fn document(&self, id: EntityId) -> Option<&DocumentRow> {
+        self.documents.iter().find(|d| d.id == id)
+    }
+
+    fn author_entity(&self, id: EntityId) -> ProvideResult<AuthorEntity> {
+        let author = self.author(id)?;
+        Ok(AuthorEntity {
+            id: Some(author.id),
+            fullname: author.fullname.clone(),
+            resource: author.resource.clone(),
+        })
+    }
+
+    fn image_entity(&self, id: EntityId) -> ProvideResult<ImageEntity> {
+        let image = self.image(id)?;
+        Ok(ImageEntity {
+            id: Some(image.id),
+            title: image.title.clone(),
+            author: self.author_entity(image.author_id)?,
+            resource: image.resource.clone(),
+        })
+    }
+
+    fn short_entity(&self, doc: &DocumentRow) -> ProvideResult<ShortDocEntity> {
+        Ok(ShortDocEntity {
+            id: doc.id,
+            title: doc.title.clone(),
+            outline: doc.outline.clone(),
+            author: self.author_entity(doc.author_id)?,
+            tags: doc.tags.clone(),
+            image: self.image_entity(doc.image_id)?,
+            kind: doc.kind,
+            genre: doc.genre,
+            created_at: doc.created_at,
+            updated_at: doc.updated_at,
+        })
+    }
+
+    fn entity(&self, doc: &DocumentRow) -> ProvideResult<DocEntity> {
+        Ok(DocEntity {
+            id: doc.id,
+            title: doc.title.clone(),
+            outline: doc.outline.clone(),
+            author: self.author_entity(doc.author_id)?,
+            tags: doc.tags.clone(),
+            image: self.image_entity(doc.image_id)?,
+            kind: doc.kind,
+            genre: doc.genre,
+            content: doc.content.clone(),
+            created_at: doc.created_at,
+            updated_at: doc.updated_at,
+            version: doc.version,
+        })
+    }
+
+    /// The documents matching the predicate, most recently updated first.
+    fn short_entities<P>(&self, predicate: P) -> ProvideResult<Vec<ShortDocEntity>>
+    where
+        P: Fn(&DocumentRow) -> bool,
+    {
+        let mut docs = self
+            .documents
+            .iter()
+            .filter(|doc| predicate(doc))
+            .collect::<Vec<_>>();
+        docs.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+        docs.into_iter().map(|doc| self.short_entity(doc)).collect()
+    }
+
+    fn check_unique_title(&self, id: EntityId, title: &str) -> ProvideResult<()> {
+        if self
+            .documents
+            .iter()
+            .any(|d| d.id != id && d.title == title)
+        {
+            return Err(ProvideError::UniqueViolation {
+                details: format!("Key (title)=({}) already exists.", title),
+            });
+        }
+        // Like the partial unique index of the other stores, empty slugs are not unique.
+        let slug = slugify(title);
+        if !slug.is_empty()
+            && self
+                .documents
+                .iter()
+                .any(|d| d.id != id && slugify(&d.title) == slug)
+        {
+            return Err(ProvideError::UniqueViolation {
+                details: format!("Key (slug)=({}) already exists.", slug),
+            });
+        }
+        Ok(())
+    }
+
+    /// Insert a document, or update it, the way the Postgres schema does: the version
+    /// is only taken for a new document, an existing one gets the next version.
+    fn write_document(
+        &mut self,
+        doc: &DocEntity,
+        created_at: DateTime<Utc>,
+        updated_at: DateTime<Utc>,
+        version: i32,
+    ) -> ProvideResult<DocEntity> {
+        let author_id = self.upsert_author(&doc.author.fullname, &doc.author.resource);
+        let image_id = self.upsert_image(
+            &doc.image.title,
+            &doc.image.resource,
+            &doc.image.author.fullname,
+            &doc.image.author.resource,
+        );
+        self.check_unique_title(doc.id, &doc.title)?;
+
+        let index = self.documents.iter().position(|d| d.id == doc.id);
+        let version = match index {
+            Some(index) => self.documents[index].version + 1,
+            None => version,
+        };
+        let row = DocumentRow {
+            id: doc.id,
+            title: doc.title.clone(),
+            outline: doc.outline.clone(),
+            author_id,
+            content: doc.content.clone(),
+            tags: doc.tags.clone(),
+            image_id,
+            kind: doc.kind,
+            genre: doc.genre,
+            created_at,
+            updated_at,
+            version,
+        };
+        match index {
+            Some(index) => self.documents[index] = row,
+            None => self.documents.push(row),
+        }
+
+        self.get_entity(doc.id)?.ok_or(ProvideError::NotFound)
+    }
+
+    fn get_entity(&self, id: EntityId) -> ProvideResult<Option<DocEntity>> {
+        self.document(id).map(|doc| self.entity(doc)).transpose()
+    }
+}
+
+fn dangling(table: &str, id: EntityId) -> ProvideError {
+    ProvideError::ModelViolation {
+        details: format!("dangling reference to {} {}", table, id),
+    }
+}
+
+/// Naive full text search: every word of the query must appear in the document.
+fn matches_query(doc: &DocumentRow, words: &[String]) -> bool {
+    let text = format!(
+        "{} {} {} {}",
+        doc.title,
+        doc.tags.join(" "),
+        doc.outline,
+        doc.content
+    )
+    .to_lowercase();
+    !words.is_empty() && words.iter().all(|word| text.contains(word.as_str()))
+}
+
+#[async_trait]
+impl ProvideJournal for Journal {
+    async fn get_all_documents(&mut self) -> ProvideResult<Vec<ShortDocEntity>> {
+        self.short_entities(|doc| doc.kind == DocKind::Doc)
+    }
+
+    async fn get_document_by_id(&mut self, id: EntityId) -> ProvideResult<Option<DocEntity>> {
+        self.get_entity(id)
+    }
+
+    async fn get_document_version(&mut self, id: EntityId) -> ProvideResult<Option<i32>> {
+        Ok(self.document(id).map(|doc| doc.version))
+    }
+
+    fn export_documents(&mut self) -> BoxStream<'_, ProvideResult<DocEntity>> {
+        // The stream borrows the journal, which is not modified meanwhile.
+        let journal: &Journal = self;
+        let mut docs = journal.documents.iter().collect::<Vec<_>>();
+        docs.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        let docs = docs.into_iter().map(move |doc| journal.entity(doc));
+        stream::iter(docs).boxed()
+    }
+
+    fn export_authors(&mut self) -> BoxStream<'_, ProvideResult<AuthorEntity>> {
+        let journal: &Journal = self;
+        let mut authors = journal.authors.iter().collect::<Vec<_>>();
+        authors.sort_by(|a, b| a.fullname.cmp(&b.fullname));
+        let authors = authors
+            .into_iter()
+            .map(move |author| journal.author_entity(author.id));
+        stream::iter(authors).boxed()
+    }
+
+    fn export_images(&mut self) -> BoxStream<'_, ProvideResult<ImageEntity>> {
+        let journal: &Journal = self;
+        let mut images = journal.images.iter().collect::<Vec<_>>();
+        images.sort_by(|a, b| a.resource.cmp(&b.resource));
+        let images = images
+            .into_iter()
+            .map(move |image| journal.image_entity(image.id));
+        stream::iter(images).boxed()
+    }
+
+    fn export_tags(&mut self) -> BoxStream<'_, ProvideResult<String>> {
+        let mut tags = self
+            .documents
+            .iter()
+            .flat_map(|doc| doc.tags.iter().cloned())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        stream::iter(tags.into_iter().map(Ok)).boxed()
+    }
+
+    async fn restore_author(&mut self, author: &AuthorEntity) -> ProvideResult<()> {
+        self.upsert_author(&author.fullname, &author.resource);
+        Ok(())
+    }
+
+    async fn restore_image(&mut self, image: &ImageEntity) -> ProvideResult<()> {
+        self.upsert_image(
+            &image.title,
+            &image.resource,
+            &image.author.fullname,
+            &image.author.resource,
+        );
+        Ok(())
+    }
+
+    async fn create_or_update_document(&mut self, doc: &DocEntity) -> ProvideResult<DocEntity> {
+        let now = Utc::now();
+        let created_at = self
+            .document(doc.id)
+            .map_or(now, |existing| existing.created_at);
+        self.write_document(doc, created_at, now, 1)
+    }
+
+    async fn create_document(&mut self, doc: &DocEntity) -> ProvideResult<Option<DocEntity>> {
+        if self.document(doc.id).is_some() {
+            return Ok(None);
+        }
+        let now = Utc::now();
+        self.write_document(doc, now, now, 1).map(Some)
+    }
+
+    async fn restore_document(&mut self, doc: &DocEntity) -> ProvideResult<DocEntity> {
+        self.write_document(doc, doc.created_at, doc.updated_at, doc.version)
+    }
+
+    async fn delete_all_documents(&mut self) -> ProvideResult<u64> {
+        let deleted = self.documents.len() as u64;
+        self.documents.clear();
+        Ok(deleted)
+    }
+
+    async fn find_document_by_title_or_slug(
+        &mut self,
+        title: &str,
+        slug: &str,
+    ) -> ProvideResult<Option<EntityId>> {
+        Ok(self
+            .documents
+            .iter()
+            .find(|d| d.title == title || (!slug.is_empty() && slugify(&d.title) == slug))
+            .map(|d| d.id))
+    }
+
+    async fn update_document(
+        &mut self,
+        id: EntityId,
+        patch: &DocPatchEntity,
+    ) -> ProvideResult<Option<DocEntity>> {
+        match self.get_entity(id)? {
+            None => Ok(None),
+            Some(doc) => self
+                .create_or_update_document(&patch.apply(doc))
+                .await
+                .map(Some),
+        }
+    }
+
+    async fn get_all_documents_by_query(
+        &mut self,
+        query: &str,
+    ) -> ProvideResult<Vec<ShortDocEntity>> {
+        let words = query
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>();
+        self.short_entities(|doc| matches_query(doc, &words))
+    }
+
+    async fn get_all_documents_by_tag(&mut self, tag: &str) -> ProvideResult<Vec<ShortDocEntity>> {
+        self.short_entities(|doc| doc.tags.iter().any(|t| t == tag))
+    }
+
+    async fn count_documents(&mut self) -> ProvideResult<Vec<DocCount>> {
+        let mut counts: Vec<DocCount> = Vec::new();
+        for doc in &self.documents {
+            match counts
+                .iter_mut()
+                .find(|c| c.kind == doc.kind && c.genre == doc.genre)
+            {
+                Some(count) => count.count += 1,
+                None => counts.push(DocCount {
+                    kind: doc.kind,
+                    genre: doc.genre,
+                    count: 1,
+                }),
+            }
+        }
+        Ok(counts)
+    }
+}
+
+/// A journal held in memory, mostly for tests. Transactions run one at a time, so they
+/// are all serializable, whatever the options.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    journal: Arc<Mutex<Journal>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn begin(&self, _options: TxOptions) -> ProvideResult<Box<dyn JournalTransaction>> {
+        let committed = self.journal.clone().lock_owned().await;
+        let working = (*committed).clone();
+        Ok(Box::new(MemoryTransaction {
+            committed,
+            working,
+            savepoints: Vec::new(),
+        }))
+    }
+
+    /// Ready once running transactions release the journal.
+    async fn check(&self) -> Vec<Check> {
+        let _journal = self.journal.lock().await;
+        vec![Check::new("store", Ok::<(), String>(()))]
+    }
+}
+
+/// Works on a copy of the journal, which replaces the journal on commit.
+struct MemoryTransaction {
+    committed: OwnedMutexGuard<Journal>,
+    working: Journal,
+    savepoints: Vec<(String, Journal)>,
+}
+
+impl MemoryTransaction {
+    fn find_savepoint(&self, name: &str) -> ProvideResult<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|(n, _)| n == name)
+            .ok_or_else(|| ProvideError::ModelViolation {
+                details: format!("savepoint \"{}\" does not exist", name),
+            })
+    }
+}
+
+#[async_trait]
+impl JournalTransaction for MemoryTransaction {
+    fn journal(&mut self) -> &mut (dyn model::ProvideJournal + Send) {
+        &mut self.working
+    }
+
+    async fn commit(self: Box<Self>) -> ProvideResult<()> {
+        let MemoryTransaction {
+            mut committed,
+            working,
+            ..
+        } = *self;
+        *committed = working;
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> ProvideResult<()> {
+        Ok(())
+    }
+
+    async fn savepoint(&mut self, name: &str) -> ProvideResult<()> {
+        self.savepoints
+            .push((String::from(name), self.working.clone()));
+        Ok(())
+    }
+
+    async fn release_savepoint(&mut self, name: &str) -> ProvideResult<()> {
+        let index = self.find_savepoint(name)?;
+        self.savepoints.truncate(index);
+        Ok(())
+    }
+
+    async fn rollback_to_savepoint(&mut self, name: &str) -> ProvideResult<()> {
+        let index = self.find_savepoint(name)?;
+        self.working = self.savepoints[index].1.clone();
+        self.savepoints.truncate(index + 1);
+        Ok(())
+    }
+}
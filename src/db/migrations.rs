@@ -0,0 +1,338 @@
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use slog::{info, Logger};
+use snafu::ResultExt;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::PgQueryAs;
+use sqlx::{Connection, Executor, PgConnection, PgPool, Transaction};
+use std::fmt;
+
+use crate::error;
+
+/// A schema migration, whose SQL is embedded in the binary.
+#[derive(Debug)]
+pub struct Migration {
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+impl Migration {
+    /// The checksum of the `up` script, recorded when the migration is applied, so that we
+    /// can detect a migration that was modified afterwards.
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.up.as_bytes()))
+    }
+}
+
+macro_rules! migration {
+    ($name:literal) => {
+        Migration {
+            name: $name,
+            up: include_str!(concat!("../../migrations/", $name, "/up.sql")),
+            down: include_str!(concat!("../../migrations/", $name, "/down.sql")),
+        }
+    };
+}
+
+/// All the migrations, in the order in which they are applied.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!("2020-06-01-000000_init"),
+    migration!("2020-12-01-120000_document_version"),
+    migration!("2020-12-02-120000_update_document"),
+    migration!("2020-12-03-120000_document_slug"),
+    migration!("2020-12-04-120000_document_slug_unique"),
+];
+
+/// Keeps track of the applied migrations. It lives outside of the `main` schema, which is
+/// dropped by the first migration.
+const CREATE_MIGRATIONS_TABLE: &str = r#"
+    CREATE TABLE IF NOT EXISTS public.journal_migrations (
+      name TEXT PRIMARY KEY,
+      checksum TEXT NOT NULL,
+      applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    )"#;
+
+/// Serializes concurrent migration runs, eg several replicas starting at once.
+const MIGRATIONS_LOCK: i64 = 0x6a6f75726e616c;
+
+type PgTransaction = Transaction<PoolConnection<PgConnection>>;
+
+/// A migration recorded in the database.
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationState {
+    Applied(DateTime<Utc>),
+    Pending,
+}
+
+impl fmt::Display for MigrationState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationState::Applied(at) => write!(f, "applied {}", at.to_rfc3339()),
+            MigrationState::Pending => write!(f, "pending"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub name: &'static str,
+    pub state: MigrationState,
+}
+
+/// Apply all pending migrations, each in its own transaction. Returns the names of the
+/// migrations applied.
+pub async fn up(pool: &PgPool, logger: &Logger) -> Result<Vec<&'static str>, error::Error> {
+    let mut done = Vec::new();
+    loop {
+        let mut tx = begin(pool).await?;
+        let applied = verify(&mut tx).await?;
+        let migration = match MIGRATIONS.get(applied.len()) {
+            Some(migration) => migration,
+            None => {
+                commit(tx).await?;
+                return Ok(done);
+            }
+        };
+
+        info!(logger, "Applying migration {}", migration.name);
+        run_script(&mut tx, migration.up)
+            .await
+            .context(error::DBError {
+                msg: format!("Could not apply migration {}", migration.name),
+            })?;
+        sqlx::query("INSERT INTO public.journal_migrations (name, checksum) VALUES ($1, $2)")
+            .bind(migration.name)
+            .bind(migration.checksum())
+            .execute(&mut tx as &mut PgConnection)
+            .await
+            .context(error::DBError {
+                msg: format!("Could not record migration {}", migration.name),
+            })?;
+        commit(tx).await?;
+        done.push(migration.name);
+    }
+}
+
+/// Revert the last `steps` applied migrations, each in its own transaction. Returns the
+/// names of the migrations reverted.
+pub async fn down(
+    pool: &PgPool,
+    logger: &Logger,
+    steps: usize,
+) -> Result<Vec<&'static str>, error::Error> {
+    let mut done = Vec::new();
+    while done.len() < steps {
+        let mut tx = begin(pool).await?;
+        let applied = verify(&mut tx).await?;
+        if applied.is_empty() {
+            commit(tx).await?;
+            break;
+        }
+        let migration = &MIGRATIONS[applied.len() - 1];
+
+        info!(logger, "Reverting migration {}", migration.name);
+        run_script(&mut tx, migration.down)
+            .await
+            .context(error::DBError {
+                msg: format!("Could not revert migration {}", migration.name),
+            })?;
+        sqlx::query("DELETE FROM public.journal_migrations WHERE name = $1")
+            .bind(migration.name)
+            .execute(&mut tx as &mut PgConnection)
+            .await
+            .context(error::DBError {
+                msg: format!("Could not unrecord migration {}", migration.name),
+            })?;
+        commit(tx).await?;
+        done.push(migration.name);
+    }
+    Ok(done)
+}
+
+/// Report, for each migration known to this binary, whether it is applied.
+pub async fn status(pool: &PgPool) -> Result<Vec<MigrationStatus>, error::Error> {
+    let mut tx = begin(pool).await?;
+    let applied = verify(&mut tx).await?;
+    commit(tx).await?;
+
+    let status = MIGRATIONS
+        .iter()
+        .enumerate()
+        .map(|(i, migration)| MigrationStatus {
+            name: migration.name,
+            state: match applied.get(i) {
+                Some(applied) => MigrationState::Applied(applied.applied_at),
+                None => MigrationState::Pending,
+            },
+        })
+        .collect();
+
+    Ok(status)
+}
+
+/// Check that the schema is exactly the one expected by this binary: every migration is
+/// applied, and there is no applied migration unknown to this binary.
+///
+/// Unlike the other operations, this one does not write to the database.
+pub async fn check(pool: &PgPool) -> Result<(), error::Error> {
+    let mut conn = pool.acquire().await.context(error::DBError {
+        msg: "could not acquire connection",
+    })?;
+
+    let (exists,): (bool,) =
+        sqlx::query_as("SELECT to_regclass('public.journal_migrations') IS NOT NULL")
+            .fetch_one(&mut conn as &mut PgConnection)
+            .await
+            .context(error::DBError {
+                msg: "could not look for migrations table",
+            })?;
+
+    let applied = if exists {
+        verify(&mut conn).await?.len()
+    } else {
+        0
+    };
+
+    match MIGRATIONS.get(applied) {
+        None => Ok(()),
+        Some(missing) => Err(error::Error::MigrationError {
+            msg: format!(
+                "The database schema is missing migration {} ({} of {} applied), run `service migrate up`",
+                missing.name,
+                applied,
+                MIGRATIONS.len()
+            ),
+        }),
+    }
+}
+
+/// Whether the database holds anything managed by the migrations, applied or not.
+pub async fn is_empty(pool: &PgPool) -> Result<bool, error::Error> {
+    let (empty,): (bool,) = sqlx::query_as(
+        r#"SELECT to_regclass('public.journal_migrations') IS NULL
+             AND to_regnamespace('main') IS NULL"#,
+    )
+    .fetch_one(pool)
+    .await
+    .context(error::DBError {
+        msg: "could not inspect database",
+    })?;
+
+    Ok(empty)
+}
+
+/// Drop everything created by the migrations, including what was created by earlier
+/// tools, which did not record migrations.
+pub async fn reset(pool: &PgPool, logger: &Logger) -> Result<(), error::Error> {
+    let reverted = down(pool, logger, MIGRATIONS.len()).await?;
+    info!(logger, "Reverted {} migration(s)", reverted.len());
+
+    let mut tx = begin(pool).await?;
+    run_script(
+        &mut tx,
+        r#"
+        DROP SCHEMA IF EXISTS main CASCADE;
+        DROP TYPE IF EXISTS genre;
+        DROP TYPE IF EXISTS kind;
+        DELETE FROM public.journal_migrations;"#,
+    )
+    .await
+    .context(error::DBError {
+        msg: "could not reset database",
+    })?;
+    commit(tx).await
+}
+
+/// Begin a transaction holding the migrations lock, with the migrations table created.
+async fn begin(pool: &PgPool) -> Result<PgTransaction, error::Error> {
+    let conn = pool.acquire().await.context(error::DBError {
+        msg: "could not acquire connection",
+    })?;
+    let mut tx = conn.begin().await.context(error::DBError {
+        msg: "could not initiate transaction",
+    })?;
+
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MIGRATIONS_LOCK)
+        .execute(&mut tx as &mut PgConnection)
+        .await
+        .context(error::DBError {
+            msg: "could not lock migrations",
+        })?;
+
+    run_script(&mut tx, CREATE_MIGRATIONS_TABLE)
+        .await
+        .context(error::DBError {
+            msg: "could not create migrations table",
+        })?;
+
+    Ok(tx)
+}
+
+/// Run a script holding several statements, which, unlike a query, cannot be prepared.
+async fn run_script(conn: &mut PgConnection, script: &str) -> sqlx::Result<u64> {
+    conn.execute(script).await
+}
+
+async fn commit(tx: PgTransaction) -> Result<(), error::Error> {
+    tx.commit().await.context(error::DBError {
+        msg: "could not commit transaction",
+    })?;
+    Ok(())
+}
+
+/// Read the applied migrations, and check that they are, in order, the first migrations
+/// known to this binary, unmodified since they were applied.
+async fn verify(conn: &mut PgConnection) -> Result<Vec<AppliedMigration>, error::Error> {
+    let rows: Vec<(String, String, DateTime<Utc>)> = sqlx::query_as(
+        "SELECT name, checksum, applied_at FROM public.journal_migrations ORDER BY name",
+    )
+    .fetch_all(conn)
+    .await
+    .context(error::DBError {
+        msg: "could not read applied migrations",
+    })?;
+    let applied = rows
+        .into_iter()
+        .map(|(name, checksum, applied_at)| AppliedMigration {
+            name,
+            checksum,
+            applied_at,
+        })
+        .collect::<Vec<_>>();
+
+    for (i, applied) in applied.iter().enumerate() {
+        match MIGRATIONS.get(i) {
+            Some(migration) if migration.name == applied.name => {
+                if migration.checksum() != applied.checksum {
+                    return Err(error::Error::MigrationError {
+                        msg: format!(
+                            "Migration {} was modified after it was applied (checksum {}, expected {})",
+                            migration.name,
+                            migration.checksum(),
+                            applied.checksum
+                        ),
+                    });
+                }
+            }
+            _ => {
+                return Err(error::Error::MigrationError {
+                    msg: format!(
+                        "Migration {} is applied, but unknown to this version of the service",
+                        applied.name
+                    ),
+                });
+            }
+        }
+    }
+
+    Ok(applied)
+}
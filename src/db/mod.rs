@@ -1,10 +1,169 @@
 use async_trait::async_trait;
+use serde::Serialize;
+use slog::Logger;
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
+use crate::error;
+use crate::settings::Database;
+
+pub mod memory;
+pub mod migrations;
 pub mod model;
 pub mod pg;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod tx;
 
+/// A backend holding the journal.
 #[async_trait]
-pub trait Db {
-    type Conn;
-    async fn conn(&self) -> Result<Self::Conn, sqlx::Error>;
+pub trait Store: Debug + Send + Sync {
+    /// Begin a transaction with the given characteristics.
+    async fn begin(
+        &self,
+        options: tx::TxOptions,
+    ) -> model::ProvideResult<Box<dyn JournalTransaction>>;
+
+    /// Probe the store for readiness, eg that connections can be acquired, the schema
+    /// queried, and that it is at the version expected by this binary.
+    async fn check(&self) -> Vec<Check>;
+
+    /// Close the connections of the store, waiting for those in use to be released.
+    async fn close(&self) {}
+
+    /// The state of the connection pool, for stores which have one.
+    fn pool_status(&self) -> Option<PoolStatus> {
+        None
+    }
+}
+
+/// The state of a connection pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolStatus {
+    /// Connections open, idle or in use
+    pub size: u32,
+    pub idle: usize,
+    /// Tasks waiting for a connection
+    pub waiters: usize,
+}
+
+/// Reject pool settings the pool would not honor, rather than fail on the first request.
+pub(crate) fn check_pool_settings(settings: &Database) -> Result<(), error::Error> {
+    if settings.max_size == 0 {
+        return Err(error::Error::MiscError {
+            msg: String::from("Invalid database settings: max_size must be at least 1"),
+        });
+    }
+    if settings.min_idle > settings.max_size {
+        return Err(error::Error::MiscError {
+            msg: format!(
+                "Invalid database settings: min_idle ({}) exceeds max_size ({})",
+                settings.min_idle, settings.max_size
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Counts a task waiting for a connection, for as long as it lives, so that a task
+/// which gives up waiting is not counted anymore.
+pub(crate) struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    pub(crate) fn new(waiters: &'a AtomicUsize) -> Self {
+        waiters.fetch_add(1, Ordering::Relaxed);
+        Waiting(waiters)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A step of a readiness probe.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    pub fn new<T, E: fmt::Display>(name: &'static str, result: Result<T, E>) -> Self {
+        match result {
+            Ok(_) => Check {
+                name,
+                ok: true,
+                error: None,
+            },
+            Err(err) => Check {
+                name,
+                ok: false,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+/// A transaction on a store. Nothing done through the journal is visible to other
+/// transactions until it is committed.
+#[async_trait]
+pub trait JournalTransaction: Send {
+    fn journal(&mut self) -> &mut (dyn model::ProvideJournal + Send);
+
+    async fn commit(self: Box<Self>) -> model::ProvideResult<()>;
+
+    async fn rollback(self: Box<Self>) -> model::ProvideResult<()>;
+
+    /// Set a savepoint, so that part of a transaction can be rolled back.
+    async fn savepoint(&mut self, name: &str) -> model::ProvideResult<()>;
+
+    /// Keep everything done since the savepoint.
+    async fn release_savepoint(&mut self, name: &str) -> model::ProvideResult<()>;
+
+    /// Discard everything done since the savepoint.
+    async fn rollback_to_savepoint(&mut self, name: &str) -> model::ProvideResult<()>;
+}
+
+/// The backends a store can be opened with, given by the scheme of the database URL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// `memory://`: starts empty, and is lost on exit.
+    Memory,
+    /// `postgres://` or `postgresql://`
+    Postgres,
+    /// `sqlite://`, only available with the `sqlite` feature.
+    Sqlite,
+}
+
+impl Backend {
+    pub fn from_url(url: &str) -> Result<Self, error::Error> {
+        let scheme = url.split(':').next().unwrap_or_default();
+        match scheme {
+            "memory" => Ok(Backend::Memory),
+            "postgres" | "postgresql" => Ok(Backend::Postgres),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => Err(error::Error::MiscError {
+                msg: format!("Unsupported database URL scheme '{}'", scheme),
+            }),
+        }
+    }
+}
+
+/// Open the store given by the URL of the settings, with their pool settings.
+pub async fn open(settings: &Database, logger: &Logger) -> Result<Arc<dyn Store>, error::Error> {
+    match Backend::from_url(&settings.url)? {
+        Backend::Memory => Ok(Arc::new(memory::MemoryStore::new())),
+        Backend::Postgres => Ok(Arc::new(pg::PgStore::new(settings, logger).await?)),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Ok(Arc::new(sqlite::SqliteStore::new(settings, logger).await?)),
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => Err(error::Error::MiscError {
+            msg: String::from("SQLite support requires building with the sqlite feature"),
+        }),
+    }
 }
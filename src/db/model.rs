@@ -1,19 +1,20 @@
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
 use snafu::Snafu;
 use std::convert::TryFrom;
 use uuid::Uuid;
 
 pub type EntityId = Uuid;
 
-#[derive(Debug, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type)]
 #[sqlx(rename = "kind", rename_all = "lowercase")]
 pub enum DocKind {
     Doc,
     Post,
 }
 
-#[derive(Debug, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, sqlx::Type)]
 #[sqlx(rename = "genre", rename_all = "lowercase")]
 pub enum DocGenre {
     Tutorial,
@@ -22,6 +23,14 @@ pub enum DocGenre {
     Reference,
 }
 
+/// The number of documents of a kind and genre.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocCount {
+    pub kind: DocKind,
+    pub genre: DocGenre,
+    pub count: i64,
+}
+
 #[derive(Debug)]
 pub struct AuthorEntity {
     pub id: Option<EntityId>,
@@ -64,22 +73,174 @@ pub struct DocEntity {
     pub content: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub version: i32,
+}
+
+/// A partial update of a document: only the fields which are set are modified.
+#[derive(Debug, Default)]
+pub struct DocPatchEntity {
+    pub title: Option<String>,
+    pub outline: Option<String>,
+    pub author_fullname: Option<String>,
+    pub author_resource: Option<String>,
+    /// Replaces all the tags.
+    pub tags: Option<Vec<String>>,
+    /// Appended to the tags, unless already present.
+    pub add_tags: Option<Vec<String>>,
+    /// Removed from the tags.
+    pub remove_tags: Option<Vec<String>>,
+    pub image_title: Option<String>,
+    pub image_resource: Option<String>,
+    pub image_author_fullname: Option<String>,
+    pub image_author_resource: Option<String>,
+    pub kind: Option<DocKind>,
+    pub genre: Option<DocGenre>,
+    pub content: Option<String>,
+}
+
+impl DocPatchEntity {
+    /// Apply the patch to a document, the way `main.update_document` does. The author and
+    /// the image are replaced as a whole, so that they can be upserted.
+    pub fn apply(&self, doc: DocEntity) -> DocEntity {
+        let DocEntity {
+            id,
+            title,
+            outline,
+            author,
+            tags,
+            image,
+            kind,
+            genre,
+            content,
+            created_at,
+            updated_at,
+            version,
+        } = doc;
+
+        let author = AuthorEntity {
+            id: None,
+            fullname: self.author_fullname.clone().unwrap_or(author.fullname),
+            resource: self.author_resource.clone().unwrap_or(author.resource),
+        };
+
+        let image = ImageEntity {
+            id: None,
+            title: self.image_title.clone().unwrap_or(image.title),
+            author: AuthorEntity {
+                id: None,
+                fullname: self
+                    .image_author_fullname
+                    .clone()
+                    .unwrap_or(image.author.fullname),
+                resource: self
+                    .image_author_resource
+                    .clone()
+                    .unwrap_or(image.author.resource),
+            },
+            resource: self.image_resource.clone().unwrap_or(image.resource),
+        };
+
+        let mut tags = self.tags.clone().unwrap_or(tags);
+        if let Some(add_tags) = &self.add_tags {
+            for tag in add_tags {
+                if !tags.contains(tag) {
+                    tags.push(tag.clone());
+                }
+            }
+        }
+        if let Some(remove_tags) = &self.remove_tags {
+            tags.retain(|tag| !remove_tags.contains(tag));
+        }
+
+        DocEntity {
+            id,
+            title: self.title.clone().unwrap_or(title),
+            outline: self.outline.clone().unwrap_or(outline),
+            author,
+            tags,
+            image,
+            kind: self.kind.unwrap_or(kind),
+            genre: self.genre.unwrap_or(genre),
+            content: self.content.clone().unwrap_or(content),
+            created_at,
+            updated_at,
+            version,
+        }
+    }
 }
 
 #[async_trait]
 pub trait ProvideJournal {
     async fn get_all_documents(&mut self) -> ProvideResult<Vec<ShortDocEntity>>;
 
+    /// Stream every document, whatever its kind, with its content, eg to back up the
+    /// journal.
+    fn export_documents(&mut self) -> BoxStream<'_, ProvideResult<DocEntity>>;
+
+    /// Stream every author, including those no document refers to anymore.
+    fn export_authors(&mut self) -> BoxStream<'_, ProvideResult<AuthorEntity>>;
+
+    /// Stream every image, with its author, including those no document refers to
+    /// anymore.
+    fn export_images(&mut self) -> BoxStream<'_, ProvideResult<ImageEntity>>;
+
+    /// Stream the distinct tags of the documents, in alphabetical order.
+    fn export_tags(&mut self) -> BoxStream<'_, ProvideResult<String>>;
+
+    /// Create an author, or update the resource of the author with that fullname.
+    async fn restore_author(&mut self, author: &AuthorEntity) -> ProvideResult<()>;
+
+    /// Create an image, or update the title and the author of the image with that
+    /// resource.
+    async fn restore_image(&mut self, image: &ImageEntity) -> ProvideResult<()>;
+
     async fn get_document_by_id(&mut self, id: EntityId) -> ProvideResult<Option<DocEntity>>;
 
+    /// Return the current version of a document, locking it until the end of the
+    /// transaction.
+    async fn get_document_version(&mut self, id: EntityId) -> ProvideResult<Option<i32>>;
+
     async fn create_or_update_document(&mut self, doc: &DocEntity) -> ProvideResult<DocEntity>;
 
+    /// Insert a new document. Returns None, leaving the document untouched, if there is
+    /// already a document with that id, including one inserted by a concurrent
+    /// transaction.
+    async fn create_document(&mut self, doc: &DocEntity) -> ProvideResult<Option<DocEntity>>;
+
+    /// Create or update a document with the timestamps and the version it was backed up
+    /// with. A document which exists already gets the next version, so that clients
+    /// holding the previous one see the change.
+    async fn restore_document(&mut self, doc: &DocEntity) -> ProvideResult<DocEntity>;
+
+    /// Delete every document, returning how many were deleted.
+    async fn delete_all_documents(&mut self) -> ProvideResult<u64>;
+
+    /// Return the id of a document with the given title, or with a title having the
+    /// same slug.
+    async fn find_document_by_title_or_slug(
+        &mut self,
+        title: &str,
+        slug: &str,
+    ) -> ProvideResult<Option<EntityId>>;
+
+    /// Apply a partial update to an existing document. Returns None if there is no
+    /// document with that id.
+    async fn update_document(
+        &mut self,
+        id: EntityId,
+        patch: &DocPatchEntity,
+    ) -> ProvideResult<Option<DocEntity>>;
+
     async fn get_all_documents_by_query(
         &mut self,
         query: &str,
     ) -> ProvideResult<Vec<ShortDocEntity>>;
 
     async fn get_all_documents_by_tag(&mut self, tag: &str) -> ProvideResult<Vec<ShortDocEntity>>;
+
+    /// Count the documents of every kind and genre. Combinations without documents are
+    /// omitted.
+    async fn count_documents(&mut self) -> ProvideResult<Vec<DocCount>>;
 }
 
 pub type ProvideResult<T> = Result<T, ProvideError>;
@@ -102,12 +263,50 @@ pub enum ProvideError {
     #[snafu(visibility(pub))]
     ModelViolation { details: String },
 
+    /// The document was modified since the version the client expected
+    #[snafu(display("Version conflict: current version is {}", current))]
+    #[snafu(visibility(pub))]
+    VersionConflict { current: i32 },
+
+    /// The transaction conflicted with a concurrent one, and can be retried
+    #[snafu(display("Transaction conflict: {}", details))]
+    #[snafu(visibility(pub))]
+    TransactionConflict { details: String },
+
+    /// The query, or the wait for a connection, took longer than allowed
+    #[snafu(display("Query timeout: {}", details))]
+    #[snafu(visibility(pub))]
+    QueryTimeout { details: String },
+
     /// The requested operation violates the data model
     #[snafu(display("UnHandled Error: {}", source))]
     #[snafu(visibility(pub))]
     UnHandledError { source: sqlx::Error },
 }
 
+impl ProvideError {
+    /// The name of the variant, eg to label metrics.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            ProvideError::NotFound => "NotFound",
+            ProvideError::UniqueViolation { .. } => "UniqueViolation",
+            ProvideError::ModelViolation { .. } => "ModelViolation",
+            ProvideError::VersionConflict { .. } => "VersionConflict",
+            ProvideError::TransactionConflict { .. } => "TransactionConflict",
+            ProvideError::QueryTimeout { .. } => "QueryTimeout",
+            ProvideError::UnHandledError { .. } => "UnHandledError",
+        }
+    }
+
+    /// Whether running the transaction again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ProvideError::TransactionConflict { .. } => true,
+            _ => false,
+        }
+    }
+}
+
 impl From<sqlx::Error> for ProvideError {
     /// Convert a SQLx error into a provider error
     ///
@@ -120,17 +319,21 @@ impl From<sqlx::Error> for ProvideError {
             sqlx::Error::Database(db_err) => {
                 if let Some(pg_err) = db_err.try_downcast_ref::<sqlx::postgres::PgError>() {
                     if let Ok(provide_err) = ProvideError::try_from(pg_err) {
-                        provide_err
-                    } else {
-                        ProvideError::UnHandledError {
-                            source: sqlx::Error::Database(db_err),
-                        }
+                        return provide_err;
                     }
-                } else {
-                    ProvideError::UnHandledError {
-                        source: sqlx::Error::Database(db_err),
+                }
+                #[cfg(feature = "sqlite")]
+                {
+                    if let Some(sqlite_err) = db_err.try_downcast_ref::<sqlx::sqlite::SqliteError>()
+                    {
+                        if let Ok(provide_err) = ProvideError::try_from(sqlite_err) {
+                            return provide_err;
+                        }
                     }
                 }
+                ProvideError::UnHandledError {
+                    source: sqlx::Error::Database(db_err),
+                }
             }
             _ => ProvideError::UnHandledError { source: e },
         }
@@ -1,21 +1,26 @@
 use async_trait::async_trait;
-use slog::{debug, info, o, Logger};
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use slog::{info, Logger};
 use snafu::ResultExt;
 use sqlx::error::DatabaseError;
 use sqlx::pool::PoolConnection;
 use sqlx::postgres::{PgError, PgQueryAs, PgRow};
 use sqlx::row::{FromRow, Row};
-use sqlx::{PgConnection, PgPool};
+use sqlx::{Connection, PgConnection, PgPool, Transaction};
 use std::convert::TryFrom;
-use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use super::migrations;
 use super::model;
-use super::Db;
+use super::tx::TxOptions;
+use super::{check_pool_settings, Check, JournalTransaction, PoolStatus, Store, Waiting};
 use crate::error;
+use crate::settings::Database;
 
-// This should match the information in return_document_type
+// This should match the information in return_document_type, followed by the version
+// (see DOCUMENT_WITH_VERSION)
 impl<'c> FromRow<'c, PgRow<'c>> for model::DocEntity {
     fn from_row(row: &PgRow<'c>) -> Result<Self, sqlx::Error> {
         let author = model::AuthorEntity {
@@ -49,6 +54,7 @@ impl<'c> FromRow<'c, PgRow<'c>> for model::DocEntity {
             content: row.try_get(7)?,
             created_at: row.try_get(17)?,
             updated_at: row.try_get(18)?,
+            version: row.try_get(19)?,
         })
     }
 }
@@ -90,12 +96,192 @@ impl<'c> FromRow<'c, PgRow<'c>> for model::ShortDocEntity {
     }
 }
 
+impl<'c> FromRow<'c, PgRow<'c>> for model::DocCount {
+    fn from_row(row: &PgRow<'c>) -> Result<Self, sqlx::Error> {
+        Ok(model::DocCount {
+            kind: row.try_get(0)?,
+            genre: row.try_get(1)?,
+            count: row.try_get(2)?,
+        })
+    }
+}
+
+/// The version is not part of return_document_type, so we join it to the document.
+const DOCUMENT_WITH_VERSION: &str = r#"
+    SELECT d.*, v.version
+    FROM main.get_document_by_id($1) AS d
+    JOIN main.documents AS v ON v.id = d.id"#;
+
+/// Every document, as read by DOCUMENT_WITH_VERSION, oldest first.
+const ALL_DOCUMENTS_WITH_VERSION: &str = r#"
+    SELECT d.*, v.version
+    FROM main.documents AS v, main.get_document_by_id(v.id) AS d
+    ORDER BY v.created_at"#;
+
 /// Open a connection to a database
 pub async fn connect(db_url: &str) -> sqlx::Result<PgPool> {
     let pool = PgPool::new(db_url).await?;
     Ok(pool)
 }
 
+pub type PgTransaction = Transaction<PoolConnection<PgConnection>>;
+
+/// The journal, stored in Postgres.
+#[derive(Debug, Clone)]
+pub struct PgStore {
+    pub pool: PgPool,
+    /// Tasks waiting for a connection, which the pool does not report
+    waiters: Arc<AtomicUsize>,
+    acquire_timeout: Duration,
+    /// Milliseconds, set on each transaction
+    statement_timeout: Option<u64>,
+}
+
+impl PgStore {
+    /// Connect to the database, and check that its schema is the one expected by this
+    /// binary.
+    pub async fn new(settings: &Database, logger: &Logger) -> Result<Self, error::Error> {
+        check_pool_settings(settings)?;
+        let url = &settings.url;
+        let mut builder = PgPool::builder()
+            .max_size(settings.max_size)
+            .min_size(settings.min_idle)
+            .connect_timeout(Duration::from_secs(settings.connect_timeout));
+        if let Some(idle_timeout) = settings.idle_timeout {
+            builder = builder.idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        let pool = builder.build(url).await.context(error::DBError {
+            msg: format!("Could not connect to {}", url),
+        })?;
+
+        let row: (String,) = sqlx::query_as("SELECT version()")
+            .fetch_one(&pool)
+            .await
+            .context(error::DBError {
+                msg: format!("Could not test database version for {}", url),
+            })?;
+
+        info!(logger, "db version: {:?}", row.0);
+
+        // Refuse to serve with a schema this binary was not written for.
+        migrations::check(&pool).await?;
+
+        Ok(PgStore {
+            pool,
+            waiters: Arc::new(AtomicUsize::new(0)),
+            acquire_timeout: Duration::from_secs(settings.acquire_timeout),
+            statement_timeout: settings.statement_timeout,
+        })
+    }
+}
+
+#[async_trait]
+impl Store for PgStore {
+    async fn begin(&self, options: TxOptions) -> model::ProvideResult<Box<dyn JournalTransaction>> {
+        let conn = {
+            let _waiting = Waiting::new(&self.waiters);
+            tokio::time::timeout(self.acquire_timeout, self.pool.acquire())
+                .await
+                .map_err(|_| model::ProvideError::QueryTimeout {
+                    details: format!("no connection available within {:?}", self.acquire_timeout),
+                })??
+        };
+        let mut tx = conn.begin().await?;
+
+        let mode = if options.read_only {
+            "READ ONLY"
+        } else {
+            "READ WRITE"
+        };
+        sqlx::query(&format!(
+            "SET TRANSACTION ISOLATION LEVEL {} {}",
+            options.isolation, mode
+        ))
+        .execute(&mut tx as &mut PgConnection)
+        .await?;
+
+        // Local to the transaction, as the connection goes back to the pool.
+        if let Some(statement_timeout) = self.statement_timeout {
+            sqlx::query(&format!(
+                "SET LOCAL statement_timeout = {}",
+                statement_timeout
+            ))
+            .execute(&mut tx as &mut PgConnection)
+            .await?;
+        }
+
+        Ok(Box::new(tx))
+    }
+
+    async fn check(&self) -> Vec<Check> {
+        let mut conn = match self.pool.acquire().await {
+            Ok(conn) => conn,
+            Err(err) => return vec![Check::new("pool", Err::<(), _>(err))],
+        };
+        let query = sqlx::query("SELECT 1 FROM main.documents LIMIT 1")
+            .execute(&mut conn as &mut PgConnection)
+            .await;
+        drop(conn);
+
+        vec![
+            Check::new("pool", Ok::<(), sqlx::Error>(())),
+            Check::new("query", query),
+            Check::new("migrations", migrations::check(&self.pool).await),
+        ]
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        Some(PoolStatus {
+            size: self.pool.size(),
+            idle: self.pool.idle(),
+            waiters: self.waiters.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[async_trait]
+impl JournalTransaction for PgTransaction {
+    fn journal(&mut self) -> &mut (dyn model::ProvideJournal + Send) {
+        let conn: &mut PgConnection = self;
+        conn
+    }
+
+    async fn commit(self: Box<Self>) -> model::ProvideResult<()> {
+        Transaction::commit(*self).await?;
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> model::ProvideResult<()> {
+        Transaction::rollback(*self).await?;
+        Ok(())
+    }
+
+    async fn savepoint(&mut self, name: &str) -> model::ProvideResult<()> {
+        sqlx::query(&format!("SAVEPOINT {}", name))
+            .execute(self as &mut PgConnection)
+            .await?;
+        Ok(())
+    }
+
+    async fn release_savepoint(&mut self, name: &str) -> model::ProvideResult<()> {
+        sqlx::query(&format!("RELEASE SAVEPOINT {}", name))
+            .execute(self as &mut PgConnection)
+            .await?;
+        Ok(())
+    }
+
+    async fn rollback_to_savepoint(&mut self, name: &str) -> model::ProvideResult<()> {
+        sqlx::query(&format!("ROLLBACK TO SAVEPOINT {}", name))
+            .execute(self as &mut PgConnection)
+            .await?;
+        Ok(())
+    }
+}
+
 impl TryFrom<&PgError> for model::ProvideError {
     type Error = ();
 
@@ -112,6 +298,14 @@ impl TryFrom<&PgError> for model::ProvideError {
             code if code.starts_with("23") => model::ProvideError::ModelViolation {
                 details: pg_err.message().to_owned(),
             },
+            // serialization_failure and deadlock_detected
+            "40001" | "40P01" => model::ProvideError::TransactionConflict {
+                details: pg_err.message().to_owned(),
+            },
+            // query_canceled, eg by statement_timeout
+            "57014" => model::ProvideError::QueryTimeout {
+                details: pg_err.message().to_owned(),
+            },
             _ => return Err(()),
         };
 
@@ -119,15 +313,6 @@ impl TryFrom<&PgError> for model::ProvideError {
     }
 }
 
-#[async_trait]
-impl Db for PgPool {
-    type Conn = PoolConnection<PgConnection>;
-
-    async fn conn(&self) -> Result<Self::Conn, sqlx::Error> {
-        self.acquire().await
-    }
-}
-
 #[async_trait]
 impl model::ProvideJournal for PgConnection {
     async fn get_all_documents(&mut self) -> model::ProvideResult<Vec<model::ShortDocEntity>> {
@@ -143,20 +328,122 @@ impl model::ProvideJournal for PgConnection {
         &mut self,
         id: model::EntityId,
     ) -> model::ProvideResult<Option<model::DocEntity>> {
-        let doc: Option<model::DocEntity> =
-            sqlx::query_as(r#"SELECT * FROM main.get_document_by_id($1)"#)
+        let doc: Option<model::DocEntity> = sqlx::query_as(DOCUMENT_WITH_VERSION)
+            .bind(id)
+            .fetch_optional(self)
+            .await?;
+
+        Ok(doc)
+    }
+
+    fn export_documents(&mut self) -> BoxStream<'_, model::ProvideResult<model::DocEntity>> {
+        sqlx::query_as::<_, model::DocEntity>(ALL_DOCUMENTS_WITH_VERSION)
+            .fetch(self)
+            .map_err(model::ProvideError::from)
+            .boxed()
+    }
+
+    fn export_authors(&mut self) -> BoxStream<'_, model::ProvideResult<model::AuthorEntity>> {
+        sqlx::query_as::<_, (model::EntityId, String, String)>(
+            "SELECT id, fullname, resource FROM main.authors ORDER BY fullname",
+        )
+        .fetch(self)
+        .map(|row| {
+            let (id, fullname, resource) = row?;
+            Ok(model::AuthorEntity {
+                id: Some(id),
+                fullname,
+                resource,
+            })
+        })
+        .boxed()
+    }
+
+    fn export_images(&mut self) -> BoxStream<'_, model::ProvideResult<model::ImageEntity>> {
+        sqlx::query_as::<
+            _,
+            (
+                model::EntityId,
+                String,
+                String,
+                model::EntityId,
+                String,
+                String,
+            ),
+        >(
+            "SELECT i.id, i.title, i.resource, a.id, a.fullname, a.resource
+             FROM main.images AS i JOIN main.authors AS a ON a.id = i.author_id
+             ORDER BY i.resource",
+        )
+        .fetch(self)
+        .map(|row| {
+            let (id, title, resource, author_id, author_fullname, author_resource) = row?;
+            Ok(model::ImageEntity {
+                id: Some(id),
+                title,
+                author: model::AuthorEntity {
+                    id: Some(author_id),
+                    fullname: author_fullname,
+                    resource: author_resource,
+                },
+                resource,
+            })
+        })
+        .boxed()
+    }
+
+    fn export_tags(&mut self) -> BoxStream<'_, model::ProvideResult<String>> {
+        sqlx::query_as::<_, (String,)>(
+            "SELECT DISTINCT unnest(tags) AS tag FROM main.documents ORDER BY tag",
+        )
+        .fetch(self)
+        .map_ok(|(tag,)| tag)
+        .map_err(model::ProvideError::from)
+        .boxed()
+    }
+
+    async fn restore_author(&mut self, author: &model::AuthorEntity) -> model::ProvideResult<()> {
+        sqlx::query("SELECT main.upsert_author($1, $2)")
+            .bind(&author.fullname)
+            .bind(&author.resource)
+            .execute(self)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn restore_image(&mut self, image: &model::ImageEntity) -> model::ProvideResult<()> {
+        sqlx::query("SELECT main.upsert_image($1, $2, $3, $4)")
+            .bind(&image.title)
+            .bind(&image.resource)
+            .bind(&image.author.fullname)
+            .bind(&image.author.resource)
+            .execute(self)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_document_version(
+        &mut self,
+        id: model::EntityId,
+    ) -> model::ProvideResult<Option<i32>> {
+        let version: Option<(i32,)> =
+            sqlx::query_as(r#"SELECT version FROM main.documents WHERE id = $1 FOR UPDATE"#)
                 .bind(id)
                 .fetch_optional(self)
                 .await?;
 
-        Ok(doc)
+        Ok(version.map(|v| v.0))
     }
 
     async fn create_or_update_document(
         &mut self,
         doc: &model::DocEntity,
     ) -> model::ProvideResult<model::DocEntity> {
-        let resp: model::DocEntity = sqlx::query_as(
+        // The document returned by create_document_with_id does not hold the version,
+        // which is maintained by a trigger, so we read it back once it is written.
+        sqlx::query(
             "SELECT * FROM main.create_document_with_id(
             $1::UUID, $2::TEXT, $3::TEXT, $4::TEXT, $5::TEXT,
             $5::TEXT, $6::TEXT, $7::TEXT[], $8::TEXT, $9::TEXT,
@@ -175,10 +462,155 @@ impl model::ProvideJournal for PgConnection {
         .bind(&doc.image.resource)
         .bind(&doc.kind)
         .bind(&doc.genre)
-        .fetch_one(self)
+        .execute(&mut *self)
         .await?;
 
-        Ok(resp)
+        self.get_document_by_id(doc.id)
+            .await?
+            .ok_or(model::ProvideError::NotFound)
+    }
+
+    async fn create_document(
+        &mut self,
+        doc: &model::DocEntity,
+    ) -> model::ProvideResult<Option<model::DocEntity>> {
+        // A concurrent insert of the same id is waited for, and then nothing is inserted.
+        let inserted = sqlx::query(
+            "INSERT INTO main.documents (
+               id, title, outline, author_id, content, tags, image_id, kind, genre)
+             VALUES (
+               $1, $2, $3, main.upsert_author($4, $5), $6, $7,
+               main.upsert_image($8, $9, $10, $11), $12, $13)
+             ON CONFLICT (id) DO NOTHING",
+        )
+        .bind(&doc.id)
+        .bind(&doc.title)
+        .bind(&doc.outline)
+        .bind(&doc.author.fullname)
+        .bind(&doc.author.resource)
+        .bind(&doc.content)
+        .bind(&doc.tags)
+        .bind(&doc.image.title)
+        .bind(&doc.image.resource)
+        .bind(&doc.image.author.fullname)
+        .bind(&doc.image.author.resource)
+        .bind(&doc.kind)
+        .bind(&doc.genre)
+        .execute(&mut *self)
+        .await?;
+
+        if inserted == 0 {
+            return Ok(None);
+        }
+        self.get_document_by_id(doc.id).await
+    }
+
+    async fn restore_document(
+        &mut self,
+        doc: &model::DocEntity,
+    ) -> model::ProvideResult<model::DocEntity> {
+        // On update, the version is incremented by the increment_document_version
+        // trigger.
+        sqlx::query(
+            "INSERT INTO main.documents (
+               id, title, outline, author_id, content, tags, image_id, kind, genre,
+               created_at, updated_at, version)
+             VALUES (
+               $1, $2, $3, main.upsert_author($4, $5), $6, $7,
+               main.upsert_image($8, $9, $10, $11), $12, $13, $14, $15, $16)
+             ON CONFLICT (id) DO UPDATE SET
+               title = EXCLUDED.title,
+               outline = EXCLUDED.outline,
+               author_id = EXCLUDED.author_id,
+               content = EXCLUDED.content,
+               tags = EXCLUDED.tags,
+               image_id = EXCLUDED.image_id,
+               kind = EXCLUDED.kind,
+               genre = EXCLUDED.genre,
+               created_at = EXCLUDED.created_at,
+               updated_at = EXCLUDED.updated_at",
+        )
+        .bind(&doc.id)
+        .bind(&doc.title)
+        .bind(&doc.outline)
+        .bind(&doc.author.fullname)
+        .bind(&doc.author.resource)
+        .bind(&doc.content)
+        .bind(&doc.tags)
+        .bind(&doc.image.title)
+        .bind(&doc.image.resource)
+        .bind(&doc.image.author.fullname)
+        .bind(&doc.image.author.resource)
+        .bind(&doc.kind)
+        .bind(&doc.genre)
+        .bind(&doc.created_at)
+        .bind(&doc.updated_at)
+        .bind(&doc.version)
+        .execute(&mut *self)
+        .await?;
+
+        self.get_document_by_id(doc.id)
+            .await?
+            .ok_or(model::ProvideError::NotFound)
+    }
+
+    async fn delete_all_documents(&mut self) -> model::ProvideResult<u64> {
+        let deleted = sqlx::query("DELETE FROM main.documents")
+            .execute(self)
+            .await?;
+
+        Ok(deleted)
+    }
+
+    async fn find_document_by_title_or_slug(
+        &mut self,
+        title: &str,
+        slug: &str,
+    ) -> model::ProvideResult<Option<model::EntityId>> {
+        let id: Option<(model::EntityId,)> = sqlx::query_as(
+            r#"SELECT id FROM main.documents WHERE title = $1 OR ($2 <> '' AND main.slugify(title) = $2) LIMIT 1"#,
+        )
+        .bind(title)
+        .bind(slug)
+        .fetch_optional(self)
+        .await?;
+
+        Ok(id.map(|id| id.0))
+    }
+
+    async fn update_document(
+        &mut self,
+        id: model::EntityId,
+        patch: &model::DocPatchEntity,
+    ) -> model::ProvideResult<Option<model::DocEntity>> {
+        let resp: (Option<model::EntityId>,) = sqlx::query_as(
+            "SELECT main.update_document(
+            $1::UUID, $2::TEXT, $3::TEXT, $4::TEXT, $5::TEXT,
+            $6::TEXT, $7::TEXT[], $8::TEXT[], $9::TEXT[], $10::TEXT,
+            $11::TEXT, $12::TEXT, $13::TEXT, $14::KIND, $15::GENRE)",
+        )
+        .bind(id)
+        .bind(&patch.title)
+        .bind(&patch.outline)
+        .bind(&patch.author_fullname)
+        .bind(&patch.author_resource)
+        .bind(&patch.content)
+        .bind(&patch.tags)
+        .bind(&patch.add_tags)
+        .bind(&patch.remove_tags)
+        .bind(&patch.image_title)
+        .bind(&patch.image_resource)
+        .bind(&patch.image_author_fullname)
+        .bind(&patch.image_author_resource)
+        .bind(&patch.kind)
+        .bind(&patch.genre)
+        .fetch_one(&mut *self)
+        .await?;
+
+        match resp.0 {
+            None => Ok(None),
+            Some(id) => self.get_document_by_id(id).await,
+        }
     }
 
     async fn get_all_documents_by_query(
@@ -206,93 +638,13 @@ impl model::ProvideJournal for PgConnection {
 
         Ok(docs)
     }
-}
-
-pub async fn init_db(conn_str: &str, logger: Logger) -> Result<(), error::Error> {
-    info!(logger, "Initializing  DB @ {}", conn_str);
-    migration_down(conn_str, &logger).await?;
-    migration_up(conn_str, &logger).await?;
-    Ok(())
-}
 
-pub async fn migration_up(conn_str: &str, logger: &Logger) -> Result<(), error::Error> {
-    let clogger = logger.new(o!("database" => String::from(conn_str)));
-    debug!(clogger, "Movine Up");
-    // This is essentially running 'psql $DATABASE_URL < db/init.sql', and logging the
-    // psql output.
-    // FIXME This relies on a command psql, which is not desibable.
-    // We could alternatively try to use sqlx...
-    // There may be a tool for doing migrations.
-    let mut cmd = Command::new("movine");
-    cmd.env("DATABASE_URL", conn_str);
-    cmd.arg("up");
-    cmd.stdout(Stdio::piped());
-
-    let mut child = cmd.spawn().context(error::TokioIOError {
-        msg: String::from("Failed to execute movine"),
-    })?;
-
-    let stdout = child.stdout.take().ok_or(error::Error::MiscError {
-        msg: String::from("child did not have a handle to stdout"),
-    })?;
-
-    let mut reader = BufReader::new(stdout).lines();
-
-    // Ensure the child process is spawned in the runtime so it can
-    // make progress on its own while we await for any output.
-    tokio::spawn(async {
-        // FIXME Need to do something about logging this and returning an error.
-        let _status = child.await.expect("child process encountered an error");
-        // println!("child status was: {}", status);
-    });
-    debug!(clogger, "Spawned migration up");
-
-    while let Some(line) = reader.next_line().await.context(error::TokioIOError {
-        msg: String::from("Could not read from piped output"),
-    })? {
-        debug!(clogger, "movine: {}", line);
-    }
-
-    Ok(())
-}
+    async fn count_documents(&mut self) -> model::ProvideResult<Vec<model::DocCount>> {
+        let counts: Vec<model::DocCount> =
+            sqlx::query_as("SELECT kind, genre, count(*) FROM main.documents GROUP BY kind, genre")
+                .fetch_all(self)
+                .await?;
 
-pub async fn migration_down(conn_str: &str, logger: &Logger) -> Result<(), error::Error> {
-    let clogger = logger.new(o!("database" => String::from(conn_str)));
-    debug!(clogger, "Movine Down");
-    // This is essentially running 'psql $DATABASE_URL < db/init.sql', and logging the
-    // psql output.
-    // FIXME This relies on a command psql, which is not desibable.
-    // We could alternatively try to use sqlx...
-    // There may be a tool for doing migrations.
-    let mut cmd = Command::new("movine");
-    cmd.env("DATABASE_URL", conn_str);
-    cmd.arg("down");
-    cmd.stdout(Stdio::piped());
-
-    let mut child = cmd.spawn().context(error::TokioIOError {
-        msg: String::from("Failed to execute movine"),
-    })?;
-
-    let stdout = child.stdout.take().ok_or(error::Error::MiscError {
-        msg: String::from("child did not have a handle to stdout"),
-    })?;
-
-    let mut reader = BufReader::new(stdout).lines();
-
-    // Ensure the child process is spawned in the runtime so it can
-    // make progress on its own while we await for any output.
-    tokio::spawn(async {
-        // FIXME Need to do something about logging this and returning an error.
-        let _status = child.await.expect("child process encountered an error");
-        // println!("child status was: {}", status);
-    });
-    debug!(clogger, "Spawned migration down");
-
-    while let Some(line) = reader.next_line().await.context(error::TokioIOError {
-        msg: String::from("Could not read from piped output"),
-    })? {
-        debug!(clogger, "movine: {}", line);
-    }
-
-    Ok(())
+        Ok(counts)
+    }
 }
@@ -0,0 +1,759 @@
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use slog::{info, Logger};
+use snafu::ResultExt;
+use sqlx::error::DatabaseError;
+use sqlx::pool::PoolConnection;
+use sqlx::row::{FromRow, Row};
+use sqlx::sqlite::{SqliteError, SqliteQueryAs, SqliteRow};
+use sqlx::{Connection, Executor, SqliteConnection, SqlitePool, Transaction};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::model;
+use super::tx::TxOptions;
+use super::{check_pool_settings, Check, JournalTransaction, PoolStatus, Store, Waiting};
+use crate::api::utils::slugify;
+use crate::error;
+use crate::settings::Database;
+
+/// Version of migrations/sqlite/schema.sql, recorded in PRAGMA user_version.
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = include_str!("../../migrations/sqlite/schema.sql");
+
+pub type SqliteTransaction = Transaction<PoolConnection<SqliteConnection>>;
+
+fn decode_error<E>(err: E) -> sqlx::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    sqlx::Error::Decode(Box::new(err))
+}
+
+fn get_uuid(row: &SqliteRow, index: usize) -> Result<Uuid, sqlx::Error> {
+    let id: String = row.try_get(index)?;
+    Uuid::parse_str(&id).map_err(decode_error)
+}
+
+fn parse_uuid(id: &str) -> model::ProvideResult<Uuid> {
+    Uuid::parse_str(id).map_err(|err| decode_error(err).into())
+}
+
+fn get_timestamp(row: &SqliteRow, index: usize) -> Result<DateTime<Utc>, sqlx::Error> {
+    let timestamp: String = row.try_get(index)?;
+    DateTime::parse_from_rfc3339(&timestamp)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(decode_error)
+}
+
+fn get_tags(row: &SqliteRow, index: usize) -> Result<Vec<String>, sqlx::Error> {
+    let tags: String = row.try_get(index)?;
+    serde_json::from_str(&tags).map_err(decode_error)
+}
+
+fn get_kind(row: &SqliteRow, index: usize) -> Result<model::DocKind, sqlx::Error> {
+    let kind: String = row.try_get(index)?;
+    match kind.as_str() {
+        "doc" => Ok(model::DocKind::Doc),
+        "post" => Ok(model::DocKind::Post),
+        _ => Err(sqlx::Error::Decode(
+            format!("unknown document kind '{}'", kind).into(),
+        )),
+    }
+}
+
+fn get_genre(row: &SqliteRow, index: usize) -> Result<model::DocGenre, sqlx::Error> {
+    let genre: String = row.try_get(index)?;
+    match genre.as_str() {
+        "tutorial" => Ok(model::DocGenre::Tutorial),
+        "howto" => Ok(model::DocGenre::Howto),
+        "background" => Ok(model::DocGenre::Background),
+        "reference" => Ok(model::DocGenre::Reference),
+        _ => Err(sqlx::Error::Decode(
+            format!("unknown document genre '{}'", genre).into(),
+        )),
+    }
+}
+
+fn kind_str(kind: model::DocKind) -> &'static str {
+    match kind {
+        model::DocKind::Doc => "doc",
+        model::DocKind::Post => "post",
+    }
+}
+
+fn genre_str(genre: model::DocGenre) -> &'static str {
+    match genre {
+        model::DocGenre::Tutorial => "tutorial",
+        model::DocGenre::Howto => "howto",
+        model::DocGenre::Background => "background",
+        model::DocGenre::Reference => "reference",
+    }
+}
+
+fn timestamp_str(timestamp: DateTime<Utc>) -> String {
+    // A fixed width, so that timestamps sort as strings.
+    timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn get_author(row: &SqliteRow, index: usize) -> Result<model::AuthorEntity, sqlx::Error> {
+    Ok(model::AuthorEntity {
+        id: Some(get_uuid(row, index)?),
+        fullname: row.try_get(index + 1)?,
+        resource: row.try_get(index + 2)?,
+    })
+}
+
+fn get_image(row: &SqliteRow) -> Result<model::ImageEntity, sqlx::Error> {
+    Ok(model::ImageEntity {
+        id: Some(get_uuid(row, 8)?),
+        title: row.try_get(9)?,
+        author: get_author(row, 10)?,
+        resource: row.try_get(13)?,
+    })
+}
+
+// This should match the columns of document_view
+impl<'c> FromRow<'c, SqliteRow<'c>> for model::DocEntity {
+    fn from_row(row: &SqliteRow<'c>) -> Result<Self, sqlx::Error> {
+        Ok(model::DocEntity {
+            id: get_uuid(row, 0)?,
+            title: row.try_get(1)?,
+            outline: row.try_get(2)?,
+            author: get_author(row, 3)?,
+            content: row.try_get(6)?,
+            tags: get_tags(row, 7)?,
+            image: get_image(row)?,
+            kind: get_kind(row, 14)?,
+            genre: get_genre(row, 15)?,
+            created_at: get_timestamp(row, 16)?,
+            updated_at: get_timestamp(row, 17)?,
+            version: row.try_get(18)?,
+        })
+    }
+}
+
+// This should match the columns of document_view
+impl<'c> FromRow<'c, SqliteRow<'c>> for model::ShortDocEntity {
+    fn from_row(row: &SqliteRow<'c>) -> Result<Self, sqlx::Error> {
+        Ok(model::ShortDocEntity {
+            id: get_uuid(row, 0)?,
+            title: row.try_get(1)?,
+            outline: row.try_get(2)?,
+            author: get_author(row, 3)?,
+            tags: get_tags(row, 7)?,
+            image: get_image(row)?,
+            kind: get_kind(row, 14)?,
+            genre: get_genre(row, 15)?,
+            created_at: get_timestamp(row, 16)?,
+            updated_at: get_timestamp(row, 17)?,
+        })
+    }
+}
+
+impl<'c> FromRow<'c, SqliteRow<'c>> for model::DocCount {
+    fn from_row(row: &SqliteRow<'c>) -> Result<Self, sqlx::Error> {
+        Ok(model::DocCount {
+            kind: get_kind(row, 0)?,
+            genre: get_genre(row, 1)?,
+            count: row.try_get(2)?,
+        })
+    }
+}
+
+impl TryFrom<&SqliteError> for model::ProvideError {
+    type Error = ();
+
+    /// Attempt to convert a SQLite error into a generic ProvideError
+    ///
+    /// * [SQLite Result Codes](https://www.sqlite.org/rescode.html)
+    fn try_from(sqlite_err: &SqliteError) -> Result<Self, Self::Error> {
+        let code = sqlite_err
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .ok_or(())?;
+        let provider_err = match code {
+            // SQLITE_CONSTRAINT_PRIMARYKEY and SQLITE_CONSTRAINT_UNIQUE
+            1555 | 2067 => model::ProvideError::UniqueViolation {
+                details: sqlite_err.message().to_owned(),
+            },
+            // SQLITE_CONSTRAINT
+            code if code & 0xff == 19 => model::ProvideError::ModelViolation {
+                details: sqlite_err.message().to_owned(),
+            },
+            // SQLITE_BUSY and SQLITE_LOCKED: another connection holds the database
+            code if code & 0xff == 5 || code & 0xff == 6 => {
+                model::ProvideError::TransactionConflict {
+                    details: sqlite_err.message().to_owned(),
+                }
+            }
+            _ => return Err(()),
+        };
+
+        Ok(provider_err)
+    }
+}
+
+/// The journal, stored in a single SQLite file.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pub pool: SqlitePool,
+    /// Tasks waiting for a connection, which the pool does not report
+    waiters: Arc<AtomicUsize>,
+    acquire_timeout: Duration,
+}
+
+impl SqliteStore {
+    /// Open the database, creating its schema if it is empty. SQLite has no statement
+    /// timeout, so `statement_timeout` is ignored.
+    pub async fn new(settings: &Database, logger: &Logger) -> Result<Self, error::Error> {
+        check_pool_settings(settings)?;
+        let url = &settings.url;
+        let mut builder = SqlitePool::builder()
+            .max_size(settings.max_size)
+            .min_size(settings.min_idle)
+            .connect_timeout(Duration::from_secs(settings.connect_timeout));
+        if let Some(idle_timeout) = settings.idle_timeout {
+            builder = builder.idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        let pool = builder.build(url).await.context(error::DBError {
+            msg: format!("Could not open SQLite database {}", url),
+        })?;
+
+        let mut tx = pool
+            .acquire()
+            .await
+            .context(error::DBError {
+                msg: "could not acquire connection",
+            })?
+            .begin()
+            .await
+            .context(error::DBError {
+                msg: "could not initiate transaction",
+            })?;
+
+        let (version,): (i32,) = sqlx::query_as("PRAGMA user_version")
+            .fetch_one(&mut tx as &mut SqliteConnection)
+            .await
+            .context(error::DBError {
+                msg: "could not read schema version",
+            })?;
+
+        if version == 0 {
+            info!(logger, "Creating SQLite schema version {}", SCHEMA_VERSION);
+            let conn: &mut SqliteConnection = &mut tx;
+            conn.execute(SCHEMA).await.context(error::DBError {
+                msg: "could not create schema",
+            })?;
+            // PRAGMA does not take parameters.
+            let pragma = format!("PRAGMA user_version = {}", SCHEMA_VERSION);
+            conn.execute(pragma.as_str())
+                .await
+                .context(error::DBError {
+                    msg: "could not record schema version",
+                })?;
+        } else if version != SCHEMA_VERSION {
+            return Err(error::Error::MigrationError {
+                msg: format!(
+                    "The SQLite schema is at version {}, but this binary expects version {}",
+                    version, SCHEMA_VERSION
+                ),
+            });
+        }
+
+        tx.commit().await.context(error::DBError {
+            msg: "could not commit transaction",
+        })?;
+
+        Ok(SqliteStore {
+            pool,
+            waiters: Arc::new(AtomicUsize::new(0)),
+            acquire_timeout: Duration::from_secs(settings.acquire_timeout),
+        })
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    /// SQLite transactions are always serializable, and the read only flag is not
+    /// enforced.
+    async fn begin(
+        &self,
+        _options: TxOptions,
+    ) -> model::ProvideResult<Box<dyn JournalTransaction>> {
+        let conn = {
+            let _waiting = Waiting::new(&self.waiters);
+            tokio::time::timeout(self.acquire_timeout, self.pool.acquire())
+                .await
+                .map_err(|_| model::ProvideError::QueryTimeout {
+                    details: format!("no connection available within {:?}", self.acquire_timeout),
+                })??
+        };
+        let tx = conn.begin().await?;
+        Ok(Box::new(tx))
+    }
+
+    async fn check(&self) -> Vec<Check> {
+        let mut conn = match self.pool.acquire().await {
+            Ok(conn) => conn,
+            Err(err) => return vec![Check::new("pool", Err::<(), _>(err))],
+        };
+        let query = sqlx::query("SELECT 1 FROM documents LIMIT 1")
+            .execute(&mut conn as &mut SqliteConnection)
+            .await;
+        let version = sqlx::query_as("PRAGMA user_version")
+            .fetch_one(&mut conn as &mut SqliteConnection)
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|(version,): (i32,)| {
+                if version == SCHEMA_VERSION {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "schema is at version {}, expected {}",
+                        version, SCHEMA_VERSION
+                    ))
+                }
+            });
+
+        vec![
+            Check::new("pool", Ok::<(), sqlx::Error>(())),
+            Check::new("query", query),
+            Check::new("schema", version),
+        ]
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        Some(PoolStatus {
+            size: self.pool.size(),
+            idle: self.pool.idle(),
+            waiters: self.waiters.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[async_trait]
+impl JournalTransaction for SqliteTransaction {
+    fn journal(&mut self) -> &mut (dyn model::ProvideJournal + Send) {
+        let conn: &mut SqliteConnection = self;
+        conn
+    }
+
+    async fn commit(self: Box<Self>) -> model::ProvideResult<()> {
+        Transaction::commit(*self).await?;
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> model::ProvideResult<()> {
+        Transaction::rollback(*self).await?;
+        Ok(())
+    }
+
+    async fn savepoint(&mut self, name: &str) -> model::ProvideResult<()> {
+        sqlx::query(&format!("SAVEPOINT {}", name))
+            .execute(self as &mut SqliteConnection)
+            .await?;
+        Ok(())
+    }
+
+    async fn release_savepoint(&mut self, name: &str) -> model::ProvideResult<()> {
+        sqlx::query(&format!("RELEASE SAVEPOINT {}", name))
+            .execute(self as &mut SqliteConnection)
+            .await?;
+        Ok(())
+    }
+
+    async fn rollback_to_savepoint(&mut self, name: &str) -> model::ProvideResult<()> {
+        sqlx::query(&format!("ROLLBACK TO SAVEPOINT {}", name))
+            .execute(self as &mut SqliteConnection)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Returns the id of the author with the given fullname, creating it if needed.
+async fn upsert_author(
+    conn: &mut SqliteConnection,
+    author: &model::AuthorEntity,
+) -> model::ProvideResult<String> {
+    sqlx::query(
+        "INSERT INTO authors (id, fullname, resource) VALUES (?, ?, ?)
+         ON CONFLICT (fullname) DO UPDATE SET resource = excluded.resource",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(&author.fullname)
+    .bind(&author.resource)
+    .execute(&mut *conn)
+    .await?;
+
+    let (id,): (String,) = sqlx::query_as("SELECT id FROM authors WHERE fullname = ?")
+        .bind(&author.fullname)
+        .fetch_one(conn)
+        .await?;
+
+    Ok(id)
+}
+
+/// Returns the id of the image with the given resource, creating it if needed.
+async fn upsert_image(
+    conn: &mut SqliteConnection,
+    image: &model::ImageEntity,
+) -> model::ProvideResult<String> {
+    let author_id = upsert_author(&mut *conn, &image.author).await?;
+
+    sqlx::query(
+        "INSERT INTO images (id, title, author_id, resource) VALUES (?, ?, ?, ?)
+         ON CONFLICT (resource) DO UPDATE SET
+           title = excluded.title, author_id = excluded.author_id",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(&image.title)
+    .bind(&author_id)
+    .bind(&image.resource)
+    .execute(&mut *conn)
+    .await?;
+
+    let (id,): (String,) = sqlx::query_as("SELECT id FROM images WHERE resource = ?")
+        .bind(&image.resource)
+        .fetch_one(conn)
+        .await?;
+
+    Ok(id)
+}
+
+/// Insert a document, or update it, taking the timestamps and the version given for a
+/// new document.
+const UPSERT_DOCUMENT: &str = "INSERT INTO documents (
+       id, title, slug, outline, author_id, content, tags, image_id, kind, genre,
+       created_at, updated_at, version)
+     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+     ON CONFLICT (id) DO UPDATE SET
+       title = excluded.title,
+       slug = excluded.slug,
+       outline = excluded.outline,
+       author_id = excluded.author_id,
+       content = excluded.content,
+       tags = excluded.tags,
+       image_id = excluded.image_id,
+       kind = excluded.kind,
+       genre = excluded.genre,
+       updated_at = excluded.updated_at,
+       version = documents.version + 1";
+
+/// Like UPSERT_DOCUMENT, but an existing document is left untouched.
+const INSERT_DOCUMENT: &str = "INSERT INTO documents (
+       id, title, slug, outline, author_id, content, tags, image_id, kind, genre,
+       created_at, updated_at, version)
+     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+     ON CONFLICT (id) DO NOTHING";
+
+/// Like UPSERT_DOCUMENT, but an existing document also takes the given timestamps.
+const RESTORE_DOCUMENT: &str = "INSERT INTO documents (
+       id, title, slug, outline, author_id, content, tags, image_id, kind, genre,
+       created_at, updated_at, version)
+     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+     ON CONFLICT (id) DO UPDATE SET
+       title = excluded.title,
+       slug = excluded.slug,
+       outline = excluded.outline,
+       author_id = excluded.author_id,
+       content = excluded.content,
+       tags = excluded.tags,
+       image_id = excluded.image_id,
+       kind = excluded.kind,
+       genre = excluded.genre,
+       created_at = excluded.created_at,
+       updated_at = excluded.updated_at,
+       version = documents.version + 1";
+
+/// Write a document with one of the statements above, along with its author and image.
+async fn write_document(
+    conn: &mut SqliteConnection,
+    statement: &str,
+    doc: &model::DocEntity,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    version: i32,
+) -> model::ProvideResult<u64> {
+    let author_id = upsert_author(&mut *conn, &doc.author).await?;
+    let image_id = upsert_image(&mut *conn, &doc.image).await?;
+    let tags =
+        serde_json::to_string(&doc.tags).map_err(|err| model::ProvideError::ModelViolation {
+            details: format!("Could not serialize tags: {}", err),
+        })?;
+
+    let written = sqlx::query(statement)
+        .bind(doc.id.to_string())
+        .bind(&doc.title)
+        .bind(slugify(&doc.title))
+        .bind(&doc.outline)
+        .bind(&author_id)
+        .bind(&doc.content)
+        .bind(&tags)
+        .bind(&image_id)
+        .bind(kind_str(doc.kind))
+        .bind(genre_str(doc.genre))
+        .bind(timestamp_str(created_at))
+        .bind(timestamp_str(updated_at))
+        .bind(version)
+        .execute(conn)
+        .await?;
+
+    Ok(written)
+}
+
+/// Turn a free text query into an FTS5 query matching documents holding every word, so
+/// that FTS5 operators in the text are not interpreted.
+fn fts_query(query: &str) -> String {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[async_trait]
+impl model::ProvideJournal for SqliteConnection {
+    async fn get_all_documents(&mut self) -> model::ProvideResult<Vec<model::ShortDocEntity>> {
+        let docs: Vec<model::ShortDocEntity> = sqlx::query_as(
+            "SELECT * FROM document_view WHERE kind = 'doc' ORDER BY updated_at DESC",
+        )
+        .fetch_all(self)
+        .await?;
+
+        Ok(docs)
+    }
+
+    async fn get_document_by_id(
+        &mut self,
+        id: model::EntityId,
+    ) -> model::ProvideResult<Option<model::DocEntity>> {
+        let doc: Option<model::DocEntity> =
+            sqlx::query_as("SELECT * FROM document_view WHERE id = ?")
+                .bind(id.to_string())
+                .fetch_optional(self)
+                .await?;
+
+        Ok(doc)
+    }
+
+    /// There is no row locking in SQLite: a concurrent write makes the transaction fail
+    /// with SQLITE_BUSY, and it is retried.
+    async fn get_document_version(
+        &mut self,
+        id: model::EntityId,
+    ) -> model::ProvideResult<Option<i32>> {
+        let version: Option<(i32,)> = sqlx::query_as("SELECT version FROM documents WHERE id = ?")
+            .bind(id.to_string())
+            .fetch_optional(self)
+            .await?;
+
+        Ok(version.map(|v| v.0))
+    }
+
+    fn export_documents(&mut self) -> BoxStream<'_, model::ProvideResult<model::DocEntity>> {
+        sqlx::query_as::<_, model::DocEntity>("SELECT * FROM document_view ORDER BY created_at")
+            .fetch(self)
+            .map_err(model::ProvideError::from)
+            .boxed()
+    }
+
+    fn export_authors(&mut self) -> BoxStream<'_, model::ProvideResult<model::AuthorEntity>> {
+        sqlx::query_as::<_, (String, String, String)>(
+            "SELECT id, fullname, resource FROM authors ORDER BY fullname",
+        )
+        .fetch(self)
+        .map(|row| {
+            let (id, fullname, resource) = row?;
+            Ok(model::AuthorEntity {
+                id: Some(parse_uuid(&id)?),
+                fullname,
+                resource,
+            })
+        })
+        .boxed()
+    }
+
+    fn export_images(&mut self) -> BoxStream<'_, model::ProvideResult<model::ImageEntity>> {
+        sqlx::query_as::<_, (String, String, String, String, String, String)>(
+            "SELECT i.id, i.title, i.resource, a.id, a.fullname, a.resource
+             FROM images AS i JOIN authors AS a ON a.id = i.author_id
+             ORDER BY i.resource",
+        )
+        .fetch(self)
+        .map(|row| {
+            let (id, title, resource, author_id, author_fullname, author_resource) = row?;
+            Ok(model::ImageEntity {
+                id: Some(parse_uuid(&id)?),
+                title,
+                author: model::AuthorEntity {
+                    id: Some(parse_uuid(&author_id)?),
+                    fullname: author_fullname,
+                    resource: author_resource,
+                },
+                resource,
+            })
+        })
+        .boxed()
+    }
+
+    fn export_tags(&mut self) -> BoxStream<'_, model::ProvideResult<String>> {
+        sqlx::query_as::<_, (String,)>(
+            "SELECT DISTINCT tag.value FROM documents, json_each(documents.tags) AS tag
+             ORDER BY tag.value",
+        )
+        .fetch(self)
+        .map_ok(|(tag,)| tag)
+        .map_err(model::ProvideError::from)
+        .boxed()
+    }
+
+    async fn restore_author(&mut self, author: &model::AuthorEntity) -> model::ProvideResult<()> {
+        upsert_author(self, author).await?;
+
+        Ok(())
+    }
+
+    async fn restore_image(&mut self, image: &model::ImageEntity) -> model::ProvideResult<()> {
+        upsert_image(self, image).await?;
+
+        Ok(())
+    }
+
+    async fn create_or_update_document(
+        &mut self,
+        doc: &model::DocEntity,
+    ) -> model::ProvideResult<model::DocEntity> {
+        let now = Utc::now();
+        write_document(&mut *self, UPSERT_DOCUMENT, doc, now, now, 1).await?;
+
+        self.get_document_by_id(doc.id)
+            .await?
+            .ok_or(model::ProvideError::NotFound)
+    }
+
+    async fn create_document(
+        &mut self,
+        doc: &model::DocEntity,
+    ) -> model::ProvideResult<Option<model::DocEntity>> {
+        let now = Utc::now();
+        let inserted = write_document(&mut *self, INSERT_DOCUMENT, doc, now, now, 1).await?;
+
+        if inserted == 0 {
+            return Ok(None);
+        }
+        self.get_document_by_id(doc.id).await
+    }
+
+    async fn restore_document(
+        &mut self,
+        doc: &model::DocEntity,
+    ) -> model::ProvideResult<model::DocEntity> {
+        write_document(
+            &mut *self,
+            RESTORE_DOCUMENT,
+            doc,
+            doc.created_at,
+            doc.updated_at,
+            doc.version,
+        )
+        .await?;
+
+        self.get_document_by_id(doc.id)
+            .await?
+            .ok_or(model::ProvideError::NotFound)
+    }
+
+    async fn delete_all_documents(&mut self) -> model::ProvideResult<u64> {
+        let deleted = sqlx::query("DELETE FROM documents").execute(self).await?;
+
+        Ok(deleted)
+    }
+
+    async fn find_document_by_title_or_slug(
+        &mut self,
+        title: &str,
+        slug: &str,
+    ) -> model::ProvideResult<Option<model::EntityId>> {
+        let id: Option<(String,)> = sqlx::query_as(
+            "SELECT id FROM documents WHERE title = ? OR (slug = ? AND slug <> '') LIMIT 1",
+        )
+        .bind(title)
+        .bind(slug)
+        .fetch_optional(self)
+        .await?;
+
+        id.map(|(id,)| parse_uuid(&id)).transpose()
+    }
+
+    async fn update_document(
+        &mut self,
+        id: model::EntityId,
+        patch: &model::DocPatchEntity,
+    ) -> model::ProvideResult<Option<model::DocEntity>> {
+        match self.get_document_by_id(id).await? {
+            None => Ok(None),
+            Some(doc) => self
+                .create_or_update_document(&patch.apply(doc))
+                .await
+                .map(Some),
+        }
+    }
+
+    async fn get_all_documents_by_query(
+        &mut self,
+        query: &str,
+    ) -> model::ProvideResult<Vec<model::ShortDocEntity>> {
+        let query = fts_query(query);
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let docs: Vec<model::ShortDocEntity> = sqlx::query_as(
+            "SELECT v.* FROM document_view AS v
+             JOIN documents_fts ON documents_fts.rowid = v.doc_rowid
+             WHERE documents_fts MATCH ?
+             ORDER BY documents_fts.rank",
+        )
+        .bind(&query)
+        .fetch_all(self)
+        .await?;
+
+        Ok(docs)
+    }
+
+    async fn get_all_documents_by_tag(
+        &mut self,
+        tag: &str,
+    ) -> model::ProvideResult<Vec<model::ShortDocEntity>> {
+        let docs: Vec<model::ShortDocEntity> = sqlx::query_as(
+            "SELECT * FROM document_view
+             WHERE EXISTS (SELECT 1 FROM json_each(document_view.tags) WHERE value = ?)
+             ORDER BY updated_at DESC",
+        )
+        .bind(tag)
+        .fetch_all(self)
+        .await?;
+
+        Ok(docs)
+    }
+
+    async fn count_documents(&mut self) -> model::ProvideResult<Vec<model::DocCount>> {
+        let counts: Vec<model::DocCount> =
+            sqlx::query_as("SELECT kind, genre, count(*) FROM documents GROUP BY kind, genre")
+                .fetch_all(self)
+                .await?;
+
+        Ok(counts)
+    }
+}
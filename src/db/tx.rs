@@ -0,0 +1,116 @@
+use futures::future::Future;
+use slog::{debug, Logger};
+use snafu::ResultExt;
+use std::fmt;
+use std::time::Duration;
+
+use super::{JournalTransaction, Store};
+use crate::error;
+
+/// Number of attempts before giving up on a transaction failing with a retryable error.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled on each subsequent retry.
+const BASE_BACKOFF: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl fmt::Display for IsolationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IsolationLevel::ReadCommitted => write!(f, "READ COMMITTED"),
+            IsolationLevel::RepeatableRead => write!(f, "REPEATABLE READ"),
+            IsolationLevel::Serializable => write!(f, "SERIALIZABLE"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TxOptions {
+    pub isolation: IsolationLevel,
+    pub read_only: bool,
+}
+
+impl TxOptions {
+    /// For queries: a consistent snapshot, which cannot modify the database.
+    pub fn read_only() -> Self {
+        TxOptions {
+            isolation: IsolationLevel::RepeatableRead,
+            read_only: true,
+        }
+    }
+
+    /// For mutations: concurrent transactions behave as if run one after the other.
+    pub fn read_write() -> Self {
+        TxOptions {
+            isolation: IsolationLevel::Serializable,
+            read_only: false,
+        }
+    }
+}
+
+/// Run `f` in a transaction, and commit it if `f` succeeds.
+///
+/// `f` takes ownership of the transaction, and gives it back along with its result, so
+/// that it can borrow from its environment. If the transaction fails with a retryable
+/// error (deadlock, serialization failure), it is run again from scratch, with an
+/// exponential backoff.
+pub async fn run<T, F, Fut>(
+    store: &dyn Store,
+    options: TxOptions,
+    logger: &Logger,
+    mut f: F,
+) -> Result<T, error::Error>
+where
+    F: FnMut(Box<dyn JournalTransaction>) -> Fut,
+    Fut: Future<Output = (Box<dyn JournalTransaction>, Result<T, error::Error>)>,
+{
+    let mut attempt = 1;
+    loop {
+        let result = match store.begin(options).await {
+            Ok(tx) => {
+                let (tx, result) = f(tx).await;
+                match result {
+                    Ok(value) => tx
+                        .commit()
+                        .await
+                        .map(|_| value)
+                        .context(error::DBProvideError {
+                            msg: "could not commit transaction",
+                        }),
+                    Err(err) => {
+                        if let Err(rollback_err) = tx.rollback().await {
+                            debug!(logger, "Could not rollback transaction: {}", rollback_err);
+                        }
+                        Err(err)
+                    }
+                }
+            }
+            Err(err) => Err(error::Error::DBProvideError {
+                msg: String::from("could not initiate transaction"),
+                source: err,
+            }),
+        };
+
+        match result {
+            Err(err) if err.is_retryable() && attempt < MAX_ATTEMPTS => {
+                let backoff = BASE_BACKOFF * 2u32.pow(attempt - 1);
+                debug!(
+                    logger,
+                    "Retrying transaction in {}ms: {}",
+                    backoff.as_millis(),
+                    err;
+                    "attempt" => attempt
+                );
+                tokio::time::delay_for(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
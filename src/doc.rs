@@ -0,0 +1,289 @@
+use clap::ArgMatches;
+use slog::{info, Logger};
+use snafu::ResultExt;
+use std::env;
+use std::path::Path;
+use url::Url;
+use uuid::Uuid;
+
+use journal::api::markdown;
+use journal::api::model::{Doc, DocSpec, DocumentRequestBody, ShortDoc};
+use journal::client::{self, Client};
+use journal::error;
+
+/// Longest title shown in listings, beyond which titles are truncated.
+const MAX_TITLE_WIDTH: usize = 48;
+
+/// Manage the documents of a running journal service, through its GraphQL API.
+#[allow(clippy::needless_lifetimes)]
+pub async fn doc<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
+    match matches.subcommand() {
+        ("list", Some(sm)) => list(sm).await,
+        ("show", Some(sm)) => show(sm).await,
+        ("search", Some(sm)) => search(sm).await,
+        ("push", Some(sm)) => push(sm, logger).await,
+        ("open", Some(sm)) => open(sm, logger).await,
+        _ => Err(error::Error::MiscError {
+            msg: String::from("Unrecognized doc subcommand"),
+        }),
+    }
+}
+
+#[allow(clippy::needless_lifetimes)]
+async fn list<'a>(matches: &ArgMatches<'a>) -> Result<(), error::Error> {
+    let docs = client(matches)
+        .list_documents()
+        .await
+        .map_err(|err| client_error("Could not list documents", err))?;
+    print_table(&docs.docs);
+    Ok(())
+}
+
+#[allow(clippy::needless_lifetimes)]
+async fn show<'a>(matches: &ArgMatches<'a>) -> Result<(), error::Error> {
+    let id = id_arg(matches)?;
+    let doc = fetch(&client(matches), id).await?;
+    print!("{}", markdown::to_markdown(&doc)?);
+    Ok(())
+}
+
+#[allow(clippy::needless_lifetimes)]
+async fn search<'a>(matches: &ArgMatches<'a>) -> Result<(), error::Error> {
+    let query = matches.value_of("query").ok_or(error::Error::MiscError {
+        msg: String::from("Missing query"),
+    })?;
+    let client = client(matches);
+    let docs = if matches.is_present("tag") {
+        client.list_documents_by_tag(query).await
+    } else {
+        client.list_documents_by_query(query).await
+    }
+    .map_err(|err| client_error("Could not search documents", err))?;
+    print_table(&docs.docs);
+    Ok(())
+}
+
+/// Push a note. When the file is named after a document id, as the `note` script does,
+/// that document is created or replaced, otherwise a new document is created.
+#[allow(clippy::needless_lifetimes)]
+async fn push<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
+    let path = matches.value_of("file").ok_or(error::Error::MiscError {
+        msg: String::from("Missing note file"),
+    })?;
+    let resources = matches
+        .value_of("resources")
+        .ok_or(error::Error::MiscError {
+            msg: String::from("Missing resources URL"),
+        })?;
+    let resources = Url::parse(resources).map_err(|err| error::Error::MiscError {
+        msg: format!("Invalid resources URL {}: {}", resources, err),
+    })?;
+
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .context(error::TokioIOError {
+            msg: format!("Could not read {}", path),
+        })?;
+    let spec = markdown::from_note(&text, &resources)?;
+
+    let client = client(matches);
+    let id = Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| Uuid::parse_str(stem).ok());
+    let resp = match id {
+        Some(id) => {
+            info!(logger, "Pushing {} as document {}", path, id);
+            let body = DocumentRequestBody {
+                doc: spec.with_id(id),
+            };
+            client.create_or_update_document(&body).await
+        }
+        None => {
+            info!(logger, "Pushing {} as a new document", path);
+            client.create_document(&spec).await
+        }
+    }
+    .map_err(|err| client_error(&format!("Could not push {}", path), err))?;
+
+    if let Some(doc) = resp.doc {
+        println!("{} (version {})", doc.id, doc.version);
+    }
+    Ok(())
+}
+
+/// Edit a document in `$EDITOR`, and push it back if it was modified. The update is
+/// rejected if the document was modified by someone else in the meantime, in which case
+/// the edited file is kept.
+#[allow(clippy::needless_lifetimes)]
+async fn open<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
+    let id = id_arg(matches)?;
+    let client = client(matches);
+    let doc = fetch(&client, id).await?;
+
+    let original = markdown::to_markdown(&doc)?;
+    let path = env::temp_dir().join(format!("{}.md", id));
+    tokio::fs::write(&path, &original)
+        .await
+        .context(error::TokioIOError {
+            msg: format!("Could not write {}", path.display()),
+        })?;
+
+    edit(&path).await?;
+
+    let edited = tokio::fs::read_to_string(&path)
+        .await
+        .context(error::TokioIOError {
+            msg: format!("Could not read {}", path.display()),
+        })?;
+    if edited == original {
+        info!(logger, "Document {} unchanged", id);
+    } else {
+        let body = DocumentRequestBody {
+            doc: DocSpec::from(markdown::from_markdown(id, &edited)?),
+        };
+        let resp = client
+            .create_or_update_document(&body)
+            .await
+            .map_err(|err| {
+                client_error(
+                    &format!(
+                        "Could not push document {}, your changes are kept in {}",
+                        id,
+                        path.display()
+                    ),
+                    err,
+                )
+            })?;
+        if let Some(doc) = resp.doc {
+            println!("{} (version {})", doc.id, doc.version);
+        }
+    }
+
+    tokio::fs::remove_file(&path)
+        .await
+        .context(error::TokioIOError {
+            msg: format!("Could not remove {}", path.display()),
+        })
+}
+
+/// Run the editor on the file, and wait for it to exit. `$EDITOR` may hold arguments,
+/// eg `code --wait`.
+async fn edit(path: &Path) -> Result<(), error::Error> {
+    let editor = env::var("EDITOR").unwrap_or_else(|_| String::from("vi"));
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or(error::Error::MiscError {
+        msg: String::from("EDITOR is empty"),
+    })?;
+
+    let status = tokio::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .await
+        .context(error::TokioIOError {
+            msg: format!("Could not run editor {}", editor),
+        })?;
+
+    if !status.success() {
+        return Err(error::Error::MiscError {
+            msg: format!(
+                "Editor {} failed ({}), {} was not pushed",
+                editor,
+                status,
+                path.display()
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[allow(clippy::needless_lifetimes)]
+fn client<'a>(matches: &ArgMatches<'a>) -> Client {
+    // The url has a default value.
+    Client::new(matches.value_of("url").unwrap())
+}
+
+#[allow(clippy::needless_lifetimes)]
+fn id_arg<'a>(matches: &ArgMatches<'a>) -> Result<Uuid, error::Error> {
+    let id = matches.value_of("id").ok_or(error::Error::MiscError {
+        msg: String::from("Missing document id"),
+    })?;
+    Uuid::parse_str(id).map_err(|err| error::Error::MiscError {
+        msg: format!("Invalid document id {}: {}", id, err),
+    })
+}
+
+async fn fetch(client: &Client, id: Uuid) -> Result<Doc, error::Error> {
+    client
+        .find_document_by_id(id)
+        .await
+        .map_err(|err| client_error(&format!("Could not fetch document {}", id), err))?
+        .doc
+        .ok_or(error::Error::MiscError {
+            msg: format!("Document {} not found", id),
+        })
+}
+
+/// Validation errors are reported with all their violations.
+fn client_error(msg: &str, err: client::Error) -> error::Error {
+    let details = match &err {
+        client::Error::Validation { violations, .. } => violations
+            .iter()
+            .map(|violation| format!("{}: {}", violation.field, violation.message))
+            .collect::<Vec<_>>()
+            .join("; "),
+        err => err.to_string(),
+    };
+    error::Error::MiscError {
+        msg: format!("{}: {}", msg, details),
+    }
+}
+
+fn print_table(docs: &[ShortDoc]) {
+    let header = ["ID", "TITLE", "KIND", "GENRE", "UPDATED", "TAGS"];
+    let rows = docs
+        .iter()
+        .map(|doc| {
+            [
+                doc.id.to_string(),
+                truncate(&doc.front.title, MAX_TITLE_WIDTH),
+                format!("{:?}", doc.front.kind).to_lowercase(),
+                format!("{:?}", doc.front.genre).to_lowercase(),
+                doc.front.updated_at.format("%Y-%m-%d").to_string(),
+                doc.front.tags.join(", "),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let mut widths = header.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    line(header.to_vec());
+    for row in &rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        String::from(text)
+    } else {
+        let mut text = text.chars().take(width - 1).collect::<String>();
+        text.push('…');
+        text
+    }
+}
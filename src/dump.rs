@@ -0,0 +1,547 @@
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use futures::stream::{BoxStream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use snafu::ResultExt;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use journal::api::model;
+use journal::db::model as db;
+use journal::db::model::ProvideJournal;
+use journal::db::tx::TxOptions;
+use journal::db::{JournalTransaction, Store};
+use journal::error;
+use journal::settings::Settings;
+use journal::state::State;
+
+/// Version of the records written by `dump`. `restore` refuses any other version.
+const DUMP_VERSION: u32 = 1;
+
+/// A single line of a dump.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Line {
+    version: u32,
+    #[serde(flatten)]
+    record: Record,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+enum Record {
+    Header {
+        created_at: DateTime<Utc>,
+    },
+    /// Every author, including those no document refers to anymore.
+    Author(model::Author),
+    /// Every image, including those no document refers to anymore.
+    Image(model::Image),
+    /// The tags of the documents. They are held by the documents, and restored with
+    /// them.
+    Tag(String),
+    Document(model::Doc),
+}
+
+/// The records dumped or restored, by type.
+#[derive(Debug, Default, PartialEq)]
+struct Counts {
+    authors: usize,
+    images: usize,
+    tags: usize,
+    documents: usize,
+    /// Documents of the dump which were not restored, as they conflict with the
+    /// database.
+    conflicts: usize,
+}
+
+/// How the dump is combined with the documents in the database.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// Documents missing from the dump are kept, and when a document is in both, the one
+    /// updated last is kept.
+    Merge,
+    /// The journal is emptied first, so that it holds exactly the documents of the dump.
+    Replace,
+}
+
+impl FromStr for Mode {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(Mode::Merge),
+            "replace" => Ok(Mode::Replace),
+            _ => Err(error::Error::MiscError {
+                msg: format!("Unknown restore mode '{}' (expected merge or replace)", s),
+            }),
+        }
+    }
+}
+
+#[allow(clippy::needless_lifetimes)]
+pub async fn dump<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
+    let settings = Settings::new(matches)?;
+    let state = State::new(&settings, &logger).await?;
+
+    let mut writer: Box<dyn AsyncWrite + Unpin + Send> = match matches.value_of("output") {
+        None | Some("-") => Box::new(tokio::io::stdout()),
+        Some(path) => Box::new(tokio::fs::File::create(path).await.context(
+            error::TokioIOError {
+                msg: format!("Could not create dump file {}", path),
+            },
+        )?),
+    };
+
+    let counts = dump_journal(&*state.store, &mut writer).await?;
+
+    writer.flush().await.context(error::TokioIOError {
+        msg: String::from("Could not flush dump"),
+    })?;
+
+    info!(
+        logger,
+        "Dumped {} authors, {} images, {} tags and {} documents",
+        counts.authors,
+        counts.images,
+        counts.tags,
+        counts.documents
+    );
+
+    Ok(())
+}
+
+/// Write the whole journal, from a single snapshot, so that the dump is consistent even
+/// if the journal is modified meanwhile. Records are written as they are read from the
+/// store.
+async fn dump_journal<W>(store: &dyn Store, writer: &mut W) -> Result<Counts, error::Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut tx = store
+        .begin(TxOptions::read_only())
+        .await
+        .context(error::DBProvideError {
+            msg: "Could not begin transaction",
+        })?;
+    let counts = dump_records(tx.journal(), writer).await;
+    // Nothing was written.
+    tx.rollback().await.context(error::DBProvideError {
+        msg: "Could not end transaction",
+    })?;
+    counts
+}
+
+async fn dump_records<W>(
+    conn: &mut (dyn ProvideJournal + Send),
+    writer: &mut W,
+) -> Result<Counts, error::Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    write_record(
+        writer,
+        Record::Header {
+            created_at: Utc::now(),
+        },
+    )
+    .await?;
+
+    Ok(Counts {
+        authors: write_records(writer, conn.export_authors(), "authors", |author| {
+            Record::Author(model::Author::from(author))
+        })
+        .await?,
+        images: write_records(writer, conn.export_images(), "images", |image| {
+            Record::Image(model::Image::from(image))
+        })
+        .await?,
+        tags: write_records(writer, conn.export_tags(), "tags", Record::Tag).await?,
+        documents: write_records(writer, conn.export_documents(), "documents", |doc| {
+            Record::Document(model::Doc::from(doc))
+        })
+        .await?,
+        conflicts: 0,
+    })
+}
+
+/// Write a record for each entity of the stream, returning how many were written.
+async fn write_records<W, T, F>(
+    writer: &mut W,
+    mut entities: BoxStream<'_, db::ProvideResult<T>>,
+    name: &str,
+    record: F,
+) -> Result<usize, error::Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+    F: Fn(T) -> Record,
+{
+    let mut count = 0;
+    while let Some(entity) = entities.try_next().await.context(error::DBProvideError {
+        msg: format!("Could not export {}", name),
+    })? {
+        write_record(writer, record(entity)).await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[allow(clippy::needless_lifetimes)]
+pub async fn restore<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
+    let mode = Mode::from_str(matches.value_of("mode").unwrap_or("merge"))?;
+
+    let reader: Box<dyn AsyncRead + Unpin + Send> =
+        match matches.value_of("input") {
+            None | Some("-") => Box::new(tokio::io::stdin()),
+            Some(path) => Box::new(tokio::fs::File::open(path).await.context(
+                error::TokioIOError {
+                    msg: format!("Could not open dump file {}", path),
+                },
+            )?),
+        };
+
+    let settings = Settings::new(matches)?;
+    let state = State::new(&settings, &logger).await?;
+
+    let counts = restore_journal(&*state.store, reader, mode, &logger).await?;
+
+    info!(
+        logger,
+        "Restored {} authors, {} images and {} documents with {} tags ({} conflicts, mode {:?})",
+        counts.authors,
+        counts.images,
+        counts.documents,
+        counts.tags,
+        counts.conflicts,
+        mode
+    );
+
+    Ok(())
+}
+
+/// Restore a dump in a single transaction, reading it line by line. The transaction is
+/// not retried, as the dump can only be read once.
+async fn restore_journal<R>(
+    store: &dyn Store,
+    reader: R,
+    mode: Mode,
+    logger: &Logger,
+) -> Result<Counts, error::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut tx = store
+        .begin(TxOptions::read_write())
+        .await
+        .context(error::DBProvideError {
+            msg: "Could not begin transaction",
+        })?;
+
+    match restore_records(&mut *tx, reader, mode, logger).await {
+        Ok(counts) => {
+            tx.commit().await.context(error::DBProvideError {
+                msg: "Could not commit restore",
+            })?;
+            Ok(counts)
+        }
+        Err(err) => {
+            if let Err(rollback_err) = tx.rollback().await {
+                warn!(logger, "Could not roll back restore: {}", rollback_err);
+            }
+            Err(err)
+        }
+    }
+}
+
+async fn restore_records<R>(
+    tx: &mut dyn JournalTransaction,
+    reader: R,
+    mode: Mode,
+    logger: &Logger,
+) -> Result<Counts, error::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    if mode == Mode::Replace {
+        let deleted = tx
+            .journal()
+            .delete_all_documents()
+            .await
+            .context(error::DBProvideError {
+                msg: "Could not delete documents",
+            })?;
+        info!(logger, "Deleted {} documents before restoring", deleted);
+    }
+
+    let mut counts = Counts::default();
+    let mut lines = BufReader::new(reader).lines();
+    let mut lineno = 0;
+    while let Some(text) = lines.next_line().await.context(error::TokioIOError {
+        msg: String::from("Could not read dump"),
+    })? {
+        lineno += 1;
+        if text.trim().is_empty() {
+            continue;
+        }
+        let line: Line = serde_json::from_str(&text).context(error::JSONError {
+            msg: format!("Could not parse dump record at line {}", lineno),
+        })?;
+        if line.version != DUMP_VERSION {
+            return Err(error::Error::MiscError {
+                msg: format!(
+                    "Unsupported dump version {} at line {} (expected {})",
+                    line.version, lineno, DUMP_VERSION
+                ),
+            });
+        }
+
+        match line.record {
+            Record::Header { .. } => {}
+            Record::Author(author) => {
+                tx.journal()
+                    .restore_author(&db::AuthorEntity::from(author))
+                    .await
+                    .context(error::DBProvideError {
+                        msg: format!("Could not restore author at line {}", lineno),
+                    })?;
+                counts.authors += 1;
+            }
+            Record::Image(image) => {
+                tx.journal()
+                    .restore_image(&db::ImageEntity::from(image))
+                    .await
+                    .context(error::DBProvideError {
+                        msg: format!("Could not restore image at line {}", lineno),
+                    })?;
+                counts.images += 1;
+            }
+            Record::Tag(_) => counts.tags += 1,
+            Record::Document(doc) => {
+                let doc = db::DocEntity::from(doc);
+                restore_document(tx, &doc, mode, &mut counts, logger).await?;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// Restore a document, unless it is older than the one in the database when merging. A
+/// document whose title or slug belongs to another document is reported and skipped,
+/// and the savepoint keeps the transaction usable for the rest of the dump.
+async fn restore_document(
+    tx: &mut dyn JournalTransaction,
+    doc: &db::DocEntity,
+    mode: Mode,
+    counts: &mut Counts,
+    logger: &Logger,
+) -> Result<(), error::Error> {
+    if mode == Mode::Merge {
+        let existing =
+            tx.journal()
+                .get_document_by_id(doc.id)
+                .await
+                .context(error::DBProvideError {
+                    msg: format!("Could not get document {}", doc.id),
+                })?;
+
+        if let Some(existing) = existing {
+            let keep_existing = existing.updated_at >= doc.updated_at;
+            warn!(
+                logger,
+                "Conflict on document {} ({}): keeping {} version",
+                doc.id,
+                doc.title,
+                if keep_existing { "database" } else { "dump" }
+            );
+            if keep_existing {
+                counts.conflicts += 1;
+                return Ok(());
+            }
+        }
+    }
+
+    tx.savepoint("restore_document")
+        .await
+        .context(error::DBProvideError {
+            msg: "could not create savepoint",
+        })?;
+
+    match tx.journal().restore_document(doc).await {
+        Ok(_) => {
+            tx.release_savepoint("restore_document")
+                .await
+                .context(error::DBProvideError {
+                    msg: "could not release savepoint",
+                })?;
+            counts.documents += 1;
+        }
+        Err(db::ProvideError::UniqueViolation { details }) => {
+            tx.rollback_to_savepoint("restore_document")
+                .await
+                .context(error::DBProvideError {
+                    msg: "could not rollback to savepoint",
+                })?;
+            counts.conflicts += 1;
+            warn!(
+                logger,
+                "Conflict on document {} ({}): {}, skipping it", doc.id, doc.title, details
+            );
+        }
+        Err(err) => {
+            return Err(err).context(error::DBProvideError {
+                msg: format!("Could not restore document {}", doc.id),
+            })
+        }
+    }
+    Ok(())
+}
+
+async fn write_record<W>(writer: &mut W, record: Record) -> Result<(), error::Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let line = Line {
+        version: DUMP_VERSION,
+        record,
+    };
+    let mut text = serde_json::to_string(&line).context(error::JSONError {
+        msg: String::from("Could not serialize dump record"),
+    })?;
+    text.push('\n');
+    writer
+        .write_all(text.as_bytes())
+        .await
+        .context(error::TokioIOError {
+            msg: String::from("Could not write dump record"),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use journal::db::memory::MemoryStore;
+    use uuid::Uuid;
+
+    fn author(fullname: &str) -> db::AuthorEntity {
+        db::AuthorEntity {
+            id: None,
+            fullname: String::from(fullname),
+            resource: format!("https://example.com/authors/{}", fullname),
+        }
+    }
+
+    fn doc(title: &str, tags: &[&str], day: u32) -> db::DocEntity {
+        db::DocEntity {
+            id: Uuid::new_v4(),
+            title: String::from(title),
+            outline: String::from("Outline"),
+            author: author("jane"),
+            tags: tags.iter().map(|tag| String::from(*tag)).collect(),
+            image: db::ImageEntity {
+                id: None,
+                title: String::from("Cover"),
+                author: author("john"),
+                resource: String::from("https://example.com/images/cover.jpg"),
+            },
+            kind: db::DocKind::Doc,
+            genre: db::DocGenre::Tutorial,
+            content: String::from("Content"),
+            created_at: Utc.ymd(2020, 12, day).and_hms(8, 0, 0),
+            updated_at: Utc.ymd(2020, 12, day).and_hms(9, 0, 0),
+            version: 2,
+        }
+    }
+
+    async fn store_with(docs: &[db::DocEntity], authors: &[db::AuthorEntity]) -> MemoryStore {
+        let store = MemoryStore::new();
+        let mut tx = store.begin(TxOptions::read_write()).await.unwrap();
+        for doc in docs {
+            tx.journal().restore_document(doc).await.unwrap();
+        }
+        for author in authors {
+            tx.journal().restore_author(author).await.unwrap();
+        }
+        tx.commit().await.unwrap();
+        store
+    }
+
+    async fn dump_lines(store: &MemoryStore) -> Vec<String> {
+        let mut dump = Vec::new();
+        dump_journal(store, &mut dump).await.unwrap();
+        String::from_utf8(dump)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn logger() -> Logger {
+        Logger::root(slog::Discard, slog::o!())
+    }
+
+    #[tokio::test]
+    async fn dump_and_restore_round_trip() {
+        let store = store_with(
+            &[
+                doc("Hello World", &["rust"], 1),
+                doc("Ownership", &["rust", "memory"], 2),
+            ],
+            &[author("unreferenced")],
+        )
+        .await;
+        let mut dump = Vec::new();
+        let counts = dump_journal(&store, &mut dump).await.unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                authors: 3,
+                images: 1,
+                tags: 2,
+                documents: 2,
+                conflicts: 0,
+            }
+        );
+
+        let restored = MemoryStore::new();
+        let counts = restore_journal(&restored, dump.as_slice(), Mode::Replace, &logger())
+            .await
+            .unwrap();
+        assert_eq!(counts.documents, 2);
+        assert_eq!(counts.conflicts, 0);
+
+        // Everything but the header, which is dated, is the same.
+        assert_eq!(
+            dump_lines(&restored).await[1..],
+            dump_lines(&store).await[1..]
+        );
+    }
+
+    #[tokio::test]
+    async fn conflicting_documents_are_skipped() {
+        let store = store_with(&[doc("Hello World", &[], 1), doc("Ownership", &[], 2)], &[]).await;
+        let mut dump = Vec::new();
+        dump_journal(&store, &mut dump).await.unwrap();
+
+        // Another document already holds the title of the first one.
+        let restored = store_with(&[doc("Hello world!", &[], 3)], &[]).await;
+        let counts = restore_journal(&restored, dump.as_slice(), Mode::Merge, &logger())
+            .await
+            .unwrap();
+        assert_eq!(counts.documents, 1);
+        assert_eq!(counts.conflicts, 1);
+
+        let lines = dump_lines(&restored).await;
+        let titles = lines
+            .iter()
+            .filter_map(
+                |line| match serde_json::from_str::<Line>(line).unwrap().record {
+                    Record::Document(doc) => Some(doc.front.title),
+                    _ => None,
+                },
+            )
+            .collect::<Vec<_>>();
+        assert_eq!(titles, vec!["Ownership", "Hello world!"]);
+    }
+}
@@ -1,6 +1,7 @@
-use juniper::{graphql_value, FieldError, IntoFieldError};
+use juniper::{graphql_value, FieldError, IntoFieldError, Object, Value};
 use snafu::Snafu;
 
+use crate::api::validation::Violation;
 use crate::db::model::ProvideError;
 
 #[derive(Debug, Snafu)]
@@ -45,6 +46,13 @@ pub enum Error {
         source: serde_json::Error,
     },
 
+    #[snafu(display("YAML Error: {} - {}", msg, source))]
+    #[snafu(visibility(pub))]
+    YAMLError {
+        msg: String,
+        source: serde_yaml::Error,
+    },
+
     #[snafu(display("DB Error: {} - {}", msg, source))]
     #[snafu(visibility(pub))]
     DBError { msg: String, source: sqlx::Error },
@@ -53,81 +61,137 @@ pub enum Error {
     #[snafu(visibility(pub))]
     DBProvideError { msg: String, source: ProvideError },
 
+    #[snafu(display("Migration Error: {}", msg))]
+    #[snafu(visibility(pub))]
+    MigrationError { msg: String },
+
+    #[snafu(display("Unauthenticated: {}", msg))]
+    #[snafu(visibility(pub))]
+    Unauthenticated { msg: String },
+
+    #[snafu(display("Validation Error: {} violation(s)", violations.len()))]
+    #[snafu(visibility(pub))]
+    ValidationError { violations: Vec<Violation> },
+
     #[snafu(display("Reqwest Error: {} - {}", msg, source))]
     #[snafu(visibility(pub))]
     ReqwestError { msg: String, source: reqwest::Error },
 }
 
-impl IntoFieldError for Error {
-    fn into_field_error(self) -> FieldError {
+/// Codes exposed in the `code` extension of GraphQL errors. Clients rely on them, so
+/// they must remain stable.
+pub const NOT_FOUND: &str = "NOT_FOUND";
+pub const CONFLICT: &str = "CONFLICT";
+pub const VALIDATION: &str = "VALIDATION";
+pub const UNAUTHENTICATED: &str = "UNAUTHENTICATED";
+pub const TIMEOUT: &str = "TIMEOUT";
+pub const INTERNAL: &str = "INTERNAL";
+/// Reported with a 429, before the request is executed.
+pub const RATE_LIMITED: &str = "RATE_LIMITED";
+/// Reported with a 400 for a batch with more operations than a full rate limit budget.
+pub const BATCH_TOO_LARGE: &str = "BATCH_TOO_LARGE";
+
+impl Error {
+    /// The code reported to clients for this error.
+    pub fn code(&self) -> &'static str {
         match self {
-            err @ Error::Environment { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new(
-                    "Environment Error",
-                    graphql_value!({ "internal_error": errmsg }),
-                )
-            }
-
-            err @ Error::ConfigError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new("Config Error", graphql_value!({ "internal_error": errmsg }))
-            }
+            Error::DBProvideError { source, .. } => match source {
+                ProvideError::NotFound => NOT_FOUND,
+                ProvideError::UniqueViolation { .. } => CONFLICT,
+                ProvideError::VersionConflict { .. } => CONFLICT,
+                ProvideError::TransactionConflict { .. } => CONFLICT,
+                ProvideError::ModelViolation { .. } => VALIDATION,
+                ProvideError::QueryTimeout { .. } => TIMEOUT,
+                ProvideError::UnHandledError { .. } => INTERNAL,
+            },
+            Error::ValidationError { .. } => VALIDATION,
+            Error::Unauthenticated { .. } => UNAUTHENTICATED,
+            _ => INTERNAL,
+        }
+    }
 
-            err @ Error::EnvVarError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new(
-                    "Environment Variable Error",
-                    graphql_value!({ "internal_error": errmsg }),
-                )
-            }
+    /// Whether the transaction which failed with this error can be run again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::DBProvideError { source, .. } => source.is_retryable(),
+            _ => false,
+        }
+    }
 
-            err @ Error::MiscError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new(
-                    "Miscellaneous Error",
-                    graphql_value!({ "internal_error": errmsg }),
-                )
+    /// A message which can be shown to clients, as it holds no internal details.
+    pub fn public_message(&self) -> String {
+        match self {
+            Error::DBProvideError { source, .. } => match source {
+                ProvideError::NotFound => String::from("Not found"),
+                ProvideError::UniqueViolation { .. } => {
+                    String::from("Conflicts with an existing resource")
+                }
+                ProvideError::VersionConflict { current } => {
+                    format!("Version conflict: the current version is {}", current)
+                }
+                ProvideError::TransactionConflict { .. } => {
+                    String::from("Conflicts with a concurrent modification, please retry")
+                }
+                ProvideError::ModelViolation { .. } => String::from("Violates the data model"),
+                ProvideError::QueryTimeout { .. } => {
+                    String::from("The request took too long, please retry later")
+                }
+                ProvideError::UnHandledError { .. } => String::from("Internal error"),
+            },
+            Error::ValidationError { violations } => {
+                format!("Validation failed with {} violation(s)", violations.len())
             }
+            Error::Unauthenticated { .. } => String::from("Authentication required"),
+            _ => String::from("Internal error"),
+        }
+    }
 
-            err @ Error::TokioIOError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new(
-                    "Tokio IO Error",
-                    graphql_value!({ "internal_error": errmsg }),
-                )
-            }
+    /// Convert into a GraphQL error, whose extensions carry the code and the request id.
+    /// The details of the error, which may reveal internals, are only included when
+    /// debug is set.
+    pub fn into_field_error_with(self, request_id: &str, debug: bool) -> FieldError {
+        let message = self.public_message();
 
-            err @ Error::IOError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new("IO Error", graphql_value!({ "internal_error": errmsg }))
-            }
+        let mut extensions = Object::with_capacity(5);
+        extensions.add_field("code", Value::scalar(self.code()));
+        if !request_id.is_empty() {
+            extensions.add_field("requestId", Value::scalar(request_id));
+        }
+        if debug {
+            extensions.add_field("details", Value::scalar(self.to_string()));
+        }
 
-            err @ Error::JSONError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new("JSON Error", graphql_value!({ "internal_error": errmsg }))
+        match self {
+            Error::DBProvideError {
+                source: ProvideError::VersionConflict { current },
+                ..
+            } => {
+                extensions.add_field("currentVersion", Value::scalar(current));
             }
-
-            err @ Error::DBError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new("DB Error", graphql_value!({ "internal_error": errmsg }))
+            Error::ValidationError { violations } => {
+                let violations = violations
+                    .into_iter()
+                    .map(|violation| {
+                        let Violation {
+                            field,
+                            code,
+                            message,
+                        } = violation;
+                        graphql_value!({ "field": field, "code": code, "message": message })
+                    })
+                    .collect::<Vec<_>>();
+                extensions.add_field("violations", Value::list(violations));
             }
+            _ => {}
+        }
 
-            err @ Error::DBProvideError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new(
-                    "Provide Error",
-                    graphql_value!({ "internal_error": errmsg }),
-                )
-            }
+        FieldError::new(message, Value::object(extensions))
+    }
+}
 
-            err @ Error::ReqwestError { .. } => {
-                let errmsg = format!("{}", err);
-                FieldError::new(
-                    "Reqwest Error",
-                    graphql_value!({ "internal_error": errmsg }),
-                )
-            }
-        }
+impl IntoFieldError for Error {
+    /// Outside of a request, there is no request id, and we never expose details.
+    fn into_field_error(self) -> FieldError {
+        self.into_field_error_with("", false)
     }
 }
@@ -0,0 +1,75 @@
+use clap::ArgMatches;
+use futures::stream::TryStreamExt;
+use slog::{debug, info, Logger};
+use snafu::ResultExt;
+use std::path::Path;
+
+use journal::api::markdown;
+use journal::api::model;
+use journal::db::model::ProvideJournal;
+use journal::db::tx::TxOptions;
+use journal::error;
+use journal::settings::Settings;
+use journal::state::State;
+
+#[allow(clippy::needless_lifetimes)]
+pub async fn export_markdown<'a>(
+    matches: &ArgMatches<'a>,
+    logger: Logger,
+) -> Result<(), error::Error> {
+    let dir = matches.value_of("dir").ok_or(error::Error::MiscError {
+        msg: String::from("Missing export directory"),
+    })?;
+    let dir = Path::new(dir);
+
+    let settings = Settings::new(matches)?;
+    let state = State::new(&settings, &logger).await?;
+
+    tokio::fs::create_dir_all(dir)
+        .await
+        .context(error::TokioIOError {
+            msg: format!("Could not create export directory {}", dir.display()),
+        })?;
+
+    // Documents of every kind, from a single snapshot, written as they are read.
+    let mut tx =
+        state
+            .store
+            .begin(TxOptions::read_only())
+            .await
+            .context(error::DBProvideError {
+                msg: "Could not begin transaction",
+            })?;
+    info!(logger, "Exporting documents to {}", dir.display());
+    let exported = write_documents(tx.journal(), dir, &logger).await;
+    tx.rollback().await.context(error::DBProvideError {
+        msg: "Could not end transaction",
+    })?;
+    info!(logger, "Exported {} documents", exported?);
+
+    Ok(())
+}
+
+async fn write_documents(
+    conn: &mut (dyn ProvideJournal + Send),
+    dir: &Path,
+    logger: &Logger,
+) -> Result<usize, error::Error> {
+    let mut docs = conn.export_documents();
+    let mut count = 0;
+    while let Some(doc) = docs.try_next().await.context(error::DBProvideError {
+        msg: "Could not export documents",
+    })? {
+        let doc = model::Doc::from(doc);
+        let text = markdown::to_markdown(&doc)?;
+        let path = dir.join(format!("{}.md", doc.id));
+        debug!(logger, "Writing {}", path.display());
+        tokio::fs::write(&path, text)
+            .await
+            .context(error::TokioIOError {
+                msg: format!("Could not write {}", path.display()),
+            })?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -1,10 +1,15 @@
 use clap::ArgMatches;
 use slog::{info, Logger};
+use snafu::ResultExt;
+use sqlx::postgres::PgQueryAs;
 
-use journal::db;
+use journal::db::pg;
+use journal::db::{self, migrations, Backend};
 use journal::error;
 use journal::settings::Settings;
 
+/// Initialize an empty database. A database which is not empty is only wiped and
+/// initialized again with `--force`, and `--confirm` giving the name of the database.
 #[allow(clippy::needless_lifetimes)]
 pub async fn init<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
     info!(logger, "Initiazing application");
@@ -16,7 +21,55 @@ pub async fn init<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), er
         info!(logger, "Database URL: {}", settings.database.url);
     }
 
-    // FIXME Here I hardcode, in the form of the path to the module, that we're using
-    // a sqlite database...
-    db::pg::init_db(&settings.database.url, logger).await
+    // Other stores create their schema when they are opened.
+    if Backend::from_url(&settings.database.url)? != Backend::Postgres {
+        db::open(&settings.database, &logger).await?;
+        info!(logger, "Initialized database");
+        return Ok(());
+    }
+
+    let pool = pg::connect(&settings.database.url)
+        .await
+        .context(error::DBError {
+            msg: format!("Could not connect to {}", settings.database.url),
+        })?;
+
+    let (database,): (String,) = sqlx::query_as("SELECT current_database()")
+        .fetch_one(&pool)
+        .await
+        .context(error::DBError {
+            msg: "Could not get database name",
+        })?;
+
+    if !migrations::is_empty(&pool).await? {
+        if !matches.is_present("force") {
+            return Err(error::Error::MiscError {
+                msg: format!(
+                    "Database {} is not empty. Use `service migrate up` to upgrade it, or \
+                     `service init --force --confirm {}` to wipe it",
+                    database, database
+                ),
+            });
+        }
+        if matches.value_of("confirm") != Some(database.as_str()) {
+            return Err(error::Error::MiscError {
+                msg: format!(
+                    "Refusing to wipe database {}: --confirm must give its name",
+                    database
+                ),
+            });
+        }
+        info!(logger, "Wiping database {}", database);
+        migrations::reset(&pool, &logger).await?;
+    }
+
+    let applied = migrations::up(&pool, &logger).await?;
+    info!(
+        logger,
+        "Initialized database {} ({} migrations applied)",
+        database,
+        applied.len()
+    );
+
+    Ok(())
 }
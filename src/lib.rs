@@ -1,6 +1,10 @@
 pub mod api;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod db;
 pub mod error;
+pub mod logging;
+pub mod metrics;
 pub mod settings;
 pub mod state;
 pub mod utils;
@@ -0,0 +1,120 @@
+use slog::{o, Drain, Level, Logger, Never, OwnedKVList, Record};
+use slog_async::AsyncGuard;
+use std::fs::OpenOptions;
+use std::str::FromStr;
+
+use crate::error;
+use crate::settings::{LogFormat, Logging};
+
+type BoxedDrain = Box<dyn Drain<Ok = (), Err = Never> + Send>;
+
+/// Build the root logger described by the settings. Records are written by a separate
+/// thread: they are flushed when the guard is dropped, which must happen before exiting.
+pub fn logger(settings: &Logging) -> Result<(Logger, AsyncGuard), error::Error> {
+    let stdout = match settings.format {
+        LogFormat::Term => {
+            let decorator = slog_term::TermDecorator::new().build();
+            boxed(slog_term::FullFormat::new(decorator).build().fuse())
+        }
+        LogFormat::Json => boxed(
+            slog_json::Json::new(std::io::stdout())
+                .add_default_keys()
+                .build()
+                .fuse(),
+        ),
+    };
+
+    let drain = match &settings.file {
+        None => stdout,
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| error::Error::MiscError {
+                    msg: format!("Could not open log file {}: {}", path, err),
+                })?;
+            let file = match settings.format {
+                LogFormat::Term => {
+                    let decorator = slog_term::PlainDecorator::new(file);
+                    boxed(slog_term::FullFormat::new(decorator).build().fuse())
+                }
+                LogFormat::Json => {
+                    boxed(slog_json::Json::new(file).add_default_keys().build().fuse())
+                }
+            };
+            boxed(slog::Duplicate::new(stdout, file).fuse())
+        }
+    };
+
+    let drain = ModuleFilter::new(drain, settings)?.fuse();
+    let (drain, guard) = slog_async::Async::new(drain).build_with_guard();
+
+    Ok((Logger::root(drain.fuse(), o!()), guard))
+}
+
+fn boxed<D>(drain: D) -> BoxedDrain
+where
+    D: Drain<Ok = (), Err = Never> + Send + 'static,
+{
+    Box::new(drain)
+}
+
+fn parse_level(level: &str) -> Result<Level, error::Error> {
+    Level::from_str(level).map_err(|_| error::Error::MiscError {
+        msg: format!(
+            "Invalid log level '{}', expected one of critical, error, warning, info, debug, trace",
+            level
+        ),
+    })
+}
+
+/// Filter records by level, with a level per module, the most specific module winning.
+struct ModuleFilter<D> {
+    drain: D,
+    level: Level,
+    /// Sorted by decreasing length, so that the first match is the most specific one.
+    modules: Vec<(String, Level)>,
+}
+
+impl<D> ModuleFilter<D> {
+    fn new(drain: D, settings: &Logging) -> Result<Self, error::Error> {
+        let mut modules = settings
+            .modules
+            .iter()
+            .map(|(module, level)| Ok((module.clone(), parse_level(level)?)))
+            .collect::<Result<Vec<_>, error::Error>>()?;
+        modules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+
+        Ok(ModuleFilter {
+            drain,
+            level: parse_level(&settings.level)?,
+            modules,
+        })
+    }
+
+    fn level(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .find(|(prefix, _)| {
+                module == prefix
+                    || (module.starts_with(prefix.as_str())
+                        && module[prefix.len()..].starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+}
+
+impl<D: Drain> Drain for ModuleFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.level(record.module())) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
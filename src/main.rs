@@ -1,10 +1,20 @@
-use clap::{App, Arg, SubCommand};
-use slog::{o, warn, Drain};
+use clap::{App, AppSettings, Arg, SubCommand};
+use slog::warn;
 
+mod cors;
+mod doc;
+mod dump;
+mod export;
 mod init;
+mod migrate;
+mod ratelimit;
 mod server;
+mod test;
+mod tls;
 
 use journal::error;
+use journal::logging;
+use journal::settings::Settings;
 
 #[tokio::main]
 async fn main() -> Result<(), error::Error> {
@@ -35,25 +45,187 @@ async fn main() -> Result<(), error::Error> {
             SubCommand::with_name("init")
                 .about("Initialize Database")
                 .version("0.1")
-                .author("Matthieu Paindavoine <matt@area403.org>"),
+                .author("Matthieu Paindavoine <matt@area403.org>")
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .requires("confirm")
+                        .help("Wipe the database if it is not empty"),
+                )
+                .arg(
+                    Arg::with_name("confirm")
+                        .value_name("DATABASE")
+                        .long("confirm")
+                        .help("Name of the database to wipe, required by --force"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Manage database migrations")
+                .version("0.1")
+                .author("Matthieu Paindavoine <matt@area403.org>")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("up").about("Apply all pending migrations"))
+                .subcommand(
+                    SubCommand::with_name("down")
+                        .about("Revert the last applied migrations")
+                        .arg(steps_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("redo")
+                        .about("Revert the last applied migrations, and apply them again")
+                        .arg(steps_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("List the migrations, and whether they are applied"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export-markdown")
+                .about("Export documents as markdown files with front matter")
+                .version("0.1")
+                .author("Matthieu Paindavoine <matt@area403.org>")
+                .arg(
+                    Arg::with_name("dir")
+                        .value_name("DIR")
+                        .required(true)
+                        .help("Directory receiving one <id>.md file per document"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Dump the whole journal as JSON Lines")
+                .version("0.1")
+                .author("Matthieu Paindavoine <matt@area403.org>")
+                .arg(
+                    Arg::with_name("output")
+                        .value_name("FILE")
+                        .short("o")
+                        .long("output")
+                        .help("Output file (defaults to stdout)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore the journal from a JSON Lines dump")
+                .version("0.1")
+                .author("Matthieu Paindavoine <matt@area403.org>")
+                .arg(
+                    Arg::with_name("input")
+                        .value_name("FILE")
+                        .help("Dump file (defaults to stdin)"),
+                )
+                .arg(
+                    Arg::with_name("mode")
+                        .value_name("MODE")
+                        .short("m")
+                        .long("mode")
+                        .possible_values(&["merge", "replace"])
+                        .default_value("merge")
+                        .help("Keep the most recent of two documents (merge), or empty the journal first (replace)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doc")
+                .about("Manage the documents of a running service")
+                .version("0.1")
+                .author("Matthieu Paindavoine <matt@area403.org>")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .arg(
+                    Arg::with_name("url")
+                        .value_name("URL")
+                        .short("u")
+                        .long("url")
+                        .env("JOURNAL_URL")
+                        .default_value("http://localhost:5000/graphql")
+                        .global(true)
+                        .help("GraphQL endpoint of the service"),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the documents"))
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Print a document as markdown with front matter")
+                        .arg(id_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("search")
+                        .about("Search documents with full text search")
+                        .arg(
+                            Arg::with_name("query")
+                                .value_name("QUERY")
+                                .required(true)
+                                .help("Words to search for, or a tag with --tag"),
+                        )
+                        .arg(
+                            Arg::with_name("tag")
+                                .short("t")
+                                .long("tag")
+                                .help("Search documents by tag"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("push")
+                        .about("Create or update a document from a note")
+                        .arg(
+                            Arg::with_name("file")
+                                .value_name("FILE")
+                                .required(true)
+                                .help("Note with front matter, named <id>.md to update a document"),
+                        )
+                        .arg(
+                            Arg::with_name("resources")
+                                .value_name("URL")
+                                .short("r")
+                                .long("resources")
+                                .env("JOURNAL_RESOURCES")
+                                .required(true)
+                                .help("Base URL of the author and image resources named in notes"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("open")
+                        .about("Edit a document in $EDITOR, and push it back when modified")
+                        .arg(id_arg()),
+                ),
         )
         .subcommand(
             SubCommand::with_name("test")
-                .about("Test Something")
+                .about("Replay a scenario against the service, on the testing database")
                 .version("0.1")
-                .author("Matthieu Paindavoine <matt@area403.org>"),
+                .author("Matthieu Paindavoine <matt@area403.org>")
+                .arg(
+                    Arg::with_name("scenario")
+                        .value_name("SCENARIO")
+                        .required(true)
+                        .help("JSON Lines file of GraphQL requests and expected responses"),
+                ),
         )
         .get_matches();
 
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    let logger = slog::Logger::root(drain, o!());
+    // The subcommands load their own settings, possibly with command line overrides. Only
+    // doc, a client of a remote service, runs without any configuration, in which case we
+    // log to the terminal.
+    let logging = match Settings::new(None) {
+        Ok(settings) => settings.logging,
+        Err(_) if matches.subcommand_name() == Some("doc") => Default::default(),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    // Dropping the guard, when main returns, flushes the logs.
+    let (logger, _guard) = logging::logger(&logging)?;
 
     match matches.subcommand() {
         ("run", Some(sm)) => server::run(sm, logger).await,
         ("init", Some(sm)) => init::init(sm, logger).await,
-        // ("test", Some(sm)) => test::test(sm, logger).await,
+        ("migrate", Some(sm)) => migrate::migrate(sm, logger).await,
+        ("export-markdown", Some(sm)) => export::export_markdown(sm, logger).await,
+        ("dump", Some(sm)) => dump::dump(sm, logger).await,
+        ("restore", Some(sm)) => dump::restore(sm, logger).await,
+        ("test", Some(sm)) => test::test(sm, logger).await,
+        ("doc", Some(sm)) => doc::doc(sm, logger).await,
         _ => {
             warn!(logger, "Unrecognized subcommand");
             Err(error::Error::MiscError {
@@ -62,3 +234,19 @@ async fn main() -> Result<(), error::Error> {
         }
     }
 }
+
+fn steps_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("steps")
+        .value_name("STEPS")
+        .short("n")
+        .long("steps")
+        .default_value("1")
+        .help("Number of migrations")
+}
+
+fn id_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("id")
+        .value_name("ID")
+        .required(true)
+        .help("Document id")
+}
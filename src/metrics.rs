@@ -0,0 +1,225 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::fmt;
+use std::time::Duration;
+
+use crate::db::model::{DocCount, ProvideError};
+use crate::db::PoolStatus;
+use crate::error;
+
+/// Buckets, in seconds, for the latencies of requests, operations and top-level fields.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The metrics of the service, exposed in the Prometheus text format.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    graphql_operation_duration: HistogramVec,
+    graphql_field_duration: HistogramVec,
+    provide_errors: IntCounterVec,
+    rate_limited: IntCounterVec,
+    pool_connections: IntGauge,
+    pool_idle: IntGauge,
+    pool_waiters: IntGauge,
+    documents: IntGaugeVec,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Metrics").finish()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("journal_http_requests_total", "HTTP requests"),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let http_request_duration = HistogramVec::new(
+            latency_opts(
+                "journal_http_request_duration_seconds",
+                "HTTP request latencies",
+            ),
+            &["method", "route"],
+        )
+        .expect("valid metric");
+        let graphql_operation_duration = HistogramVec::new(
+            latency_opts(
+                "journal_graphql_operation_duration_seconds",
+                "GraphQL operation latencies, by operation name",
+            ),
+            &["operation", "outcome"],
+        )
+        .expect("valid metric");
+        let graphql_field_duration = HistogramVec::new(
+            latency_opts(
+                "journal_graphql_field_duration_seconds",
+                "GraphQL top-level field latencies, by operation type and field",
+            ),
+            &["type", "field", "outcome"],
+        )
+        .expect("valid metric");
+        let provide_errors = IntCounterVec::new(
+            Opts::new(
+                "journal_provide_errors_total",
+                "Errors of the store reported to clients",
+            ),
+            &["variant"],
+        )
+        .expect("valid metric");
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "journal_rate_limited_total",
+                "GraphQL requests rejected by the rate limiter, by kind of operation",
+            ),
+            &["kind"],
+        )
+        .expect("valid metric");
+        let pool_connections = IntGauge::new(
+            "journal_db_pool_connections",
+            "Connections of the pool, idle or in use",
+        )
+        .expect("valid metric");
+        let pool_idle = IntGauge::new("journal_db_pool_idle", "Idle connections of the pool")
+            .expect("valid metric");
+        let pool_waiters = IntGauge::new(
+            "journal_db_pool_waiters",
+            "Tasks waiting for a connection of the pool",
+        )
+        .expect("valid metric");
+        let documents = IntGaugeVec::new(
+            Opts::new("journal_documents", "Documents, by kind and genre"),
+            &["kind", "genre"],
+        )
+        .expect("valid metric");
+
+        let metrics = Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            graphql_operation_duration,
+            graphql_field_duration,
+            provide_errors,
+            rate_limited,
+            pool_connections,
+            pool_idle,
+            pool_waiters,
+            documents,
+        };
+        metrics.register().expect("metrics registered once");
+        metrics
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry
+            .register(Box::new(self.http_requests.clone()))?;
+        self.registry
+            .register(Box::new(self.http_request_duration.clone()))?;
+        self.registry
+            .register(Box::new(self.graphql_operation_duration.clone()))?;
+        self.registry
+            .register(Box::new(self.graphql_field_duration.clone()))?;
+        self.registry
+            .register(Box::new(self.provide_errors.clone()))?;
+        self.registry
+            .register(Box::new(self.rate_limited.clone()))?;
+        self.registry
+            .register(Box::new(self.pool_connections.clone()))?;
+        self.registry.register(Box::new(self.pool_idle.clone()))?;
+        self.registry
+            .register(Box::new(self.pool_waiters.clone()))?;
+        self.registry.register(Box::new(self.documents.clone()))
+    }
+
+    pub fn observe_http(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.http_requests
+            .with_label_values(&[method, route, &status.to_string()])
+            .inc();
+        self.http_request_duration
+            .with_label_values(&[method, route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_operation(&self, operation: &str, ok: bool, elapsed: Duration) {
+        self.graphql_operation_duration
+            .with_label_values(&[operation, outcome(ok)])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_field(&self, parent: &str, field: &str, ok: bool, elapsed: Duration) {
+        self.graphql_field_duration
+            .with_label_values(&[parent, field, outcome(ok)])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn count_provide_error(&self, err: &ProvideError) {
+        self.provide_errors
+            .with_label_values(&[err.variant()])
+            .inc();
+    }
+
+    pub fn count_rate_limited(&self, kind: &str) {
+        self.rate_limited.with_label_values(&[kind]).inc();
+    }
+
+    pub fn set_pool_status(&self, status: PoolStatus) {
+        self.pool_connections.set(i64::from(status.size));
+        self.pool_idle.set(status.idle as i64);
+        self.pool_waiters.set(status.waiters as i64);
+    }
+
+    /// Replace the document totals, so that combinations without documents anymore
+    /// are not reported.
+    pub fn set_documents(&self, counts: &[DocCount]) {
+        self.documents.reset();
+        for count in counts {
+            self.documents
+                .with_label_values(&[
+                    &format!("{:?}", count.kind).to_lowercase(),
+                    &format!("{:?}", count.genre).to_lowercase(),
+                ])
+                .set(count.count);
+        }
+    }
+
+    /// Render all the metrics in the Prometheus text format.
+    pub fn render(&self) -> Result<String, error::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|err| error::Error::MiscError {
+                msg: format!("Could not encode metrics: {}", err),
+            })?;
+        String::from_utf8(buffer).map_err(|err| error::Error::MiscError {
+            msg: format!("Could not encode metrics: {}", err),
+        })
+    }
+}
+
+fn latency_opts(name: &str, help: &str) -> HistogramOpts {
+    HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec())
+}
+
+fn outcome(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "error"
+    }
+}
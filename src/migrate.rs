@@ -0,0 +1,68 @@
+use clap::ArgMatches;
+use slog::{info, Logger};
+use snafu::ResultExt;
+
+use journal::db::migrations;
+use journal::db::pg;
+use journal::db::Backend;
+use journal::error;
+use journal::settings::Settings;
+
+#[allow(clippy::needless_lifetimes)]
+pub async fn migrate<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
+    let settings = Settings::new(matches)?;
+    if Backend::from_url(&settings.database.url)? != Backend::Postgres {
+        return Err(error::Error::MiscError {
+            msg: String::from(
+                "Migrations only apply to Postgres, other stores create their schema when opened",
+            ),
+        });
+    }
+    let pool = pg::connect(&settings.database.url)
+        .await
+        .context(error::DBError {
+            msg: format!("Could not connect to {}", settings.database.url),
+        })?;
+
+    match matches.subcommand() {
+        ("up", _) => {
+            let applied = migrations::up(&pool, &logger).await?;
+            info!(logger, "Applied {} migration(s)", applied.len());
+        }
+        ("down", Some(sm)) => {
+            let steps = steps(sm)?;
+            let reverted = migrations::down(&pool, &logger, steps).await?;
+            info!(logger, "Reverted {} migration(s)", reverted.len());
+        }
+        ("redo", Some(sm)) => {
+            let steps = steps(sm)?;
+            let reverted = migrations::down(&pool, &logger, steps).await?;
+            let applied = migrations::up(&pool, &logger).await?;
+            info!(
+                logger,
+                "Reverted {} and applied {} migration(s)",
+                reverted.len(),
+                applied.len()
+            );
+        }
+        ("status", _) => {
+            for status in migrations::status(&pool).await? {
+                println!("{:<40} {}", status.name, status.state);
+            }
+        }
+        _ => {
+            return Err(error::Error::MiscError {
+                msg: String::from("Unrecognized migrate subcommand"),
+            })
+        }
+    }
+
+    Ok(())
+}
+
+fn steps(matches: &ArgMatches) -> Result<usize, error::Error> {
+    let steps = matches.value_of("steps").unwrap_or("1");
+    steps.parse().map_err(|_| error::Error::MiscError {
+        msg: format!("Invalid number of steps '{}'", steps),
+    })
+}
@@ -0,0 +1,488 @@
+use serde_json::json;
+use slog::{warn, Logger};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::http::header::{HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use journal::api::analysis::OperationKind;
+use journal::error;
+use journal::metrics::Metrics;
+use journal::settings::{Budget, RateLimit};
+
+/// Most buckets kept. Beyond, those which are full, and so no different from new ones,
+/// are dropped, then those of the clients seen least recently.
+const MAX_BUCKETS: usize = 10_000;
+
+/// The address of the peer of a connection, for servers which do not give it to warp,
+/// eg over TLS.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Who requests are charged to. Clients are identified by their address until requests
+/// are authenticated.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    Ip(IpAddr),
+    /// Requests whose origin is not known share a bucket.
+    Unknown,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Client::Ip(ip) => write!(f, "{}", ip),
+            Client::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// The client of a request, from the `X-Forwarded-For` header when the proxy setting it
+/// is trusted, or from the peer address.
+pub fn client(
+    trust_forwarded: bool,
+) -> impl Filter<Extract = (Client,), Error = warp::Rejection> + Clone {
+    let peer = warp::ext::get::<PeerAddr>()
+        .map(|peer: PeerAddr| Some(peer.0))
+        .or(warp::addr::remote())
+        .unify();
+    peer.and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(move |peer: Option<SocketAddr>, forwarded: Option<String>| {
+            let forwarded = forwarded.filter(|_| trust_forwarded).and_then(|forwarded| {
+                forwarded
+                    .split(',')
+                    .next()
+                    .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+            });
+            match forwarded.or_else(|| peer.map(|peer| peer.ip())) {
+                Some(ip) => Client::Ip(ip),
+                None => Client::Unknown,
+            }
+        })
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(budget: &Budget, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(budget.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, budget: &Budget, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.rate).min(f64::from(budget.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&self, budget: &Budget, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * budget.rate >= f64::from(budget.burst)
+    }
+
+    /// How long until the bucket holds the tokens.
+    fn wait(&self, budget: &Budget, tokens: f64) -> Duration {
+        Duration::from_secs_f64(((tokens - self.tokens) / budget.rate).max(0.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Verdict {
+    Allowed,
+    /// The budget cannot afford the request yet.
+    Limited {
+        retry_after: Duration,
+    },
+    /// The request costs more than a full budget, so that it could never be afforded.
+    TooLarge,
+}
+
+/// The outcome of charging a request to the budgets of its client.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    limit: u32,
+    remaining: u32,
+    verdict: Verdict,
+}
+
+impl Decision {
+    pub fn allowed(&self) -> bool {
+        self.verdict == Verdict::Allowed
+    }
+
+    /// Tell the client how much of its budget is left.
+    pub fn decorate(&self, mut response: Response) -> Response {
+        let headers = response.headers_mut();
+        headers.insert(
+            HeaderName::from_static("x-ratelimit-limit"),
+            HeaderValue::from(self.limit),
+        );
+        // Batches which are too large are rejected before the budget is looked at.
+        if self.verdict != Verdict::TooLarge {
+            headers.insert(
+                HeaderName::from_static("x-ratelimit-remaining"),
+                HeaderValue::from(self.remaining),
+            );
+        }
+        if let Verdict::Limited { retry_after } = self.verdict {
+            // Whole seconds, rounded up, so that retrying then succeeds.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            headers.insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        }
+        response
+    }
+
+    /// Answer 429, with a GraphQL error, or 400 for a batch which is too large to ever be
+    /// allowed, as retrying it is pointless.
+    pub fn reject(&self) -> Response {
+        let (status, message, code) = match self.verdict {
+            Verdict::TooLarge => (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Too many operations in the batch, at most {} are allowed",
+                    self.limit
+                ),
+                error::BATCH_TOO_LARGE,
+            ),
+            _ => (
+                StatusCode::TOO_MANY_REQUESTS,
+                String::from("Too many requests, please retry later"),
+                error::RATE_LIMITED,
+            ),
+        };
+        let body = json!({
+            "errors": [{
+                "message": message,
+                "extensions": { "code": code },
+            }]
+        });
+        let mut response = warp::reply::with_status(body.to_string(), status).into_response();
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        self.decorate(response)
+    }
+}
+
+/// Token buckets per client and kind of operation. Subscriptions are charged to the
+/// budget of queries.
+#[derive(Debug)]
+pub struct Limiter {
+    settings: RateLimit,
+    buckets: Mutex<HashMap<(Client, OperationKind), Bucket>>,
+    max_buckets: usize,
+    metrics: Metrics,
+    logger: Logger,
+}
+
+impl Limiter {
+    pub fn new(
+        settings: &RateLimit,
+        metrics: Metrics,
+        logger: Logger,
+    ) -> Result<Arc<Self>, error::Error> {
+        for (name, budget) in &[
+            ("queries", settings.queries),
+            ("mutations", settings.mutations),
+        ] {
+            if budget.rate.is_nan() || budget.rate <= 0.0 || budget.burst == 0 {
+                return Err(error::Error::MiscError {
+                    msg: format!(
+                        "Invalid rate limit for {}: rate and burst must be positive",
+                        name
+                    ),
+                });
+            }
+        }
+        Ok(Arc::new(Limiter {
+            settings: settings.clone(),
+            buckets: Mutex::new(HashMap::new()),
+            max_buckets: MAX_BUCKETS,
+            metrics,
+            logger,
+        }))
+    }
+
+    pub fn trust_forwarded(&self) -> bool {
+        self.settings.trust_forwarded
+    }
+
+    fn budget(&self, kind: OperationKind) -> &Budget {
+        match kind {
+            OperationKind::Mutation => &self.settings.mutations,
+            OperationKind::Query | OperationKind::Subscription => &self.settings.queries,
+        }
+    }
+
+    /// Charge the operations of a request, eg of a batch, to the client. The request is
+    /// allowed only if every budget it is charged to can afford it, and only then are
+    /// the tokens taken. A batch costing more than a full budget is rejected as too
+    /// large, without charging the client.
+    pub fn check(&self, client: &Client, operations: &[OperationKind]) -> Decision {
+        self.check_at(client, operations, Instant::now())
+    }
+
+    fn check_at(&self, client: &Client, operations: &[OperationKind], now: Instant) -> Decision {
+        let mut costs: Vec<(OperationKind, f64)> = Vec::new();
+        for operation in operations {
+            let kind = match operation {
+                OperationKind::Subscription => OperationKind::Query,
+                kind => *kind,
+            };
+            match costs.iter_mut().find(|(charged, _)| *charged == kind) {
+                Some((_, cost)) => *cost += 1.0,
+                None => costs.push((kind, 1.0)),
+            }
+        }
+
+        if let Some((kind, _)) = costs
+            .iter()
+            .find(|(kind, cost)| *cost > f64::from(self.budget(*kind).burst))
+        {
+            let burst = self.budget(*kind).burst;
+            warn!(
+                self.logger,
+                "Rejected batch of {} operations from {}", operations.len(), client;
+                "client" => client.to_string(),
+                "operations" => operations.len()
+            );
+            return Decision {
+                limit: burst,
+                remaining: 0,
+                verdict: Verdict::TooLarge,
+            };
+        }
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let missing = costs
+            .iter()
+            .filter(|(kind, _)| !buckets.contains_key(&(client.clone(), *kind)))
+            .count();
+        self.evict(&mut buckets, client, missing, now);
+
+        let mut decision: Option<Decision> = None;
+        let mut retry_after: Option<Duration> = None;
+        for (kind, cost) in &costs {
+            let budget = self.budget(*kind);
+            let bucket = buckets
+                .entry((client.clone(), *kind))
+                .or_insert_with(|| Bucket::new(budget, now));
+            bucket.refill(budget, now);
+            if bucket.tokens < *cost {
+                let wait = bucket.wait(budget, *cost);
+                retry_after = Some(retry_after.map_or(wait, |longest| longest.max(wait)));
+            }
+            // The headers report the budget with the fewest requests left.
+            let remaining = (bucket.tokens - cost).max(0.0).floor() as u32;
+            if decision.map_or(true, |decision| remaining < decision.remaining) {
+                decision = Some(Decision {
+                    limit: budget.burst,
+                    remaining,
+                    verdict: Verdict::Allowed,
+                });
+            }
+        }
+
+        let mut decision = decision.unwrap_or(Decision {
+            limit: self.settings.queries.burst,
+            remaining: self.settings.queries.burst,
+            verdict: Verdict::Allowed,
+        });
+        if let Some(retry_after) = retry_after {
+            decision.remaining = 0;
+            decision.verdict = Verdict::Limited { retry_after };
+            drop(buckets);
+            for (kind, _) in &costs {
+                self.metrics.count_rate_limited(kind.as_str());
+            }
+            warn!(
+                self.logger,
+                "Rate limited {}", client;
+                "client" => client.to_string(),
+                "operations" => operations.len()
+            );
+        } else {
+            for (kind, cost) in &costs {
+                if let Some(bucket) = buckets.get_mut(&(client.clone(), *kind)) {
+                    bucket.tokens -= cost;
+                }
+            }
+        }
+        decision
+    }
+
+    /// Make room for the `missing` buckets of the client, keeping at most `max_buckets`.
+    fn evict(
+        &self,
+        buckets: &mut HashMap<(Client, OperationKind), Bucket>,
+        client: &Client,
+        missing: usize,
+        now: Instant,
+    ) {
+        if buckets.len() + missing <= self.max_buckets {
+            return;
+        }
+        buckets.retain(|(_, kind), bucket| !bucket.is_full(self.budget(*kind), now));
+        while buckets.len() + missing > self.max_buckets {
+            let oldest = buckets
+                .iter()
+                .filter(|((owner, _), _)| owner != client)
+                .min_by_key(|(_, bucket)| bucket.updated)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => buckets.remove(&key),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const QUERY: OperationKind = OperationKind::Query;
+    const MUTATION: OperationKind = OperationKind::Mutation;
+
+    fn limiter() -> Arc<Limiter> {
+        let settings = RateLimit {
+            queries: Budget {
+                rate: 2.0,
+                burst: 4,
+            },
+            mutations: Budget {
+                rate: 1.0,
+                burst: 2,
+            },
+            trust_forwarded: false,
+        };
+        let logger = Logger::root(slog::Discard, slog::o!());
+        Limiter::new(&settings, Metrics::new(), logger).unwrap()
+    }
+
+    fn client(n: u8) -> Client {
+        Client::Ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, n)))
+    }
+
+    #[test]
+    fn burst_is_allowed_at_once() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for remaining in (0..4).rev() {
+            let decision = limiter.check_at(&client(1), &[QUERY], now);
+            assert!(decision.allowed());
+            assert_eq!(decision.remaining, remaining);
+            assert_eq!(decision.limit, 4);
+        }
+
+        let decision = limiter.check_at(&client(1), &[QUERY], now);
+        assert_eq!(
+            decision.verdict,
+            Verdict::Limited {
+                retry_after: Duration::from_millis(500)
+            }
+        );
+        let response = decision.reject();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+        assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+
+        // Other clients have their own budget.
+        assert!(limiter.check_at(&client(2), &[QUERY], now).allowed());
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let limiter = limiter();
+        let now = Instant::now();
+        assert!(limiter.check_at(&client(1), &[QUERY; 4], now).allowed());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at(&client(1), &[QUERY], later).allowed());
+        assert!(!limiter.check_at(&client(1), &[QUERY], later).allowed());
+
+        // Refills stop at the burst.
+        let much_later = now + Duration::from_secs(60);
+        let decision = limiter.check_at(&client(1), &[QUERY], much_later);
+        assert!(decision.allowed());
+        assert_eq!(decision.remaining, 3);
+    }
+
+    #[test]
+    fn batches_are_charged_to_each_budget() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        // The headers report the budget with the fewest requests left.
+        let decision = limiter.check_at(&client(1), &[QUERY, MUTATION, QUERY], now);
+        assert!(decision.allowed());
+        assert_eq!(decision.limit, 2);
+        assert_eq!(decision.remaining, 1);
+
+        // The mutations cannot be afforded, so the query is not charged either.
+        let decision = limiter.check_at(&client(1), &[QUERY, MUTATION, MUTATION], now);
+        assert!(!decision.allowed());
+        assert_eq!(
+            decision.verdict,
+            Verdict::Limited {
+                retry_after: Duration::from_secs(1)
+            }
+        );
+
+        let decision = limiter.check_at(&client(1), &[QUERY, QUERY], now);
+        assert!(decision.allowed());
+        assert_eq!(decision.remaining, 0);
+    }
+
+    #[test]
+    fn batches_larger_than_the_burst_are_rejected() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        let decision = limiter.check_at(&client(1), &[QUERY, MUTATION, MUTATION, MUTATION], now);
+        assert_eq!(decision.verdict, Verdict::TooLarge);
+        let response = decision.reject();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.headers().get(RETRY_AFTER).is_none());
+        assert_eq!(response.headers()["x-ratelimit-limit"], "2");
+
+        // Nothing was charged, and a batch of the burst is allowed.
+        let decision = limiter.check_at(&client(1), &[QUERY; 4], now);
+        assert!(decision.allowed());
+        assert_eq!(decision.remaining, 0);
+        assert!(limiter.check_at(&client(1), &[MUTATION; 2], now).allowed());
+    }
+
+    #[test]
+    fn buckets_are_capped() {
+        let mut limiter = limiter();
+        Arc::get_mut(&mut limiter).unwrap().max_buckets = 2;
+        let now = Instant::now();
+
+        for n in 1..=3 {
+            let at = now + Duration::from_millis(u64::from(n));
+            assert!(limiter.check_at(&client(n), &[QUERY], at).allowed());
+        }
+
+        // The client seen least recently lost its bucket.
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(!buckets.contains_key(&(client(1), QUERY)));
+        assert!(buckets.contains_key(&(client(3), QUERY)));
+    }
+}
@@ -1,54 +1,141 @@
 use clap::ArgMatches;
+use futures::future::Future;
+use hyper::body::Bytes;
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn, Service};
+use juniper::http::{GraphQLBatchRequest, GraphQLRequest};
 use juniper_warp::playground_filter;
-use slog::{debug, info, Logger};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use slog::{debug, info, warn, Logger};
 use snafu::ResultExt;
+use std::convert::Infallible;
 use std::net::ToSocketAddrs;
-use warp::{self, Filter};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio_rustls::server::TlsStream;
+use uuid::Uuid;
+use warp::http::{StatusCode, Uri};
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{self, Filter, Reply};
 
+use journal::api::analysis::{self, OperationKind};
 use journal::api::gql;
+use journal::db::tx::{self, TxOptions};
+use journal::db::Check;
 use journal::error;
 use journal::settings::Settings;
 use journal::state::State;
 
+use crate::cors;
+use crate::ratelimit::{self, Client, Limiter, PeerAddr};
+use crate::tls;
+
 #[allow(clippy::needless_lifetimes)]
 pub async fn run<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
     let settings = Settings::new(matches)?;
     let state = State::new(&settings, &logger).await?;
-    run_server(state).await
+
+    // The signal handler broadcasts the shutdown to the server.
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let signal_logger = state.logger.clone();
+    tokio::spawn(async move {
+        match wait_for_signal().await {
+            Ok(signal) => info!(signal_logger, "Received {}, shutting down", signal),
+            Err(err) => warn!(signal_logger, "{}, shutting down", err),
+        }
+        let _ = shutdown_tx.broadcast(true);
+    });
+
+    run_server(state, shutdown_rx).await
 }
 
-pub async fn run_server(state: State) -> Result<(), error::Error> {
+/// Serve the journal until `true` is broadcast on `shutdown_rx`, or its sender is
+/// dropped. The server then stops accepting connections, and running requests are given
+/// the grace period to complete.
+pub async fn run_server(
+    state: State,
+    shutdown_rx: watch::Receiver<bool>,
+) -> Result<(), error::Error> {
     // We keep a copy of the logger before the context takes ownership of it.
     debug!(state.logger, "Entering server");
     let state1 = state.clone();
-    let qm_state1 = warp::any().map(move || gql::Context {
-        state: state1.clone(),
-    });
+    let with_state = warp::any().map(move || state1.clone());
+    let limiter = match &state.settings.service.rate_limit {
+        Some(settings) => Some(Limiter::new(
+            settings,
+            state.metrics.clone(),
+            state.logger.clone(),
+        )?),
+        None => None,
+    };
+    let trust_forwarded = limiter
+        .as_ref()
+        .map_or(false, |limiter| limiter.trust_forwarded());
 
-    let qm_schema = gql::schema();
+    // Requests are analyzed and charged to their client before they are executed, and
+    // each operation is timed and logged by name.
+    let schema = Arc::new(gql::schema());
     let graphql = warp::post()
         .and(warp::path("graphql"))
-        .and(juniper_warp::make_graphql_filter(
-            qm_schema,
-            qm_state1.boxed(),
-        ));
+        .and(warp::any().map(move || schema.clone()))
+        .and(with_state)
+        .and(request_id())
+        .and(warp::any().map(move || limiter.clone()))
+        .and(ratelimit::client(trust_forwarded))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and_then(graphql);
 
     let playground = warp::get()
         .and(warp::path("playground"))
         .and(playground_filter("/graphql", Some("/subscriptions")));
 
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_methods(vec!["GET", "POST"])
-        .allow_headers(vec!["content-type", "authorization"])
-        .allow_any_origin()
-        .build();
+    let healthz = warp::get()
+        .and(warp::path("healthz"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&Health { status: "ok" }));
+
+    let state2 = state.clone();
+    let readyz = warp::get()
+        .and(warp::path("readyz"))
+        .and(warp::path::end())
+        .and(warp::any().map(move || state2.clone()))
+        .and_then(readyz);
+
+    let state3 = state.clone();
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(warp::any().map(move || state3.clone()))
+        .and_then(metrics);
+
+    let cors_settings = &state.settings.service.cors;
+    let cors = cors::Policy::new(cors_settings, state.settings.service.tls.is_some())?;
+    cors.log(cors_settings, &state.logger);
 
     let log = warp::log("journal::graphql");
 
-    let routes = playground.or(graphql).with(cors).with(log);
+    let http_metrics = state.metrics.clone();
+    let measure = warp::log::custom(move |info| {
+        http_metrics.observe_http(
+            info.method().as_str(),
+            route(info.path()),
+            info.status().as_u16(),
+            info.elapsed(),
+        )
+    });
+
+    let routes = playground.or(graphql).or(healthz).or(readyz).or(metrics);
+    let routes = cors::wrap(routes, cors).with(log).with(measure);
 
-    let host = state.settings.service.host;
+    let host = &state.settings.service.host;
     let port = state.settings.service.port;
     let addr = (host.as_str(), port);
     let addr = addr
@@ -61,8 +148,420 @@ pub async fn run_server(state: State) -> Result<(), error::Error> {
             msg: String::from("Cannot resolve addr"),
         })?;
 
-    info!(state.logger, "Serving journal");
-    warp::serve(routes).run(addr).await;
+    // The shutdown reaches the server, which stops accepting connections, the grace
+    // period timer, and the metrics refresh.
+    tokio::spawn(refresh_metrics(state.clone(), shutdown_rx.clone()));
+
+    let server: Pin<Box<dyn Future<Output = ()> + Send>> = match &state.settings.service.tls {
+        None => {
+            let (addr, server) = warp::serve(routes)
+                .bind_with_graceful_shutdown(addr, shutdown(shutdown_rx.clone()));
+            info!(state.logger, "Serving journal on http://{}", addr);
+            Box::pin(server)
+        }
+        Some(settings) => {
+            let certificates = tls::Certificates::load(settings)?;
+            tokio::spawn(tls::reload_on_sighup(
+                certificates.clone(),
+                state.logger.clone(),
+            ));
+
+            let listener = TcpListener::bind(addr).await.context(error::TokioIOError {
+                msg: format!("Could not bind {}", addr),
+            })?;
+            let incoming = tls::incoming(listener, certificates, state.logger.clone());
+
+            // Hyper negotiates HTTP/2 or HTTP/1.1 with ALPN.
+            // Warp does not know the peer of these connections, so it is handed to the
+            // rate limiter in the request extensions.
+            let service = warp::service(routes);
+            let make_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
+                let peer = stream.get_ref().0.peer_addr().ok().map(PeerAddr);
+                let service = service.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |mut request| {
+                        if let Some(peer) = peer {
+                            request.extensions_mut().insert(peer);
+                        }
+                        service.clone().call(request)
+                    }))
+                }
+            });
+            let server = hyper::Server::builder(accept::from_stream(incoming))
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown(shutdown_rx.clone()));
+            info!(state.logger, "Serving journal on https://{}", addr);
+
+            if let Some(port) = settings.redirect_port {
+                let (redirect_addr, redirect) = warp::serve(redirect(port))
+                    .bind_with_graceful_shutdown((addr.ip(), port), shutdown(shutdown_rx.clone()));
+                info!(
+                    state.logger,
+                    "Redirecting http://{} to HTTPS", redirect_addr
+                );
+                tokio::spawn(redirect);
+            }
+
+            let logger = state.logger.clone();
+            Box::pin(async move {
+                if let Err(err) = server.await {
+                    warn!(logger, "Server error: {}", err);
+                }
+            })
+        }
+    };
+
+    let grace_period = Duration::from_secs(state.settings.service.shutdown_grace_period);
+    tokio::select! {
+        _ = server => {
+            info!(state.logger, "All requests completed");
+        }
+        _ = async {
+            shutdown(shutdown_rx).await;
+            tokio::time::delay_for(grace_period).await;
+        } => {
+            warn!(
+                state.logger,
+                "Requests still running after {:?}, dropping them", grace_period
+            );
+        }
+    }
+
+    state.store.close().await;
+    info!(state.logger, "Journal stopped");
 
     Ok(())
 }
+
+/// Wait for SIGTERM, eg from the orchestrator during a deploy, or SIGINT.
+async fn wait_for_signal() -> Result<&'static str, error::Error> {
+    let mut terminate = signal(SignalKind::terminate()).context(error::IOError {
+        msg: String::from("Could not install SIGTERM handler"),
+    })?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        result = tokio::signal::ctrl_c() => result
+            .map(|_| "SIGINT")
+            .context(error::IOError {
+                msg: String::from("Could not wait for SIGINT"),
+            }),
+    }
+}
+
+/// Redirect plain HTTP requests to the same host and path over HTTPS, on the port served
+/// with TLS.
+fn redirect(
+    port: u16,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    warp::path::full()
+        .and(query)
+        .and(warp::header::optional::<String>("host"))
+        .map(move |path: FullPath, query: String, host: Option<String>| {
+            let host = match host.as_deref().and_then(|host| host.split(':').next()) {
+                Some(host) if !host.is_empty() => host.to_string(),
+                _ => return StatusCode::BAD_REQUEST.into_response(),
+            };
+            let authority = if port == 443 {
+                host
+            } else {
+                format!("{}:{}", host, port)
+            };
+            let location = if query.is_empty() {
+                format!("https://{}{}", authority, path.as_str())
+            } else {
+                format!("https://{}{}?{}", authority, path.as_str(), query)
+            };
+            match Uri::from_str(&location) {
+                Ok(uri) => warp::redirect(uri).into_response(),
+                Err(_) => StatusCode::BAD_REQUEST.into_response(),
+            }
+        })
+}
+
+/// Resolves once the shutdown is broadcast, or its sender is gone.
+async fn shutdown(mut rx: watch::Receiver<bool>) {
+    while let Some(false) = rx.recv().await {}
+}
+
+/// How long the readiness probe waits for the store, eg for a connection.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: Vec<Check>,
+}
+
+/// Probe the store, and answer 503 with the failed checks when it is degraded.
+async fn readyz(state: State) -> Result<impl warp::Reply, Infallible> {
+    let checks = match tokio::time::timeout(READINESS_TIMEOUT, state.store.check()).await {
+        Ok(checks) => checks,
+        Err(_) => vec![Check::new(
+            "timeout",
+            Err::<(), _>(format!("no answer within {:?}", READINESS_TIMEOUT)),
+        )],
+    };
+
+    let (status, code) = if checks.iter().all(|check| check.ok) {
+        ("ok", StatusCode::OK)
+    } else {
+        warn!(state.logger, "Service is not ready"; "checks" => format!("{:?}", checks));
+        ("degraded", StatusCode::SERVICE_UNAVAILABLE)
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&Readiness { status, checks }),
+        code,
+    ))
+}
+
+/// The documents of a request, which juniper does not expose, to analyze them before
+/// they are executed.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Documents {
+    Single(Document),
+    Batch(Vec<Document>),
+}
+
+#[derive(Debug, Deserialize)]
+struct Document {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+impl Documents {
+    fn iter(&self) -> std::slice::Iter<Document> {
+        match self {
+            Documents::Single(doc) => std::slice::from_ref(doc).iter(),
+            Documents::Batch(docs) => docs.iter(),
+        }
+    }
+}
+
+/// Read the request, from a JSON body, or from an `application/graphql` body holding
+/// the query.
+fn parse_request(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<(GraphQLBatchRequest, Documents), String> {
+    let graphql_body = content_type.map_or(false, |content_type| {
+        content_type
+            .to_lowercase()
+            .starts_with("application/graphql")
+    });
+    if graphql_body {
+        let query = String::from_utf8(body.to_vec()).map_err(|err| err.to_string())?;
+        let documents = Documents::Single(Document {
+            query: query.clone(),
+            operation_name: None,
+        });
+        let request = GraphQLBatchRequest::Single(GraphQLRequest::new(query, None, None));
+        Ok((request, documents))
+    } else {
+        let request = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+        let documents = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+        Ok((request, documents))
+    }
+}
+
+/// A response holding a single GraphQL error, for requests which are not executed.
+fn graphql_error(status: StatusCode, message: &str, code: Option<&str>) -> Response {
+    let error = match code {
+        Some(code) => json!({ "message": message, "extensions": { "code": code } }),
+        None => json!({ "message": message }),
+    };
+    let body = json!({ "errors": [error] });
+    warp::reply::with_status(
+        warp::reply::with_header(body.to_string(), "content-type", "application/json"),
+        status,
+    )
+    .into_response()
+}
+
+/// Execute a GraphQL request, once analyzed and charged to its client, recording the
+/// latency of its operation.
+async fn graphql(
+    schema: Arc<gql::Schema>,
+    state: State,
+    request_id: Uuid,
+    limiter: Option<Arc<Limiter>>,
+    client: Client,
+    content_type: Option<String>,
+    body: Bytes,
+) -> Result<Response, Infallible> {
+    let (request, documents) = match parse_request(content_type.as_deref(), &body) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            let message = format!("Invalid request: {}", err);
+            return Ok(graphql_error(StatusCode::BAD_REQUEST, &message, None));
+        }
+    };
+
+    // Rejections are logged with the request id and the operation, as executions are.
+    let operation = match &request {
+        GraphQLBatchRequest::Single(request) => request.operation_name().unwrap_or(gql::ANONYMOUS),
+        GraphQLBatchRequest::Batch(_) => "batch",
+    }
+    .to_string();
+    let context = gql::Context::for_request(state, request_id, &operation);
+
+    // Documents which cannot be analyzed are charged as queries, and rejected below.
+    let limits = &context.state.settings.graphql;
+    let analyses = documents
+        .iter()
+        .map(|doc| {
+            analysis::analyze(
+                &doc.query,
+                doc.operation_name.as_deref(),
+                &*schema,
+                &limits.weights,
+            )
+        })
+        .collect::<Vec<_>>();
+    let operations = analyses
+        .iter()
+        .map(|analysis| {
+            analysis
+                .as_ref()
+                .map_or(OperationKind::Query, |analysis| analysis.kind)
+        })
+        .collect::<Vec<_>>();
+
+    let decision = limiter.map(|limiter| limiter.check(&client, &operations));
+    let decorate = |response: Response| match decision {
+        Some(decision) => decision.decorate(response),
+        None => response,
+    };
+    if let Some(decision) = decision.filter(|decision| !decision.allowed()) {
+        return Ok(decision.reject());
+    }
+
+    let checked = analyses.iter().try_for_each(|analysis| match analysis {
+        Ok(analysis) => analysis.check(limits),
+        Err(rejection) => Err(rejection.clone()),
+    });
+    if let Err(rejection) = checked {
+        warn!(
+            context.logger,
+            "Rejected query: {}", rejection;
+            "code" => rejection.code()
+        );
+        return Ok(decorate(graphql_error(
+            StatusCode::BAD_REQUEST,
+            &rejection.to_string(),
+            Some(rejection.code()),
+        )));
+    }
+
+    let start = Instant::now();
+    let response = request.execute(&schema, &context).await;
+    let ok = response.is_ok();
+    context
+        .state
+        .metrics
+        .observe_operation(&operation, ok, start.elapsed());
+
+    let (body, status) = match serde_json::to_vec(&response) {
+        Ok(body) if ok => (body, StatusCode::OK),
+        Ok(body) => (body, StatusCode::BAD_REQUEST),
+        Err(err) => {
+            warn!(context.logger, "Could not serialize response: {}", err);
+            (Vec::new(), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+    let response = warp::reply::with_status(
+        warp::reply::with_header(body, "content-type", "application/json"),
+        status,
+    )
+    .into_response();
+    Ok(decorate(response))
+}
+
+/// The id of the request, given by the client, eg a proxy, in the X-Request-Id header,
+/// or generated.
+fn request_id() -> impl Filter<Extract = (Uuid,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("x-request-id").map(|id: Option<String>| {
+        id.and_then(|id| Uuid::parse_str(&id).ok())
+            .unwrap_or_else(Uuid::new_v4)
+    })
+}
+
+/// How often the gauges sampled from the store are refreshed. Scrapes only render them,
+/// so that they cost no transaction.
+const METRICS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Refresh the gauges sampled from the store, until the shutdown.
+async fn refresh_metrics(state: State, shutdown_rx: watch::Receiver<bool>) {
+    let stopped = shutdown(shutdown_rx);
+    tokio::pin!(stopped);
+    let mut interval = tokio::time::interval(METRICS_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut stopped => return,
+            _ = interval.tick() => sample_store(&state).await,
+        }
+    }
+}
+
+async fn sample_store(state: &State) {
+    if let Some(status) = state.store.pool_status() {
+        state.metrics.set_pool_status(status);
+    }
+
+    let counts = tx::run(
+        &*state.store,
+        TxOptions::read_only(),
+        &state.logger,
+        |mut tx| async move {
+            let counts = tx
+                .journal()
+                .count_documents()
+                .await
+                .context(error::DBProvideError {
+                    msg: "Could not count documents",
+                });
+            (tx, counts)
+        },
+    )
+    .await;
+    match counts {
+        Ok(counts) => state.metrics.set_documents(&counts),
+        Err(err) => warn!(state.logger, "Could not refresh document metrics: {}", err),
+    }
+}
+
+/// Render all the metrics.
+async fn metrics(state: State) -> Result<impl warp::Reply, Infallible> {
+    let reply = match state.metrics.render() {
+        Ok(text) => warp::reply::with_status(text, StatusCode::OK),
+        Err(err) => {
+            warn!(state.logger, "{}", err);
+            warp::reply::with_status(String::new(), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+    Ok(warp::reply::with_header(
+        reply,
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+/// The route of a request, rather than its path, to bound the number of label values.
+fn route(path: &str) -> &'static str {
+    match path {
+        "/graphql" => "/graphql",
+        "/playground" => "/playground",
+        "/healthz" => "/healthz",
+        "/readyz" => "/readyz",
+        "/metrics" => "/metrics",
+        _ => "other",
+    }
+}
@@ -2,6 +2,7 @@ use clap::ArgMatches;
 use config::{Config, Environment, File};
 use serde::Deserialize;
 use snafu::ResultExt;
+use std::collections::HashMap;
 use std::env;
 
 use super::error;
@@ -9,12 +10,217 @@ use super::error;
 #[derive(Debug, Clone, Deserialize)]
 pub struct Database {
     pub url: String,
+    /// Connections of the pool
+    #[serde(default = "default_max_size")]
+    pub max_size: u32,
+    /// Connections kept open, even when idle
+    #[serde(default)]
+    pub min_idle: u32,
+    /// Seconds to open a connection
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Seconds to wait for a connection of the pool, before giving up on the request
+    #[serde(default = "default_acquire_timeout")]
+    pub acquire_timeout: u64,
+    /// Seconds after which idle connections, beyond `min_idle`, are closed
+    pub idle_timeout: Option<u64>,
+    /// Milliseconds a query may run before it is cancelled, Postgres only
+    pub statement_timeout: Option<u64>,
+}
+
+fn default_max_size() -> u32 {
+    5
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_acquire_timeout() -> u64 {
+    10
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Service {
     pub host: String,
     pub port: u16,
+    /// Seconds given to running requests to complete on shutdown
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+    /// Serve HTTPS, when present
+    pub tls: Option<Tls>,
+    #[serde(default)]
+    pub cors: Cors,
+    /// Limit the GraphQL requests of each client, when present
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Token buckets per client, with separate budgets for queries and mutations.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimit {
+    pub queries: Budget,
+    pub mutations: Budget,
+    /// Identify clients by the first address of `X-Forwarded-For`, which only a trusted
+    /// proxy should set
+    #[serde(default)]
+    pub trust_forwarded: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Budget {
+    /// Requests per second, sustained
+    pub rate: f64,
+    /// Requests allowed at once, after a quiet period
+    pub burst: u32,
+}
+
+/// The cross-origin requests allowed from browsers, checked when the server starts.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Cors {
+    /// Origins, eg `https://journal.example.com`, subdomains of an origin, eg
+    /// `https://*.example.com`, or `*` for any origin. None by default, which only allows
+    /// same-origin requests
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "default_cors_headers")]
+    pub allowed_headers: Vec<String>,
+    /// Allow cookies and authorization headers, which requires explicit origins
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Seconds browsers may cache the answer to a preflight request
+    pub max_age: Option<u64>,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec![String::from("GET"), String::from("POST")]
+}
+
+fn default_cors_headers() -> Vec<String> {
+    vec![String::from("content-type"), String::from("authorization")]
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            allowed_origins: Vec::new(),
+            allowed_methods: default_cors_methods(),
+            allowed_headers: default_cors_headers(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+/// Certificates are reloaded on SIGHUP.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tls {
+    /// PEM file with the certificate chain
+    pub cert_path: String,
+    /// PEM file with the private key, in PKCS8 or RSA format
+    pub key_path: String,
+    /// A plain HTTP port redirecting to HTTPS
+    pub redirect_port: Option<u16>,
+}
+
+/// Whether the SERVER_TLS environment variable asks for HTTPS, with the certificates at
+/// CERT_PATH and KEY_PATH.
+fn server_tls() -> Result<bool, error::Error> {
+    match env::var("SERVER_TLS") {
+        Err(_) => Ok(false),
+        Ok(value) => match value.to_lowercase().as_str() {
+            "1" | "true" => Ok(true),
+            "" | "0" | "false" => Ok(false),
+            _ => Err(error::Error::MiscError {
+                msg: format!("SERVER_TLS must be true or false, not '{}'", value),
+            }),
+        },
+    }
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    30
+}
+
+/// Limits enforced on documents before they reach the database.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Validation {
+    pub max_title_length: usize,
+    pub max_outline_length: usize,
+    pub max_content_length: usize,
+    pub max_name_length: usize,
+    pub max_tags: usize,
+    pub max_tag_length: usize,
+}
+
+/// Limits on GraphQL documents, checked before they are executed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Graphql {
+    /// Deepest nesting of fields
+    pub max_depth: usize,
+    /// Fields selected under another name
+    pub max_aliases: usize,
+    /// Sum of the weights of the selected fields
+    pub max_cost: u64,
+    /// Weights of fields by `Type.field`, eg `"Doc.content" = 10`, the others weighing 1
+    #[serde(default)]
+    pub weights: HashMap<String, u64>,
+    /// Allow `__schema` and `__type` queries, which the playground relies on
+    pub introspection: bool,
+}
+
+impl Default for Graphql {
+    fn default() -> Self {
+        let weights = [
+            ("Doc.content", 10),
+            ("Query.listDocuments", 20),
+            ("Query.listDocumentsByTag", 20),
+            ("Query.listDocumentsByQuery", 50),
+        ]
+        .iter()
+        .map(|(field, weight)| (String::from(*field), *weight))
+        .collect();
+        Graphql {
+            max_depth: 10,
+            max_aliases: 10,
+            max_cost: 500,
+            weights,
+            introspection: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, for terminals
+    Term,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Logging {
+    pub format: LogFormat,
+    /// One of critical, error, warning, info, debug or trace
+    pub level: String,
+    /// Levels overriding the default one for modules, and their submodules, eg
+    /// `"journal::db" = "debug"`.
+    #[serde(default)]
+    pub modules: HashMap<String, String>,
+    /// A file the logs are also appended to
+    pub file: Option<String>,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            format: LogFormat::Term,
+            level: String::from("info"),
+            modules: HashMap::new(),
+            file: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -24,6 +230,11 @@ pub struct Settings {
     pub mode: String,
     pub database: Database,
     pub service: Service,
+    pub validation: Validation,
+    #[serde(default)]
+    pub graphql: Graphql,
+    #[serde(default)]
+    pub logging: Logging,
 }
 
 // TODO Parameterize the config directory
@@ -61,19 +272,40 @@ impl Settings {
                 msg: String::from("Could not merge configuration from environment variables"),
             })?;
 
-        // Now we take care of the database.url, which can be had from environment variables.
+        // Now we take care of the database.url, which can be overridden by environment
+        // variables. Its scheme selects the backend (see db::Backend).
         let key = match mode.as_str() {
             "testing" => "DATABASE_TEST_URL",
             _ => "DATABASE_URL",
         };
 
-        let db_url = env::var(key).context(error::EnvVarError {
-            msg: format!("Could not get env var {}", key),
-        })?;
+        if let Ok(db_url) = env::var(key) {
+            s.set("database.url", db_url).context(error::ConfigError {
+                msg: String::from("Could not set database url from environment variable"),
+            })?;
+        }
 
-        s.set("database.url", db_url).context(error::ConfigError {
-            msg: String::from("Could not set database url from environment variable"),
-        })?;
+        // Certificates are often provisioned outside of the configuration, eg as secrets.
+        // Their paths may be set regardless of TLS, so serving HTTPS with them must be
+        // asked for with SERVER_TLS.
+        if server_tls()? {
+            let (cert_path, key_path) = match (env::var("CERT_PATH"), env::var("KEY_PATH")) {
+                (Ok(cert_path), Ok(key_path)) => (cert_path, key_path),
+                _ => {
+                    return Err(error::Error::MiscError {
+                        msg: String::from("SERVER_TLS requires CERT_PATH and KEY_PATH"),
+                    })
+                }
+            };
+            s.set("service.tls.cert_path", cert_path)
+                .context(error::ConfigError {
+                    msg: String::from("Could not set certificate path from environment variable"),
+                })?;
+            s.set("service.tls.key_path", key_path)
+                .context(error::ConfigError {
+                    msg: String::from("Could not set key path from environment variable"),
+                })?;
+        }
 
         let m = matches.into();
         if let Some(m) = m {
@@ -1,47 +1,35 @@
+use crate::db::{self, Store};
 use crate::error;
+use crate::metrics::Metrics;
 use crate::settings::Settings;
-use slog::{info, o, Logger};
-use snafu::ResultExt;
-use sqlx::postgres::PgPool;
-use sqlx::postgres::PgQueryAs;
+use slog::{o, Logger};
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
 pub struct State {
-    pub pool: PgPool,
+    pub store: Arc<dyn Store>,
     pub logger: Logger,
     pub settings: Settings,
+    pub metrics: Metrics,
 }
 
 impl State {
     pub async fn new(settings: &Settings, logger: &Logger) -> Result<Self, error::Error> {
-        let pool = PgPool::builder()
-            .max_size(5)
-            .build(&settings.database.url)
-            .await
-            .context(error::DBError {
-                msg: String::from("foo"),
-            })?;
-
-        let row: (String,) = sqlx::query_as("SELECT version()")
-            .fetch_one(&pool)
-            .await
-            .context(error::DBError {
-                msg: format!(
-                    "Could not test database version for {}",
-                    &settings.database.url,
-                ),
-            })?;
-
-        info!(logger, "db version: {:?}", row.0);
+        let store = db::open(&settings.database, logger).await?;
+        Ok(Self::with_store(store, settings, logger))
+    }
 
+    /// Build the state around an existing store, eg an in-memory store.
+    pub fn with_store(store: Arc<dyn Store>, settings: &Settings, logger: &Logger) -> Self {
         let logger = logger.new(
             o!("host" => String::from(&settings.service.host), "port" => settings.service.port, "database" => String::from(&settings.database.url)),
         );
 
-        Ok(Self {
-            pool,
+        Self {
+            store,
             logger,
             settings: settings.clone(),
-        })
+            metrics: Metrics::new(),
+        }
     }
 }
@@ -1,39 +1,293 @@
 use clap::ArgMatches;
-use slog::{info, Logger};
-use slog::{o, Drain};
-use std::thread;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use slog::{info, warn, Logger};
+use snafu::ResultExt;
+use std::env;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::watch;
 
 use super::server::run_server;
-use ctl2mimir::error;
-use ctl2mimir::settings::Settings;
-use ctl2mimir::state::State;
+use journal::db::tx::{self, TxOptions};
+use journal::error;
+use journal::settings::Settings;
+use journal::state::State;
+use journal::utils::construct_headers;
 
+/// In an expected response, matches any value, eg a server assigned id or a timestamp.
+const ANY: &str = "$any";
+
+/// Number of attempts at reaching the server, while it starts.
+const MAX_ATTEMPTS: u32 = 50;
+
+/// A step of a scenario: a GraphQL request, and the response expected for it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Step {
+    name: Option<String>,
+    query: String,
+    #[serde(default)]
+    variables: Option<Value>,
+    operation_name: Option<String>,
+    expected: Value,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Request<'a> {
+    query: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variables: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operation_name: Option<&'a str>,
+}
+
+/// Replay a scenario against a server started in-process on the testing database, which
+/// is emptied first.
+///
+/// A scenario is a JSON Lines file, each line holding a step:
+/// `{"name": "...", "query": "...", "variables": {...}, "expected": {...}}`.
 #[allow(clippy::needless_lifetimes)]
 pub async fn test<'a>(matches: &ArgMatches<'a>, logger: Logger) -> Result<(), error::Error> {
+    let path = matches
+        .value_of("scenario")
+        .ok_or(error::Error::MiscError {
+            msg: String::from("Missing scenario"),
+        })?;
+    let steps = read_scenario(path).await?;
+
+    // Settings read the mode from the environment. The testing mode uses the in-memory
+    // store of config/testing.toml, unless DATABASE_TEST_URL gives another database.
+    env::set_var("RUN_MODE", "testing");
     let settings = Settings::new(matches)?;
+    // The service may listen on all interfaces, but we reach it on the loopback.
+    let host = match settings.service.host.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        host => host,
+    };
+    let url = format!("http://{}:{}/graphql", host, settings.service.port);
+
+    info!(logger, "Launching testing service");
+    let state = State::new(&settings, &logger).await?;
+    reset(&state, &logger).await?;
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server_logger = logger.clone();
+    let server = tokio::spawn(async move {
+        if let Err(err) = run_server(state, shutdown_rx).await {
+            warn!(server_logger, "Testing service failed: {}", err);
+        }
+    });
 
-    // FIXME There is work that should be done here to terminate the service
-    // when we are done with testing.
-    if settings.testing {
-        info!(logger, "Launching testing service");
-        let handle = tokio::runtime::Handle::current();
-        thread::spawn(move || {
-            handle.spawn(async move {
-                let decorator = slog_term::TermDecorator::new().build();
-                let drain = slog_term::FullFormat::new(decorator).build().fuse();
-                let drain = slog_async::Async::new(drain).build().fuse();
-                let logger = slog::Logger::root(drain, o!());
-                let state = State::new(&settings, &logger)
+    let client = reqwest::Client::new();
+    let result = replay(&client, &url, path, &steps).await;
+
+    // The server closes the store once stopped, whatever the outcome of the scenario.
+    info!(logger, "Stopping testing service");
+    let _ = shutdown_tx.broadcast(true);
+    if let Err(err) = server.await {
+        warn!(logger, "Testing service panicked: {}", err);
+    }
+
+    result
+}
+
+/// Empty the journal, so that the scenario starts from a known state whatever the
+/// scenarios replayed before it on the same database.
+async fn reset(state: &State, logger: &Logger) -> Result<(), error::Error> {
+    let deleted = tx::run(
+        &*state.store,
+        TxOptions::read_write(),
+        logger,
+        |mut tx| async move {
+            let deleted =
+                tx.journal()
+                    .delete_all_documents()
                     .await
-                    .expect("state creation");
-                run_server(settings, state).await
-            });
+                    .context(error::DBProvideError {
+                        msg: "Could not reset the testing database",
+                    });
+            (tx, deleted)
+        },
+    )
+    .await?;
+    info!(logger, "Deleted {} documents before the scenario", deleted);
+    Ok(())
+}
+
+/// Replay the steps of a scenario, once the server is up.
+async fn replay(
+    client: &reqwest::Client,
+    url: &str,
+    path: &str,
+    steps: &[Step],
+) -> Result<(), error::Error> {
+    wait_for_server(client, url).await?;
+
+    let mut failures = 0;
+    for (i, step) in steps.iter().enumerate() {
+        let name = step
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("step {}", i + 1));
+        let actual = send(client, url, step).await?;
+
+        let mut diffs = Vec::new();
+        diff("", &step.expected, &actual, &mut diffs);
+        if diffs.is_empty() {
+            println!("ok     {}", name);
+        } else {
+            failures += 1;
+            println!("FAILED {}", name);
+            for d in diffs {
+                println!("         {}", d);
+            }
+        }
+    }
+
+    println!(
+        "{} steps, {} passed, {} failed",
+        steps.len(),
+        steps.len() - failures,
+        failures
+    );
+
+    if failures > 0 {
+        return Err(error::Error::MiscError {
+            msg: format!(
+                "Scenario {} failed ({} of {} steps)",
+                path,
+                failures,
+                steps.len()
+            ),
         });
-        //th.join().expect("Waiting for test execution");
     }
 
-    test_environments();
     Ok(())
 }
 
-pub fn test_environments() {}
+async fn read_scenario(path: &str) -> Result<Vec<Step>, error::Error> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .context(error::TokioIOError {
+            msg: format!("Could not open scenario {}", path),
+        })?;
+
+    let mut steps = Vec::new();
+    let mut lines = BufReader::new(file).lines();
+    let mut lineno = 0;
+    while let Some(text) = lines.next_line().await.context(error::TokioIOError {
+        msg: format!("Could not read scenario {}", path),
+    })? {
+        lineno += 1;
+        if text.trim().is_empty() {
+            continue;
+        }
+        let step = serde_json::from_str(&text).context(error::JSONError {
+            msg: format!("Could not parse step at {}:{}", path, lineno),
+        })?;
+        steps.push(step);
+    }
+
+    Ok(steps)
+}
+
+/// Wait until the server answers a trivial query.
+async fn wait_for_server(client: &reqwest::Client, url: &str) -> Result<(), error::Error> {
+    let probe = Step {
+        name: None,
+        query: String::from("{ __typename }"),
+        variables: None,
+        operation_name: None,
+        expected: Value::Null,
+    };
+    let mut attempt = 1;
+    loop {
+        match send(client, url, &probe).await {
+            Ok(_) => return Ok(()),
+            Err(err) if attempt >= MAX_ATTEMPTS => return Err(err),
+            Err(_) => {
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, url: &str, step: &Step) -> Result<Value, error::Error> {
+    let request = Request {
+        query: &step.query,
+        variables: step.variables.as_ref(),
+        operation_name: step.operation_name.as_deref(),
+    };
+    let body = serde_json::to_string(&request).context(error::JSONError {
+        msg: String::from("Could not serialize request"),
+    })?;
+
+    let text = client
+        .post(url)
+        .headers(construct_headers())
+        .body(body)
+        .send()
+        .await
+        .context(error::ReqwestError {
+            msg: format!("Could not send request to {}", url),
+        })?
+        .text()
+        .await
+        .context(error::ReqwestError {
+            msg: String::from("Could not read response"),
+        })?;
+
+    serde_json::from_str(&text).context(error::JSONError {
+        msg: format!("Could not parse response: {}", text),
+    })
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Record, for each path, how the actual value differs from the expected one.
+fn diff(path: &str, expected: &Value, actual: &Value, diffs: &mut Vec<String>) {
+    let at = if path.is_empty() { "<root>" } else { path };
+    match (expected, actual) {
+        (Value::String(s), _) if s == ANY => {}
+        (Value::Object(expected), Value::Object(actual)) => {
+            for (key, value) in expected {
+                let path = child_path(path, key);
+                match actual.get(key) {
+                    Some(actual) => diff(&path, value, actual, diffs),
+                    None => diffs.push(format!("{}: missing, expected {}", path, value)),
+                }
+            }
+            for (key, value) in actual {
+                if !expected.contains_key(key) {
+                    diffs.push(format!("{}: unexpected {}", child_path(path, key), value));
+                }
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            if expected.len() != actual.len() {
+                diffs.push(format!(
+                    "{}: expected {} items, got {}",
+                    at,
+                    expected.len(),
+                    actual.len()
+                ));
+            }
+            for (i, (expected, actual)) in expected.iter().zip(actual.iter()).enumerate() {
+                diff(&format!("{}[{}]", path, i), expected, actual, diffs);
+            }
+        }
+        (expected, actual) => {
+            if expected != actual {
+                diffs.push(format!("{}: expected {}, got {}", at, expected, actual));
+            }
+        }
+    }
+}
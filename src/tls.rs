@@ -0,0 +1,165 @@
+use futures::stream::{Stream, StreamExt};
+use slog::{debug, info, warn, Logger};
+use snafu::ResultExt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use journal::error;
+use journal::settings::Tls;
+
+/// Connections waiting for the server to pick them up, once their handshake is done.
+const PENDING_CONNECTIONS: usize = 64;
+
+/// Clients which do not complete their handshake within this delay are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after failing to accept a connection, eg when out of file descriptors, so as
+/// not to spin until some are released.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The TLS configuration of the server, which is replaced when certificates are reloaded.
+/// Connections keep the configuration they were accepted with.
+#[derive(Clone)]
+pub struct Certificates {
+    settings: Tls,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl Certificates {
+    pub fn load(settings: &Tls) -> Result<Self, error::Error> {
+        let config = server_config(settings)?;
+        Ok(Certificates {
+            settings: settings.clone(),
+            config: Arc::new(RwLock::new(Arc::new(config))),
+        })
+    }
+
+    /// Read the certificates again, eg after they were renewed. On failure, the current
+    /// ones are kept.
+    pub fn reload(&self) -> Result<(), error::Error> {
+        let config = server_config(&self.settings)?;
+        let mut current = self.config.write().map_err(|_| error::Error::MiscError {
+            msg: String::from("TLS configuration lock poisoned"),
+        })?;
+        *current = Arc::new(config);
+        Ok(())
+    }
+
+    fn acceptor(&self) -> Result<TlsAcceptor, error::Error> {
+        let config = self.config.read().map_err(|_| error::Error::MiscError {
+            msg: String::from("TLS configuration lock poisoned"),
+        })?;
+        Ok(TlsAcceptor::from(config.clone()))
+    }
+}
+
+/// Offer HTTP/2, eg for SSE clients, and HTTP/1.1.
+fn server_config(settings: &Tls) -> Result<ServerConfig, error::Error> {
+    let cert_chain = read_pem(&settings.cert_path, certs)?;
+    let key = read_key(&settings.key_path)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .map_err(|err| error::Error::MiscError {
+            msg: format!(
+                "Invalid certificate {} or key {}: {}",
+                settings.cert_path, settings.key_path, err
+            ),
+        })?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(config)
+}
+
+/// The key may be in PKCS8 (`BEGIN PRIVATE KEY`) or RSA (`BEGIN RSA PRIVATE KEY`) format.
+fn read_key(path: &str) -> Result<PrivateKey, error::Error> {
+    let keys = read_pem(path, pkcs8_private_keys)?;
+    let keys = if keys.is_empty() {
+        read_pem(path, rsa_private_keys)?
+    } else {
+        keys
+    };
+    keys.into_iter().next().ok_or(error::Error::MiscError {
+        msg: format!("No private key found in {}", path),
+    })
+}
+
+fn read_pem<T, F>(path: &str, parse: F) -> Result<Vec<T>, error::Error>
+where
+    F: FnOnce(&mut dyn BufRead) -> Result<Vec<T>, ()>,
+{
+    let file = File::open(path).context(error::IOError {
+        msg: format!("Could not open {}", path),
+    })?;
+    parse(&mut BufReader::new(file)).map_err(|_| error::Error::MiscError {
+        msg: format!("Could not parse PEM file {}", path),
+    })
+}
+
+/// Accept TLS connections. Handshakes run concurrently, so that a slow or failing client
+/// does not hold up the others, and failed or timed out handshakes are only logged.
+pub fn incoming(
+    mut listener: TcpListener,
+    certificates: Certificates,
+    logger: Logger,
+) -> impl Stream<Item = Result<TlsStream<TcpStream>, io::Error>> {
+    let (tx, rx) = mpsc::channel(PENDING_CONNECTIONS);
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(logger, "Could not accept connection: {}", err);
+                    tokio::time::delay_for(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let acceptor = match certificates.acceptor() {
+                Ok(acceptor) => acceptor,
+                Err(err) => {
+                    warn!(logger, "{}", err);
+                    continue;
+                }
+            };
+            let mut tx = tx.clone();
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        // The server is gone, eg shutting down.
+                        let _ = tx.send(stream).await;
+                    }
+                    Ok(Err(err)) => debug!(logger, "TLS handshake with {} failed: {}", peer, err),
+                    Err(_) => debug!(logger, "TLS handshake with {} timed out", peer),
+                }
+            });
+        }
+    });
+    rx.map(Ok)
+}
+
+/// Reload the certificates on SIGHUP, for as long as the server runs.
+pub async fn reload_on_sighup(certificates: Certificates, logger: Logger) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!(logger, "Could not install SIGHUP handler: {}", err);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match certificates.reload() {
+            Ok(()) => info!(logger, "Reloaded TLS certificates"),
+            Err(err) => warn!(logger, "Keeping current TLS certificates: {}", err),
+        }
+    }
+}
@@ -22,11 +22,3 @@ pub fn get_service_url() -> String {
         _ => format!("http://journal:{}/graphql", port),
     }
 }
-
-pub fn get_database_url() -> String {
-    let mode = env::var("RUN_MODE").expect("RUN_MODE should be set");
-    match mode.as_str() {
-        "testing" => env::var("SQLITE_TEST_FILE").expect("SQLITE_TEST_FILE should be set"),
-        _ => env::var("SQLITE_FILE").expect("SQLITE_FILE should be set"),
-    }
-}
@@ -0,0 +1,381 @@
+//! The GraphQL schema, exercised against the in-memory store.
+
+use juniper::http::GraphQLRequest;
+use juniper::InputValue;
+use serde_json::{json, Value};
+use slog::{o, Discard, Logger};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use journal::api::gql;
+use journal::db::memory::MemoryStore;
+use journal::settings::Settings;
+use journal::state::State;
+
+const DOC_FIELDS: &str =
+    "fragment DocFields on Doc { id front { title tags kind } content version }";
+
+struct Journal {
+    schema: gql::Schema,
+    context: gql::Context,
+}
+
+impl Journal {
+    fn new() -> Self {
+        let settings: Settings = serde_json::from_value(json!({
+            "debug": false,
+            "testing": true,
+            "mode": "testing",
+            "database": { "url": "memory://" },
+            "service": { "host": "127.0.0.1", "port": 0 },
+            "validation": {
+                "max_title_length": 200,
+                "max_outline_length": 2000,
+                "max_content_length": 1000000,
+                "max_name_length": 200,
+                "max_tags": 20,
+                "max_tag_length": 50
+            }
+        }))
+        .unwrap();
+        let logger = Logger::root(Discard, o!());
+        let state = State::with_store(Arc::new(MemoryStore::new()), &settings, &logger);
+        Journal {
+            schema: gql::schema(),
+            context: gql::Context::new(state),
+        }
+    }
+
+    /// Run an operation, and return the whole response: data and errors.
+    async fn execute(&self, query: &str, variables: Value) -> Value {
+        let variables: InputValue = serde_json::from_value(variables).unwrap();
+        let request =
+            GraphQLRequest::new(format!("{}\n{}", query, DOC_FIELDS), None, Some(variables));
+        let response = request.execute(&self.schema, &self.context).await;
+        serde_json::to_value(&response).unwrap()
+    }
+
+    async fn upsert(&self, doc: Value) -> Value {
+        self.execute(
+            "mutation($doc: DocSpec!) {
+               createOrUpdateDocument(doc: { doc: $doc }) { doc { ...DocFields } }
+             }",
+            json!({ "doc": doc }),
+        )
+        .await
+    }
+
+    async fn create(&self, doc: Value) -> Value {
+        self.execute(
+            "mutation($doc: NewDocSpec!) { createDocument(doc: $doc) { doc { ...DocFields } } }",
+            json!({ "doc": doc }),
+        )
+        .await
+    }
+
+    async fn batch(&self, docs: Vec<Value>, atomic: bool) -> Value {
+        self.execute(
+            "mutation($docs: [DocSpec!]!, $atomic: Boolean) {
+               createOrUpdateDocuments(docs: $docs, atomic: $atomic) {
+                 results { id doc { ...DocFields } error { code message } }
+                 committed
+               }
+             }",
+            json!({ "docs": docs, "atomic": atomic }),
+        )
+        .await
+    }
+
+    async fn titles(&self, field: &str, argument: &str) -> Vec<String> {
+        let query = format!(
+            "query {{ {}{} {{ docs {{ front {{ title }} }} docsCount }} }}",
+            field, argument
+        );
+        let response = self.execute(&query, json!({})).await;
+        assert_eq!(response["errors"], Value::Null, "{}", response);
+        let mut titles = response["data"][field]["docs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|doc| doc["front"]["title"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            response["data"][field]["docsCount"],
+            json!(titles.len()),
+            "{}",
+            response
+        );
+        titles.sort();
+        titles
+    }
+}
+
+/// A valid new document, as given to createDocument.
+fn new_doc(title: &str, tags: &[&str], content: &str) -> Value {
+    json!({
+        "title": title,
+        "outline": "An outline",
+        "authorFullname": "Jane Doe",
+        "authorResource": "https://example.com/authors/jane-doe",
+        "tags": tags,
+        "imageTitle": "Cover",
+        "imageResource": "https://example.com/images/cover.jpg",
+        "imageAuthorFullname": "John Doe",
+        "imageAuthorResource": "https://example.com/authors/john-doe",
+        "kind": "DOC",
+        "genre": "TUTORIAL",
+        "content": content
+    })
+}
+
+/// A valid document, as given to createOrUpdateDocument(s).
+fn doc(id: Uuid, title: &str, tags: &[&str], content: &str) -> Value {
+    let mut doc = new_doc(title, tags, content);
+    doc["id"] = json!(id);
+    doc
+}
+
+/// The code of the first error of the response.
+fn error_code(response: &Value) -> &Value {
+    &response["errors"][0]["extensions"]["code"]
+}
+
+#[tokio::test]
+async fn lists_and_searches_documents() {
+    let journal = Journal::new();
+    journal
+        .upsert(doc(
+            Uuid::new_v4(),
+            "Ownership",
+            &["rust"],
+            "The borrow checker",
+        ))
+        .await;
+    journal
+        .upsert(doc(Uuid::new_v4(), "Goroutines", &["go"], "Channels"))
+        .await;
+    let mut post = doc(Uuid::new_v4(), "Release notes", &["rust"], "Version 2");
+    post["kind"] = json!("POST");
+    journal.upsert(post).await;
+
+    // Posts are not listed with the documents.
+    assert_eq!(
+        journal.titles("listDocuments", "").await,
+        vec!["Goroutines", "Ownership"]
+    );
+    assert_eq!(
+        journal
+            .titles("listDocumentsByTag", r#"(tag: "rust")"#)
+            .await,
+        vec!["Ownership", "Release notes"]
+    );
+    assert_eq!(
+        journal
+            .titles("listDocumentsByQuery", r#"(query: "borrow checker")"#)
+            .await,
+        vec!["Ownership"]
+    );
+    assert!(journal
+        .titles("listDocumentsByQuery", r#"(query: "python")"#)
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+async fn upserts_documents_and_detects_version_conflicts() {
+    let journal = Journal::new();
+    let id = Uuid::new_v4();
+
+    let mut spec = doc(id, "Ownership", &[], "First draft");
+    spec["expectedVersion"] = json!(0);
+    let response = journal.upsert(spec.clone()).await;
+    let created = &response["data"]["createOrUpdateDocument"]["doc"];
+    assert_eq!(created["id"], json!(id), "{}", response);
+    assert_eq!(created["version"], json!(1));
+
+    // The document exists already.
+    let response = journal.upsert(spec.clone()).await;
+    assert_eq!(error_code(&response), "CONFLICT", "{}", response);
+    assert_eq!(response["errors"][0]["extensions"]["currentVersion"], 1);
+
+    spec["content"] = json!("Second draft");
+    spec["expectedVersion"] = json!(1);
+    let response = journal.upsert(spec.clone()).await;
+    let updated = &response["data"]["createOrUpdateDocument"]["doc"];
+    assert_eq!(updated["version"], json!(2), "{}", response);
+    assert_eq!(updated["content"], json!("Second draft"));
+
+    // Written back from a stale read.
+    spec["content"] = json!("Lost update");
+    let response = journal.upsert(spec.clone()).await;
+    assert_eq!(error_code(&response), "CONFLICT", "{}", response);
+    assert_eq!(response["errors"][0]["extensions"]["currentVersion"], 2);
+
+    // Without an expected version, the last write wins.
+    spec["expectedVersion"] = Value::Null;
+    let response = journal.upsert(spec).await;
+    let updated = &response["data"]["createOrUpdateDocument"]["doc"];
+    assert_eq!(updated["version"], json!(3), "{}", response);
+    assert_eq!(updated["content"], json!("Lost update"));
+}
+
+#[tokio::test]
+async fn aborts_atomic_batches() {
+    let journal = Journal::new();
+    journal
+        .upsert(doc(Uuid::new_v4(), "Ownership", &[], ""))
+        .await;
+
+    let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+    let response = journal
+        .batch(
+            vec![
+                doc(first, "Lifetimes", &[], ""),
+                doc(second, "Ownership", &[], ""),
+            ],
+            true,
+        )
+        .await;
+    let batch = &response["data"]["createOrUpdateDocuments"];
+    assert_eq!(batch["committed"], json!(false), "{}", response);
+    assert_eq!(batch["results"][0]["id"], json!(first));
+    assert_eq!(batch["results"][0]["error"]["code"], "ABORTED");
+    assert_eq!(batch["results"][1]["id"], json!(second));
+    assert_eq!(batch["results"][1]["error"]["code"], "CONFLICT");
+    assert_eq!(journal.titles("listDocuments", "").await, vec!["Ownership"]);
+
+    // Invalid documents abort the batch before it reaches the store.
+    let response = journal
+        .batch(
+            vec![doc(first, "Lifetimes", &[], ""), doc(second, " ", &[], "")],
+            true,
+        )
+        .await;
+    let batch = &response["data"]["createOrUpdateDocuments"];
+    assert_eq!(batch["committed"], json!(false), "{}", response);
+    assert_eq!(batch["results"][0]["error"]["code"], "ABORTED");
+    assert_eq!(batch["results"][1]["error"]["code"], "VALIDATION");
+    assert_eq!(journal.titles("listDocuments", "").await, vec!["Ownership"]);
+}
+
+#[tokio::test]
+async fn applies_non_atomic_batches_partially() {
+    let journal = Journal::new();
+    journal
+        .upsert(doc(Uuid::new_v4(), "Ownership", &[], ""))
+        .await;
+
+    let response = journal
+        .batch(
+            vec![
+                doc(Uuid::new_v4(), "Lifetimes", &[], ""),
+                doc(Uuid::new_v4(), "Ownership", &[], ""),
+                doc(Uuid::new_v4(), "", &[], ""),
+            ],
+            false,
+        )
+        .await;
+    let batch = &response["data"]["createOrUpdateDocuments"];
+    assert_eq!(batch["committed"], json!(true), "{}", response);
+    assert_eq!(batch["results"][0]["error"], Value::Null);
+    assert_eq!(batch["results"][0]["doc"]["version"], json!(1));
+    assert_eq!(batch["results"][1]["error"]["code"], "CONFLICT");
+    assert_eq!(batch["results"][2]["error"]["code"], "VALIDATION");
+    assert_eq!(
+        journal.titles("listDocuments", "").await,
+        vec!["Lifetimes", "Ownership"]
+    );
+}
+
+#[tokio::test]
+async fn patches_tags() {
+    let journal = Journal::new();
+    let id = Uuid::new_v4();
+    journal
+        .upsert(doc(id, "Ownership", &["rust", "memory"], "Content"))
+        .await;
+
+    let response = journal
+        .execute(
+            "mutation($id: Uuid!, $patch: DocPatch!) {
+               updateDocument(id: $id, patch: $patch) { doc { ...DocFields } }
+             }",
+            json!({
+                "id": id,
+                "patch": { "addTags": ["borrowing", "rust"], "removeTags": ["memory"] }
+            }),
+        )
+        .await;
+    let patched = &response["data"]["updateDocument"]["doc"];
+    assert_eq!(
+        patched["front"]["tags"],
+        json!(["rust", "borrowing"]),
+        "{}",
+        response
+    );
+    assert_eq!(patched["content"], json!("Content"));
+    assert_eq!(patched["version"], json!(2));
+
+    let response = journal
+        .execute(
+            "mutation($id: Uuid!, $patch: DocPatch!) {
+               updateDocument(id: $id, patch: $patch) { doc { ...DocFields } }
+             }",
+            json!({ "id": Uuid::new_v4(), "patch": { "addTags": ["rust"] } }),
+        )
+        .await;
+    assert_eq!(error_code(&response), "NOT_FOUND", "{}", response);
+}
+
+#[tokio::test]
+async fn rejects_duplicate_titles_and_slugs() {
+    let journal = Journal::new();
+    let response = journal.create(new_doc("Hello World", &[], "")).await;
+    let created = &response["data"]["createDocument"]["doc"];
+    assert_eq!(created["front"]["title"], "Hello World", "{}", response);
+    assert_eq!(created["version"], json!(1));
+
+    for title in &["Hello World", "hello, world!"] {
+        let response = journal.create(new_doc(title, &[], "")).await;
+        assert_eq!(error_code(&response), "CONFLICT", "{}", response);
+    }
+    assert_eq!(
+        journal.titles("listDocuments", "").await,
+        vec!["Hello World"]
+    );
+}
+
+#[tokio::test]
+async fn reports_validation_violations() {
+    let journal = Journal::new();
+    let mut spec = new_doc(" ", &["rust", "rust"], "");
+    spec["authorResource"] = json!("not a url");
+    let response = journal.create(spec).await;
+    assert_eq!(error_code(&response), "VALIDATION", "{}", response);
+
+    let mut violations = response["errors"][0]["extensions"]["violations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|violation| {
+            (
+                violation["field"].as_str().unwrap().to_string(),
+                violation["code"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    violations.sort();
+    let expected = [
+        ("doc.authorResource", "INVALID_URL"),
+        ("doc.tags[1]", "DUPLICATE"),
+        ("doc.title", "REQUIRED"),
+    ];
+    assert_eq!(
+        violations,
+        expected
+            .iter()
+            .map(|(field, code)| (field.to_string(), code.to_string()))
+            .collect::<Vec<_>>()
+    );
+    assert!(journal.titles("listDocuments", "").await.is_empty());
+}